@@ -7,8 +7,8 @@ use std::result::Result;
 use std::*;
 use tokio::sync::*;
 #[get("/login")]
-fn get_login() -> Template {
-    Template::render("login", json!({}))
+fn get_login(csrf_token: CsrfToken) -> Template {
+    Template::render("login", json!({ "csrf_token": csrf_token }))
 }
 
 #[post("/login", data = "<form>")]
@@ -19,8 +19,8 @@ async fn post_login(auth: Auth<'_>, form: Form<Login>) -> Result<Redirect, Error
 }
 
 #[get("/signup")]
-async fn get_signup() -> Template {
-    Template::render("signup", json!({}))
+async fn get_signup(csrf_token: CsrfToken) -> Template {
+    Template::render("signup", json!({ "csrf_token": csrf_token }))
 }
 
 #[post("/signup", data = "<form>")]
@@ -37,8 +37,8 @@ async fn index(user: Option<User>) -> Template {
 }
 
 #[get("/logout")]
-fn logout(auth: Auth<'_>) -> Result<Template, Error> {
-    auth.logout()?;
+async fn logout(auth: Auth<'_>) -> Result<Template, Error> {
+    auth.logout().await?;
     Ok(Template::render("logout", json!({})))
 }
 #[get("/delete")]
@@ -68,7 +68,8 @@ async fn main() -> Result<(), Error> {
     let conn = SqliteConnection::connect("database.db").await?;
     let conn: sync::Arc<Mutex<_>> = sync::Arc::new(conn.into());
     let mut users: Users = conn.clone().into();
-    users.open_redis("redis://127.0.0.1/")?;
+    users.set_token_key(std::env::var("TOKEN_KEY").expect("TOKEN_KEY is not set"));
+    users.open_redis("redis://127.0.0.1/").await?;
     let _ = rocket::build()
         .mount(
             "/",
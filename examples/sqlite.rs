@@ -7,8 +7,8 @@ use sqlx::*;
 use std::result::Result;
 use std::*;
 #[get("/login")]
-fn get_login() -> Template {
-    Template::render("login", json!({}))
+fn get_login(csrf_token: CsrfToken) -> Template {
+    Template::render("login", json!({ "csrf_token": csrf_token }))
 }
 
 #[post("/login", data = "<form>")]
@@ -20,8 +20,8 @@ async fn post_login(auth: Auth<'_>, form: Form<Login>) -> Result<Redirect, Error
 }
 
 #[get("/signup")]
-async fn get_signup() -> Template {
-    Template::render("signup", json!({}))
+async fn get_signup(csrf_token: CsrfToken) -> Template {
+    Template::render("signup", json!({ "csrf_token": csrf_token }))
 }
 
 #[post("/signup", data = "<form>")]
@@ -38,8 +38,8 @@ async fn index(user: Option<User>) -> Template {
 }
 
 #[get("/logout")]
-fn logout(auth: Auth<'_>) -> Result<Template, Error> {
-    auth.logout()?;
+async fn logout(auth: Auth<'_>) -> Result<Template, Error> {
+    auth.logout().await?;
     Ok(Template::render("logout", json!({})))
 }
 #[get("/delete")]
@@ -61,7 +61,12 @@ async fn show_all_users(conn: &State<SqlitePool>, user: Option<User>) -> Result<
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     let conn = SqlitePool::connect("database.db").await?;
-    let users: Users = conn.clone().into();
+    let mut users: Users = conn.clone().into();
+    users.set_token_key(std::env::var("TOKEN_KEY").expect("TOKEN_KEY is not set"));
+    users.set_cookie_config(CookieConfig {
+        require_csrf: true,
+        ..Default::default()
+    });
     users.create_table().await?;
 
     let _ = rocket::build()
@@ -7,8 +7,8 @@ use std::*;
 use std::{convert::TryInto, result::Result};
 use tokio_postgres::{connect, Client};
 #[get("/login")]
-fn get_login() -> Template {
-    Template::render("login", json!({}))
+fn get_login(csrf_token: CsrfToken) -> Template {
+    Template::render("login", json!({ "csrf_token": csrf_token }))
 }
 
 #[post("/login", data = "<form>")]
@@ -20,8 +20,8 @@ async fn post_login(auth: Auth<'_>, form: Form<Login>) -> Result<Redirect, Error
 }
 
 #[get("/signup")]
-async fn get_signup() -> Template {
-    Template::render("signup", json!({}))
+async fn get_signup(csrf_token: CsrfToken) -> Template {
+    Template::render("signup", json!({ "csrf_token": csrf_token }))
 }
 
 #[post("/signup", data = "<form>")]
@@ -38,8 +38,8 @@ async fn index(user: Option<User>) -> Template {
 }
 
 #[get("/logout")]
-fn logout(auth: Auth<'_>) -> Result<Template, Error> {
-    auth.logout()?;
+async fn logout(auth: Auth<'_>) -> Result<Template, Error> {
+    auth.logout().await?;
     Ok(Template::render("logout", json!({})))
 }
 #[get("/delete")]
@@ -72,7 +72,8 @@ async fn main() -> Result<(), Error> {
     use tokio_postgres::NoTls;
     let (client, conn) = connect("host=localhost user=postgres", NoTls).await?;
     let client = sync::Arc::new(client);
-    let users: Users = client.clone().into();
+    let mut users: Users = client.clone().into();
+    users.set_token_key(std::env::var("TOKEN_KEY").expect("TOKEN_KEY is not set"));
 
     tokio::spawn(async move {
         if let Err(e) = conn.await {
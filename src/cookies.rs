@@ -1,8 +1,128 @@
 use crate::error;
 use crate::prelude::*;
-use rocket::http::{CookieJar, Status};
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
 use rocket::request::{FromRequest, Outcome, Request};
-use serde_json::from_str;
+use serde_json::{from_str, json};
+
+/// The settings of the private cookie that holds the session. They can be set with [`Users::set_cookie_config`].
+/// ```rust
+/// # use rocket_auth2::{Users, CookieConfig};
+/// # fn func(mut users: Users) {
+/// users.set_cookie_config(CookieConfig {
+///     domain: Some("example.com".into()),
+///     secure: true,
+///     persistent: true,
+///     ..Default::default()
+/// });
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CookieConfig {
+    /// The name of the cookie, `rocket_auth` by default.
+    pub name: String,
+    /// The domain the cookie is sent to. A parent domain shares the session with all of its subdomains.
+    /// By default the cookie is only sent to the host that set it.
+    pub domain: Option<String>,
+    /// The path the cookie is sent to, `/` by default.
+    pub path: String,
+    /// The `SameSite` attribute of the cookie, `Strict` by default.
+    pub same_site: SameSite,
+    /// Whether the cookie is only sent over HTTPS. If it is not set, Rocket still marks the cookie as secure when TLS is enabled.
+    pub secure: bool,
+    /// Whether the max-age of the cookie matches the lifetime of the session, such as the period passed to
+    /// [`Auth::login_for`](crate::Auth::login_for). Otherwise the cookie expires after Rocket's default of one week.
+    pub persistent: bool,
+    /// Whether [`Auth::login`](crate::Auth::login) and [`Auth::signup`](crate::Auth::signup) reject forms
+    /// without the [`CsrfToken`](crate::CsrfToken) of the client. It is off by default, since JSON clients may not have one.
+    pub require_csrf: bool,
+}
+
+impl Default for CookieConfig {
+    fn default() -> CookieConfig {
+        CookieConfig {
+            name: "rocket_auth".into(),
+            domain: None,
+            path: "/".into(),
+            same_site: SameSite::Strict,
+            secure: false,
+            persistent: false,
+            require_csrf: false,
+        }
+    }
+}
+
+impl CookieConfig {
+    /// The cookie that holds `session`, which lasts `lifetime` if the cookie is persistent.
+    pub(crate) fn session_cookie(&self, session: &Session, lifetime: Duration) -> Cookie<'static> {
+        let mut cookie = Cookie::build((self.name.clone(), json!(session).to_string()))
+            .path(self.path.clone())
+            .same_site(self.same_site)
+            .http_only(true);
+        if let Some(domain) = &self.domain {
+            cookie = cookie.domain(domain.clone());
+        }
+        if self.secure {
+            cookie = cookie.secure(true);
+        }
+        if self.persistent {
+            cookie = cookie.max_age(rocket::time::Duration::seconds(lifetime.as_secs() as i64));
+        }
+        cookie.build()
+    }
+
+    /// The name of the cookie that holds the CSRF secret of the client.
+    pub(crate) fn csrf_name(&self) -> String {
+        format!("{}_csrf", self.name)
+    }
+
+    /// The cookie that holds the CSRF secret of the client. It lasts as long as the browser session.
+    pub(crate) fn csrf_cookie(&self, secret: String) -> Cookie<'static> {
+        self.browser_session_cookie(self.csrf_name(), secret)
+    }
+
+    /// The name of the cookie that holds a login waiting for its second factor.
+    pub(crate) fn second_factor_name(&self) -> String {
+        format!("{}_2fa", self.name)
+    }
+
+    /// The cookie that holds a login waiting for its second factor. It lasts as long as the browser session,
+    /// but the login itself expires sooner.
+    pub(crate) fn second_factor_cookie(&self, login: String) -> Cookie<'static> {
+        self.browser_session_cookie(self.second_factor_name(), login)
+    }
+
+    fn browser_session_cookie(&self, name: String, value: String) -> Cookie<'static> {
+        let mut cookie = Cookie::build((name, value))
+            .path(self.path.clone())
+            .same_site(self.same_site)
+            .http_only(true);
+        if let Some(domain) = &self.domain {
+            cookie = cookie.domain(domain.clone());
+        }
+        if self.secure {
+            cookie = cookie.secure(true);
+        }
+        cookie.build()
+    }
+
+    /// A cookie that removes the session cookie, which requires the same path and domain.
+    pub(crate) fn removal_cookie(&self) -> Cookie<'static> {
+        self.removal_cookie_of(self.name.clone())
+    }
+
+    /// A cookie that removes the cookie of a login waiting for its second factor.
+    pub(crate) fn second_factor_removal_cookie(&self) -> Cookie<'static> {
+        self.removal_cookie_of(self.second_factor_name())
+    }
+
+    fn removal_cookie_of(&self, name: String) -> Cookie<'static> {
+        let mut cookie = Cookie::build(name).path(self.path.clone());
+        if let Some(domain) = &self.domain {
+            cookie = cookie.domain(domain.clone());
+        }
+        cookie.build()
+    }
+}
 
 /// The Session guard can be used to retrieve user session data.
 /// Unlike `User`, using session does not verify that the session data is
@@ -10,6 +130,10 @@ use serde_json::from_str;
 /// may have expired. The Session guard is intended for purposes where
 /// verifying the validity of the session data is unnecessary.
 ///
+/// Clients that can not hold cookies may send a token from [`Auth::issue_token`](crate::Auth::issue_token)
+/// in an `Authorization: Bearer <token>` header instead. Since the session of a token is read from the session store,
+/// it is only found while the token is still valid.
+///
 /// Note that,
 /// session data is already captured by the [`Auth`](`crate::Auth`) guard and stored in the public [`session`](`crate::Auth`) field.
 /// So it is not necessary to use them together.
@@ -21,8 +145,20 @@ pub struct Session {
     pub id: i32,
     /// The user email.
     pub email: String,
-    /// A random authentication token key.
+    /// A random identifier of this session. A user may hold several sessions at once.
+    pub session_id: String,
+    /// A random authentication token key. It is empty for stateless sessions.
     pub auth_key: String,
+    /// The Unix time at which a stateless session expires, see [`Users::set_stateless_sessions`].
+    #[serde(default)]
+    pub expires: Option<i64>,
+    /// The session version of the user when a stateless session was issued.
+    /// Bumping the version with [`Users::revoke_all_sessions`] invalidates the session.
+    #[serde(default)]
+    pub version: Option<i64>,
+    /// A random identifier of the login, which the session keeps when it is rotated. The CSRF token is tied to it.
+    #[serde(default)]
+    pub(crate) login_id: String,
 }
 
 #[async_trait]
@@ -30,15 +166,77 @@ impl<'r> FromRequest<'r> for Session {
     type Error = Error;
     async fn from_request(request: &'r Request<'_>) -> Outcome<Session, Self::Error> {
         let cookies = request.cookies();
+        let users = request.rocket().state::<Users>();
+        let name = match users {
+            Some(users) => &users.cookie.name,
+            None => "rocket_auth",
+        };
 
-        if let Some(session) = get_session(cookies) {
-            Outcome::Success(session)
-        } else {
-            Outcome::Error((Status::Unauthorized, error::Error::UnauthorizedError))
+        if let Some(session) = get_session(cookies, name) {
+            return Outcome::Success(session);
+        }
+        if let (Some(users), Some(token)) = (users, bearer_token(request)) {
+            if let Some(session) = users.token_session(token).await {
+                return Outcome::Success(session);
+            }
         }
+        Outcome::Error((Status::Unauthorized, error::Error::UnauthorizedError))
     }
 }
-fn get_session(cookies: &CookieJar) -> Option<Session> {
-    let session = cookies.get_private("rocket_auth")?;
+pub(crate) fn get_session(cookies: &CookieJar, name: &str) -> Option<Session> {
+    let session = cookies.get_private(name)?;
     from_str(session.value()).ok()
 }
+
+/// The token of an `Authorization: Bearer <token>` header.
+pub(crate) fn bearer_token<'r>(request: &'r Request<'_>) -> Option<&'r str> {
+    let header = request.headers().get_one("Authorization")?;
+    let (scheme, token) = header.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Bearer") {
+        return None;
+    }
+    Some(token.trim())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn session() -> Session {
+        Session {
+            time_stamp: 0,
+            id: 1,
+            email: "user@example.com".into(),
+            session_id: "id".into(),
+            auth_key: "token".into(),
+            expires: None,
+            version: None,
+            login_id: "login".into(),
+        }
+    }
+
+    #[test]
+    fn test_session_cookie_attributes() {
+        let config = CookieConfig {
+            name: "session".into(),
+            domain: Some("example.com".into()),
+            same_site: SameSite::Lax,
+            secure: true,
+            persistent: true,
+            ..Default::default()
+        };
+        let cookie = config.session_cookie(&session(), Duration::from_secs(60));
+        assert_eq!(cookie.name(), "session");
+        assert_eq!(cookie.domain(), Some("example.com"));
+        assert_eq!(cookie.path(), Some("/"));
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.max_age(), Some(rocket::time::Duration::seconds(60)));
+        assert_eq!(from_str::<Session>(cookie.value()).unwrap(), session());
+
+        let cookie = CookieConfig::default().session_cookie(&session(), Duration::from_secs(60));
+        assert_eq!(cookie.name(), "rocket_auth");
+        assert_eq!(cookie.max_age(), None);
+        assert_eq!(cookie.secure(), None);
+    }
+}
@@ -0,0 +1,311 @@
+use crate::cookies::{bearer_token, get_session};
+use crate::error;
+use crate::prelude::*;
+use crate::session::hash_token;
+use crate::user::generate_token;
+use rocket::http::{CookieJar, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use std::fmt::{Display, Formatter};
+use subtle::ConstantTimeEq;
+
+/// The header [`CsrfProtected`] reads the token from.
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+/// The name of the form field that holds the token, as in [`Login::csrf_token`].
+pub const CSRF_FIELD: &str = "csrf_token";
+
+/// The CSRF token of the client, to be embedded in the forms it is served.
+/// It is derived from a random secret kept in a private cookie and, for a logged in client, from its [`Session`],
+/// so it changes with every login but survives the rotation of the session token.
+///
+/// It serializes to the bare token, so it can be passed to a template as is and placed in a
+/// `<input type="hidden" name="csrf_token" value="{{ csrf_token }}">` field,
+/// or rendered as such a field with [`CsrfToken::hidden_input`].
+/// ```rust
+/// # use rocket::{get, post, form::Form, response::content::RawHtml};
+/// # use rocket_auth2::{CsrfToken, Error};
+/// #[get("/settings")]
+/// fn settings(csrf: CsrfToken) -> RawHtml<String> {
+///     RawHtml(format!(
+///         r#"<form method="post">{}<input name="theme"></form>"#,
+///         csrf.hidden_input()
+///     ))
+/// }
+///
+/// #[derive(rocket::FromForm)]
+/// struct Settings {
+///     csrf_token: String,
+///     theme: String,
+/// }
+///
+/// #[post("/settings", data = "<form>")]
+/// fn save_settings(form: Form<Settings>, csrf: CsrfToken) -> Result<(), Error> {
+///     csrf.verify(&form.csrf_token)?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct CsrfToken(String);
+
+impl CsrfToken {
+    /// The token, as it is expected in a form field or in the `X-CSRF-Token` header.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Fails with [`Error::CsrfError`] unless `token` is this token.
+    pub fn verify(&self, token: &str) -> Result<()> {
+        if bool::from(self.0.as_bytes().ct_eq(token.as_bytes())) {
+            Ok(())
+        } else {
+            Err(Error::CsrfError)
+        }
+    }
+
+    /// A hidden `csrf_token` input, to be placed inside a `<form>`.
+    pub fn hidden_input(&self) -> String {
+        format!(
+            r#"<input type="hidden" name="{}" value="{}">"#,
+            CSRF_FIELD, self.0
+        )
+    }
+}
+
+impl Display for CsrfToken {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The CSRF token of the client that sent `cookies`. It is derived from a random secret in a private cookie,
+/// which an attacker can not read, and from the login of the session if there is one.
+/// A secret is only stored for clients that have none if `create` is set, otherwise such clients have no token.
+pub(crate) fn csrf_token(users: &Users, cookies: &CookieJar, create: bool) -> Option<CsrfToken> {
+    let token_key = users.token_key().ok()?;
+    let secret = match cookies.get_private(&users.cookie.csrf_name()) {
+        Some(cookie) => cookie.value().to_string(),
+        None if create => {
+            let secret = generate_token();
+            cookies.add_private(users.cookie.csrf_cookie(secret.clone()));
+            secret
+        }
+        None => return None,
+    };
+    let scope = match get_session(cookies, &users.cookie.name) {
+        Some(session) => format!("csrf:{}:{}:{}", secret, session.id, session.login_id),
+        None => format!("csrf:{}", secret),
+    };
+    Some(CsrfToken(hash_token(token_key, &scope)))
+}
+
+/// The token of the current request, cached so that a client without a secret gets a single one.
+struct RequestCsrfToken(Option<CsrfToken>);
+
+#[async_trait]
+impl<'r> FromRequest<'r> for CsrfToken {
+    type Error = Error;
+    async fn from_request(request: &'r Request<'_>) -> Outcome<CsrfToken, Error> {
+        let Some(users) = request.rocket().state::<Users>() else {
+            return Outcome::Error((Status::InternalServerError, Error::UnmanagedStateError));
+        };
+        let token =
+            request.local_cache(|| RequestCsrfToken(csrf_token(users, request.cookies(), true)));
+        match &token.0 {
+            Some(token) => Outcome::Success(token.clone()),
+            None => Outcome::Error((Status::InternalServerError, Error::CsrfError)),
+        }
+    }
+}
+
+/// The `CsrfProtected` guard rejects requests whose `X-CSRF-Token` header does not hold the [`CsrfToken`] of the client,
+/// with `403 Forbidden`. It is meant for state-changing routes called from scripts in the page.
+/// Forms can not set headers, so routes that receive a form verify its token with [`CsrfToken::verify`] instead.
+///
+/// Requests authenticated with an `Authorization: Bearer` header and no session cookie are let through,
+/// since a browser does not attach that header on its own.
+/// ```rust
+/// # use rocket::post;
+/// # use rocket_auth2::{Auth, CsrfProtected, Error};
+/// #[post("/delete-account")]
+/// async fn delete_account(_csrf: CsrfProtected, auth: Auth<'_>) -> Result<(), Error> {
+///     auth.delete().await
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsrfProtected;
+
+#[async_trait]
+impl<'r> FromRequest<'r> for CsrfProtected {
+    type Error = Error;
+    async fn from_request(request: &'r Request<'_>) -> Outcome<CsrfProtected, Error> {
+        let Some(users) = request.rocket().state::<Users>() else {
+            return Outcome::Error((Status::InternalServerError, Error::UnmanagedStateError));
+        };
+        let cookies = request.cookies();
+        if get_session(cookies, &users.cookie.name).is_none() && bearer_token(request).is_some() {
+            return Outcome::Success(CsrfProtected);
+        }
+        let header = request.headers().get_one(CSRF_HEADER);
+        match (csrf_token(users, cookies, false), header) {
+            (Some(token), Some(header)) if token.verify(header).is_ok() => {
+                Outcome::Success(CsrfProtected)
+            }
+            _ => Outcome::Error((Status::Forbidden, error::Error::CsrfError)),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-sqlite"))]
+mod test {
+    use super::*;
+    use crate::{Auth, CookieConfig, Login};
+    use rocket::form::Form;
+    use rocket::http::{ContentType, Header};
+    use rocket::local::asynchronous::Client;
+    use rocket::{get, post, routes};
+
+    #[get("/csrf")]
+    fn show_csrf(csrf: CsrfToken) -> String {
+        csrf.to_string()
+    }
+
+    #[post("/protected")]
+    fn protected(_csrf: CsrfProtected) -> &'static str {
+        "ok"
+    }
+
+    #[post("/login", data = "<form>")]
+    async fn login(form: Form<Login>, auth: Auth<'_>) -> Result<&'static str> {
+        auth.login(&form).await.map(|_| "ok")
+    }
+
+    #[post("/logout")]
+    async fn logout(auth: Auth<'_>) -> Result<&'static str> {
+        auth.logout().await.map(|_| "ok")
+    }
+
+    async fn client() -> Client {
+        let mut users = crate::user::test_users("csrf@example.com").await;
+        users.set_cookie_config(CookieConfig {
+            require_csrf: true,
+            ..CookieConfig::default()
+        });
+        let rocket = rocket::build()
+            .mount("/", routes![show_csrf, protected, login, logout])
+            .manage(users);
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    async fn csrf_of(client: &Client) -> String {
+        client
+            .get("/csrf")
+            .dispatch()
+            .await
+            .into_string()
+            .await
+            .unwrap()
+    }
+
+    async fn log_in(client: &Client, csrf: Option<&str>) -> String {
+        let mut body = "email=csrf%40example.com&password=Password123".to_string();
+        if let Some(csrf) = csrf {
+            body += &format!("&{}={}", CSRF_FIELD, csrf);
+        }
+        let response = client
+            .post("/login")
+            .header(ContentType::Form)
+            .body(body)
+            .dispatch()
+            .await;
+        response.into_string().await.unwrap()
+    }
+
+    async fn post_protected(client: &Client, csrf: Option<&str>) -> Status {
+        let mut request = client.post("/protected");
+        if let Some(csrf) = csrf {
+            request = request.header(Header::new(CSRF_HEADER, csrf.to_string()));
+        }
+        request.dispatch().await.status()
+    }
+
+    #[tokio::test]
+    async fn test_csrf_secret_is_kept_in_a_private_cookie() {
+        let client = client().await;
+        let response = client.get("/csrf").dispatch().await;
+        let name = client.rocket().state::<Users>().unwrap().cookie.csrf_name();
+        let secret = response.cookies().get_private(&name).unwrap();
+        assert_ne!(secret.value(), "");
+        let token = response.into_string().await.unwrap();
+        assert!(token.starts_with("hmac-sha256:"));
+        assert!(!token.contains(secret.value()));
+
+        // the secret is kept, so the token is stable
+        assert_eq!(csrf_of(&client).await, token);
+        // another client gets another secret
+        assert_ne!(csrf_of(&self::client().await).await, token);
+    }
+
+    #[tokio::test]
+    async fn test_csrf_token_is_bound_to_the_login() {
+        let client = client().await;
+        let anonymous = csrf_of(&client).await;
+        assert_eq!(log_in(&client, Some(&anonymous)).await, "ok");
+        let first = csrf_of(&client).await;
+        assert_ne!(first, anonymous);
+        assert_eq!(csrf_of(&client).await, first);
+
+        let response = client.post("/logout").dispatch().await;
+        assert_eq!(response.into_string().await.unwrap(), "ok");
+        assert_eq!(csrf_of(&client).await, anonymous);
+
+        // every login gets a new token, even for the same user within the same second
+        assert_eq!(log_in(&client, Some(&anonymous)).await, "ok");
+        let second = csrf_of(&client).await;
+        assert_ne!(second, first);
+        assert_ne!(second, anonymous);
+    }
+
+    #[tokio::test]
+    async fn test_csrf_protected_checks_the_header() {
+        let client = client().await;
+        assert_eq!(post_protected(&client, None).await, Status::Forbidden);
+        let anonymous = csrf_of(&client).await;
+        assert_eq!(post_protected(&client, None).await, Status::Forbidden);
+        assert_eq!(
+            post_protected(&client, Some("hmac-sha256:00")).await,
+            Status::Forbidden
+        );
+        assert_eq!(post_protected(&client, Some(&anonymous)).await, Status::Ok);
+
+        // after a login, only the token of the session is accepted
+        assert_eq!(log_in(&client, Some(&anonymous)).await, "ok");
+        assert_eq!(
+            post_protected(&client, Some(&anonymous)).await,
+            Status::Forbidden
+        );
+        let token = csrf_of(&client).await;
+        assert_eq!(post_protected(&client, Some(&token)).await, Status::Ok);
+
+        // a bearer token without a session cookie needs no CSRF token
+        let other = self::client().await;
+        let response = other
+            .post("/protected")
+            .header(Header::new("Authorization", "Bearer token"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[tokio::test]
+    async fn test_login_checks_the_form_field() {
+        let client = client().await;
+        let rejected = Error::CsrfError.to_string();
+        assert!(log_in(&client, None).await.contains(&rejected));
+        let token = csrf_of(&client).await;
+        assert!(log_in(&client, None).await.contains(&rejected));
+        assert!(log_in(&client, Some("hmac-sha256:00"))
+            .await
+            .contains(&rejected));
+        assert_eq!(log_in(&client, Some(&token)).await, "ok");
+    }
+}
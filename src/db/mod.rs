@@ -12,7 +12,35 @@ mod sqlite;
 mod tokio_postgres;
 
 use crate::prelude::*;
+use crate::user::api_keys::ApiKey;
+use crate::user::email_change::EmailChange;
+use crate::user::password_reset::PasswordReset;
 use crate::user::roles::Roles;
+use crate::user::totp::TotpSecret;
+
+/// Turns the violation of the unique constraint on the email of a user into [`Error::EmailAlreadyExists`].
+#[cfg(any(feature = "sqlx", feature = "rusqlite", feature = "tokio-postgres"))]
+pub(crate) fn email_conflict(error: impl Into<Error>) -> Error {
+    match error.into() {
+        #[cfg(feature = "sqlx")]
+        Error::SqlxError(sqlx::Error::Database(error)) if error.is_unique_violation() => {
+            Error::EmailAlreadyExists
+        }
+        #[cfg(feature = "rusqlite")]
+        Error::RusqliteError(rusqlite::Error::SqliteFailure(error, _))
+            if error.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE =>
+        {
+            Error::EmailAlreadyExists
+        }
+        #[cfg(feature = "tokio-postgres")]
+        Error::TokioPostgresError(error)
+            if error.code() == Some(&::tokio_postgres::error::SqlState::UNIQUE_VIOLATION) =>
+        {
+            Error::EmailAlreadyExists
+        }
+        error => error,
+    }
+}
 
 #[rocket::async_trait]
 pub trait DBConnection: Send + Sync {
@@ -24,6 +52,35 @@ pub trait DBConnection: Send + Sync {
     async fn get_user_by_id(&self, user_id: i32) -> Result<User>;
     async fn get_user_by_email(&self, email: &str) -> Result<User>;
     async fn get_all_ids(&self) -> Result<Vec<i32>>;
+    async fn get_session_version(&self, user_id: i32) -> Result<i64>;
+    async fn bump_session_version(&self, user_id: i32) -> Result<()>;
+    async fn create_api_key(&self, key: &ApiKey) -> Result<()>;
+    async fn get_api_key(&self, key_id: &str) -> Result<ApiKey>;
+    async fn get_api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>>;
+    async fn touch_api_key(&self, key_id: &str, last_used: i64) -> Result<()>;
+    async fn delete_api_key(&self, key_id: &str) -> Result<()>;
+    async fn create_password_reset(&self, reset: &PasswordReset) -> Result<()>;
+    async fn get_password_reset(&self, reset_id: &str) -> Result<PasswordReset>;
+    async fn delete_password_reset(&self, user_id: i32) -> Result<()>;
+    /// Deletes the password reset with this id, and returns whether it still existed.
+    /// Of several concurrent calls for the same reset, only one returns `true`.
+    async fn consume_password_reset(&self, reset_id: &str) -> Result<bool>;
+    async fn create_email_change(&self, change: &EmailChange) -> Result<()>;
+    async fn get_email_change(&self, change_id: &str) -> Result<EmailChange>;
+    async fn delete_email_change(&self, user_id: i32) -> Result<()>;
+    /// Deletes the email change with this id, and returns whether it still existed.
+    /// Of several concurrent calls for the same change, only one returns `true`.
+    async fn consume_email_change(&self, change_id: &str) -> Result<bool>;
+    async fn set_totp_secret(&self, secret: &TotpSecret) -> Result<()>;
+    async fn get_totp_secret(&self, user_id: i32) -> Result<Option<TotpSecret>>;
+    /// Records that the code of `step` was used, unless a code of this step or a later one already was,
+    /// in which case it returns `false`.
+    async fn use_totp_step(&self, user_id: i32, step: i64) -> Result<bool>;
+    /// Counts an attempt at a code, unless `max` attempts were made since the last accepted one,
+    /// in which case it returns `false`. [`use_totp_step`](DBConnection::use_totp_step) resets the count,
+    /// and so does an attempt when the previous one was made before `since`.
+    async fn count_totp_attempt(&self, user_id: i32, max: i32, since: i64) -> Result<bool>;
+    async fn delete_totp_secret(&self, user_id: i32) -> Result<()>;
 }
 
 #[rocket::async_trait]
@@ -52,6 +109,66 @@ impl<T: DBConnection> DBConnection for std::sync::Arc<T> {
     async fn get_all_ids(&self) -> Result<Vec<i32>> {
         T::get_all_ids(self).await
     }
+    async fn get_session_version(&self, user_id: i32) -> Result<i64> {
+        T::get_session_version(self, user_id).await
+    }
+    async fn bump_session_version(&self, user_id: i32) -> Result<()> {
+        T::bump_session_version(self, user_id).await
+    }
+    async fn create_api_key(&self, key: &ApiKey) -> Result<()> {
+        T::create_api_key(self, key).await
+    }
+    async fn get_api_key(&self, key_id: &str) -> Result<ApiKey> {
+        T::get_api_key(self, key_id).await
+    }
+    async fn get_api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>> {
+        T::get_api_keys(self, user_id).await
+    }
+    async fn touch_api_key(&self, key_id: &str, last_used: i64) -> Result<()> {
+        T::touch_api_key(self, key_id, last_used).await
+    }
+    async fn delete_api_key(&self, key_id: &str) -> Result<()> {
+        T::delete_api_key(self, key_id).await
+    }
+    async fn create_password_reset(&self, reset: &PasswordReset) -> Result<()> {
+        T::create_password_reset(self, reset).await
+    }
+    async fn get_password_reset(&self, reset_id: &str) -> Result<PasswordReset> {
+        T::get_password_reset(self, reset_id).await
+    }
+    async fn delete_password_reset(&self, user_id: i32) -> Result<()> {
+        T::delete_password_reset(self, user_id).await
+    }
+    async fn consume_password_reset(&self, reset_id: &str) -> Result<bool> {
+        T::consume_password_reset(self, reset_id).await
+    }
+    async fn create_email_change(&self, change: &EmailChange) -> Result<()> {
+        T::create_email_change(self, change).await
+    }
+    async fn get_email_change(&self, change_id: &str) -> Result<EmailChange> {
+        T::get_email_change(self, change_id).await
+    }
+    async fn delete_email_change(&self, user_id: i32) -> Result<()> {
+        T::delete_email_change(self, user_id).await
+    }
+    async fn consume_email_change(&self, change_id: &str) -> Result<bool> {
+        T::consume_email_change(self, change_id).await
+    }
+    async fn set_totp_secret(&self, secret: &TotpSecret) -> Result<()> {
+        T::set_totp_secret(self, secret).await
+    }
+    async fn get_totp_secret(&self, user_id: i32) -> Result<Option<TotpSecret>> {
+        T::get_totp_secret(self, user_id).await
+    }
+    async fn use_totp_step(&self, user_id: i32, step: i64) -> Result<bool> {
+        T::use_totp_step(self, user_id, step).await
+    }
+    async fn count_totp_attempt(&self, user_id: i32, max: i32, since: i64) -> Result<bool> {
+        T::count_totp_attempt(self, user_id, max, since).await
+    }
+    async fn delete_totp_secret(&self, user_id: i32) -> Result<()> {
+        T::delete_totp_secret(self, user_id).await
+    }
 }
 
 #[rocket::async_trait]
@@ -80,4 +197,67 @@ impl<T: DBConnection> DBConnection for tokio::sync::Mutex<T> {
     async fn get_all_ids(&self) -> Result<Vec<i32>> {
         self.lock().await.get_all_ids().await
     }
+    async fn get_session_version(&self, user_id: i32) -> Result<i64> {
+        self.lock().await.get_session_version(user_id).await
+    }
+    async fn bump_session_version(&self, user_id: i32) -> Result<()> {
+        self.lock().await.bump_session_version(user_id).await
+    }
+    async fn create_api_key(&self, key: &ApiKey) -> Result<()> {
+        self.lock().await.create_api_key(key).await
+    }
+    async fn get_api_key(&self, key_id: &str) -> Result<ApiKey> {
+        self.lock().await.get_api_key(key_id).await
+    }
+    async fn get_api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>> {
+        self.lock().await.get_api_keys(user_id).await
+    }
+    async fn touch_api_key(&self, key_id: &str, last_used: i64) -> Result<()> {
+        self.lock().await.touch_api_key(key_id, last_used).await
+    }
+    async fn delete_api_key(&self, key_id: &str) -> Result<()> {
+        self.lock().await.delete_api_key(key_id).await
+    }
+    async fn create_password_reset(&self, reset: &PasswordReset) -> Result<()> {
+        self.lock().await.create_password_reset(reset).await
+    }
+    async fn get_password_reset(&self, reset_id: &str) -> Result<PasswordReset> {
+        self.lock().await.get_password_reset(reset_id).await
+    }
+    async fn delete_password_reset(&self, user_id: i32) -> Result<()> {
+        self.lock().await.delete_password_reset(user_id).await
+    }
+    async fn consume_password_reset(&self, reset_id: &str) -> Result<bool> {
+        self.lock().await.consume_password_reset(reset_id).await
+    }
+    async fn create_email_change(&self, change: &EmailChange) -> Result<()> {
+        self.lock().await.create_email_change(change).await
+    }
+    async fn get_email_change(&self, change_id: &str) -> Result<EmailChange> {
+        self.lock().await.get_email_change(change_id).await
+    }
+    async fn delete_email_change(&self, user_id: i32) -> Result<()> {
+        self.lock().await.delete_email_change(user_id).await
+    }
+    async fn consume_email_change(&self, change_id: &str) -> Result<bool> {
+        self.lock().await.consume_email_change(change_id).await
+    }
+    async fn set_totp_secret(&self, secret: &TotpSecret) -> Result<()> {
+        self.lock().await.set_totp_secret(secret).await
+    }
+    async fn get_totp_secret(&self, user_id: i32) -> Result<Option<TotpSecret>> {
+        self.lock().await.get_totp_secret(user_id).await
+    }
+    async fn use_totp_step(&self, user_id: i32, step: i64) -> Result<bool> {
+        self.lock().await.use_totp_step(user_id, step).await
+    }
+    async fn count_totp_attempt(&self, user_id: i32, max: i32, since: i64) -> Result<bool> {
+        self.lock()
+            .await
+            .count_totp_attempt(user_id, max, since)
+            .await
+    }
+    async fn delete_totp_secret(&self, user_id: i32) -> Result<()> {
+        self.lock().await.delete_totp_secret(user_id).await
+    }
 }
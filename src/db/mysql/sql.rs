@@ -3,10 +3,21 @@ CREATE TABLE IF NOT EXISTS users (
     id INT PRIMARY KEY AUTO_INCREMENT,
     email VARCHAR (254) UNIQUE NOT NULL,
 	password VARCHAR ( 255 ) NOT NULL,
-    roles BLOB NOT NULL
+    roles BLOB NOT NULL,
+    verified BOOLEAN NOT NULL DEFAULT FALSE
 );
 ";
 
+/// Databases created before emails could be verified lack the `verified` column.
+pub(crate) const HAS_VERIFIED_COLUMN: &str = "
+SELECT COUNT(*) FROM information_schema.columns
+WHERE table_schema = DATABASE() AND table_name = 'users' AND column_name = 'verified';
+";
+
+pub(crate) const ADD_VERIFIED_COLUMN: &str = "
+ALTER TABLE users ADD COLUMN verified BOOLEAN NOT NULL DEFAULT FALSE;
+";
+
 pub(crate) const INSERT_USER: &str = "
 INSERT INTO users (email, password, roles) VALUES (?, ?, ?);
 ";
@@ -15,7 +26,8 @@ pub(crate) const UPDATE_USER: &str = "
 UPDATE users SET 
     email = ?,
     password = ?,
-    roles = ?
+    roles = ?,
+    verified = ?
 WHERE
     id = ?
 ";
@@ -37,3 +49,201 @@ DELETE FROM users WHERE email = ?;
 pub(crate) const GET_ALL: &str = "
 SELECT id FROM users;
 ";
+
+pub(crate) const CREATE_SESSIONS_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS sessions (
+    id VARCHAR (255) PRIMARY KEY,
+    user_id INT NOT NULL,
+    secret VARCHAR (255) NOT NULL,
+    expires BIGINT NOT NULL,
+    idle_expires BIGINT,
+    time_stamp BIGINT NOT NULL,
+    last_active BIGINT NOT NULL,
+    ip VARCHAR (45),
+    user_agent TEXT,
+    rotate BOOLEAN NOT NULL DEFAULT FALSE,
+    used_secrets TEXT NOT NULL,
+    INDEX sessions_user_id (user_id)
+);
+";
+
+pub(crate) const INSERT_SESSION: &str = "
+REPLACE INTO sessions (id, user_id, secret, expires, idle_expires, time_stamp, last_active, ip, user_agent, rotate, used_secrets)
+VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);
+";
+
+pub(crate) const SELECT_SESSION: &str = "
+SELECT * FROM sessions
+WHERE id = ? AND expires > ? AND (idle_expires IS NULL OR idle_expires > ?);
+";
+
+pub(crate) const SELECT_SESSIONS_BY_USER: &str = "
+SELECT * FROM sessions
+WHERE user_id = ? AND expires > ? AND (idle_expires IS NULL OR idle_expires > ?);
+";
+
+pub(crate) const TOUCH_SESSION: &str = "
+UPDATE sessions SET
+    last_active = ?,
+    idle_expires = ?
+WHERE
+    id = ?;
+";
+
+pub(crate) const REMOVE_SESSION: &str = "
+DELETE FROM sessions WHERE id = ?;
+";
+/// Removes a session only if its secret did not change since it was read.
+pub(crate) const TAKE_SESSION: &str = "
+DELETE FROM sessions WHERE id = ? AND secret = ?;
+";
+pub(crate) const REMOVE_SESSIONS_BY_USER: &str = "
+DELETE FROM sessions WHERE user_id = ?;
+";
+pub(crate) const REMOVE_ALL_SESSIONS: &str = "
+DELETE FROM sessions;
+";
+pub(crate) const REMOVE_EXPIRED_SESSIONS: &str = "
+DELETE FROM sessions WHERE expires <= ? OR idle_expires <= ?;
+";
+
+pub(crate) const CREATE_SESSION_VERSIONS_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS session_versions (
+    user_id INT PRIMARY KEY,
+    version BIGINT NOT NULL
+);
+";
+
+pub(crate) const SELECT_SESSION_VERSION: &str = "
+SELECT COALESCE(session_versions.version, 0) FROM users
+LEFT JOIN session_versions ON session_versions.user_id = users.id
+WHERE users.id = ?;
+";
+
+pub(crate) const BUMP_SESSION_VERSION: &str = "
+INSERT INTO session_versions (user_id, version) VALUES (?, 1)
+ON DUPLICATE KEY UPDATE version = version + 1;
+";
+
+pub(crate) const CREATE_API_KEYS_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS api_keys (
+    id VARCHAR(64) PRIMARY KEY,
+    user_id INT NOT NULL,
+    name TEXT NOT NULL,
+    hash VARCHAR(128) NOT NULL,
+    scopes TEXT NOT NULL,
+    created BIGINT NOT NULL,
+    expires BIGINT,
+    last_used BIGINT,
+    INDEX api_keys_user_id (user_id)
+);
+";
+
+pub(crate) const INSERT_API_KEY: &str = "
+INSERT INTO api_keys (id, user_id, name, hash, scopes, created, expires, last_used)
+VALUES (?, ?, ?, ?, ?, ?, ?, ?);
+";
+
+pub(crate) const SELECT_API_KEY: &str = "
+SELECT * FROM api_keys WHERE id = ?;
+";
+
+pub(crate) const SELECT_API_KEYS_BY_USER: &str = "
+SELECT * FROM api_keys WHERE user_id = ? ORDER BY created;
+";
+
+pub(crate) const TOUCH_API_KEY: &str = "
+UPDATE api_keys SET last_used = ? WHERE id = ?;
+";
+
+pub(crate) const REMOVE_API_KEY: &str = "
+DELETE FROM api_keys WHERE id = ?;
+";
+
+pub(crate) const CREATE_PASSWORD_RESETS_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS password_resets (
+    user_id INT PRIMARY KEY,
+    id VARCHAR(64) NOT NULL UNIQUE,
+    hash VARCHAR(128) NOT NULL,
+    expires BIGINT NOT NULL
+);
+";
+
+pub(crate) const INSERT_PASSWORD_RESET: &str = "
+INSERT INTO password_resets (user_id, id, hash, expires) VALUES (?, ?, ?, ?)
+ON DUPLICATE KEY UPDATE id = VALUES(id), hash = VALUES(hash), expires = VALUES(expires);
+";
+
+pub(crate) const SELECT_PASSWORD_RESET: &str = "
+SELECT * FROM password_resets WHERE id = ?;
+";
+
+pub(crate) const REMOVE_PASSWORD_RESET: &str = "
+DELETE FROM password_resets WHERE user_id = ?;
+";
+
+pub(crate) const CONSUME_PASSWORD_RESET: &str = "
+DELETE FROM password_resets WHERE id = ?;
+";
+
+pub(crate) const CREATE_EMAIL_CHANGES_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS email_changes (
+    user_id INT PRIMARY KEY,
+    id VARCHAR(64) NOT NULL UNIQUE,
+    email VARCHAR(254) NOT NULL,
+    hash VARCHAR(128) NOT NULL,
+    expires BIGINT NOT NULL
+);
+";
+
+pub(crate) const INSERT_EMAIL_CHANGE: &str = "
+INSERT INTO email_changes (user_id, id, email, hash, expires) VALUES (?, ?, ?, ?, ?)
+ON DUPLICATE KEY UPDATE id = VALUES(id), email = VALUES(email), hash = VALUES(hash), expires = VALUES(expires);
+";
+
+pub(crate) const SELECT_EMAIL_CHANGE: &str = "
+SELECT * FROM email_changes WHERE id = ?;
+";
+
+pub(crate) const REMOVE_EMAIL_CHANGE: &str = "
+DELETE FROM email_changes WHERE user_id = ?;
+";
+
+pub(crate) const CONSUME_EMAIL_CHANGE: &str = "
+DELETE FROM email_changes WHERE id = ?;
+";
+
+pub(crate) const CREATE_TOTP_SECRETS_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS totp_secrets (
+    user_id INT PRIMARY KEY,
+    secret VARCHAR(128) NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT FALSE,
+    last_step BIGINT,
+    failures INT NOT NULL DEFAULT 0,
+    last_attempt BIGINT
+);
+";
+
+pub(crate) const INSERT_TOTP_SECRET: &str = "
+INSERT INTO totp_secrets (user_id, secret, enabled, last_step, failures, last_attempt) VALUES (?, ?, ?, ?, ?, ?)
+ON DUPLICATE KEY UPDATE secret = VALUES(secret), enabled = VALUES(enabled), last_step = VALUES(last_step), failures = VALUES(failures), last_attempt = VALUES(last_attempt);
+";
+
+pub(crate) const SELECT_TOTP_SECRET: &str = "
+SELECT * FROM totp_secrets WHERE user_id = ?;
+";
+
+pub(crate) const USE_TOTP_STEP: &str = "
+UPDATE totp_secrets SET last_step = ?, failures = 0 WHERE user_id = ? AND (last_step IS NULL OR last_step < ?);
+";
+
+pub(crate) const COUNT_TOTP_ATTEMPT: &str = "
+UPDATE totp_secrets SET
+    failures = CASE WHEN last_attempt IS NULL OR last_attempt < ? THEN 1 ELSE failures + 1 END,
+    last_attempt = ?
+WHERE user_id = ? AND (failures < ? OR last_attempt IS NULL OR last_attempt < ?);
+";
+
+pub(crate) const REMOVE_TOTP_SECRET: &str = "
+DELETE FROM totp_secrets WHERE user_id = ?;
+";
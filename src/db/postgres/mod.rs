@@ -4,7 +4,13 @@ use sql::*;
 
 use sqlx::postgres::PgPool;
 
+use crate::db::email_conflict;
+use crate::session::{idle_deadline, AuthKey, YEAR_IN_SECS};
+use crate::user::api_keys::ApiKey;
+use crate::user::email_change::EmailChange;
+use crate::user::password_reset::PasswordReset;
 use crate::user::roles::Roles;
+use crate::user::totp::TotpSecret;
 use sqlx::encode::IsNull;
 use sqlx::error::BoxDynError;
 use sqlx::*;
@@ -37,6 +43,15 @@ impl<'q> Decode<'q, Postgres> for Roles {
 impl DBConnection for PgPool {
     async fn init(&self) -> Result<()> {
         query(CREATE_TABLE).execute(self).await?;
+        query(ADD_VERIFIED_COLUMN).execute(self).await?;
+        query(CREATE_SESSIONS_TABLE).execute(self).await?;
+        query(CREATE_SESSIONS_INDEX).execute(self).await?;
+        query(CREATE_SESSION_VERSIONS_TABLE).execute(self).await?;
+        query(CREATE_API_KEYS_TABLE).execute(self).await?;
+        query(CREATE_API_KEYS_INDEX).execute(self).await?;
+        query(CREATE_PASSWORD_RESETS_TABLE).execute(self).await?;
+        query(CREATE_EMAIL_CHANGES_TABLE).execute(self).await?;
+        query(CREATE_TOTP_SECRETS_TABLE).execute(self).await?;
         Ok(())
     }
     async fn create_user(&self, email: &str, hash: &str, roles: &Roles) -> Result<()> {
@@ -45,7 +60,8 @@ impl DBConnection for PgPool {
             .bind(hash)
             .bind(roles)
             .execute(self)
-            .await?;
+            .await
+            .map_err(email_conflict)?;
         Ok(())
     }
     async fn update_user(&self, user: &User) -> Result<()> {
@@ -54,8 +70,10 @@ impl DBConnection for PgPool {
             .bind(&user.email)
             .bind(&user.password)
             .bind(&user.roles)
+            .bind(user.verified)
             .execute(self)
-            .await?;
+            .await
+            .map_err(email_conflict)?;
 
         Ok(())
     }
@@ -83,4 +101,255 @@ impl DBConnection for PgPool {
         let ids = query_scalar(GET_ALL).fetch_all(self).await?;
         Ok(ids)
     }
+    async fn get_session_version(&self, user_id: i32) -> Result<i64> {
+        let version = query_scalar(SELECT_SESSION_VERSION)
+            .bind(user_id)
+            .fetch_one(self)
+            .await?;
+        Ok(version)
+    }
+    async fn bump_session_version(&self, user_id: i32) -> Result<()> {
+        query(BUMP_SESSION_VERSION)
+            .bind(user_id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+    async fn create_api_key(&self, key: &ApiKey) -> Result<()> {
+        query(INSERT_API_KEY)
+            .bind(&key.id)
+            .bind(key.user_id)
+            .bind(&key.name)
+            .bind(&key.hash)
+            .bind(key.scopes.to_string())
+            .bind(key.created)
+            .bind(key.expires)
+            .bind(key.last_used)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+    async fn get_api_key(&self, key_id: &str) -> Result<ApiKey> {
+        let key = query_as(SELECT_API_KEY)
+            .bind(key_id)
+            .fetch_one(self)
+            .await?;
+        Ok(key)
+    }
+    async fn get_api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>> {
+        let keys = query_as(SELECT_API_KEYS_BY_USER)
+            .bind(user_id)
+            .fetch_all(self)
+            .await?;
+        Ok(keys)
+    }
+    async fn touch_api_key(&self, key_id: &str, last_used: i64) -> Result<()> {
+        query(TOUCH_API_KEY)
+            .bind(key_id)
+            .bind(last_used)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+    async fn delete_api_key(&self, key_id: &str) -> Result<()> {
+        query(REMOVE_API_KEY).bind(key_id).execute(self).await?;
+        Ok(())
+    }
+    async fn create_password_reset(&self, reset: &PasswordReset) -> Result<()> {
+        query(INSERT_PASSWORD_RESET)
+            .bind(reset.user_id)
+            .bind(&reset.id)
+            .bind(&reset.hash)
+            .bind(reset.expires)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+    async fn get_password_reset(&self, reset_id: &str) -> Result<PasswordReset> {
+        let reset = query_as(SELECT_PASSWORD_RESET)
+            .bind(reset_id)
+            .fetch_one(self)
+            .await?;
+        Ok(reset)
+    }
+    async fn delete_password_reset(&self, user_id: i32) -> Result<()> {
+        query(REMOVE_PASSWORD_RESET)
+            .bind(user_id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+    async fn consume_password_reset(&self, reset_id: &str) -> Result<bool> {
+        let result = query(CONSUME_PASSWORD_RESET)
+            .bind(reset_id)
+            .execute(self)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+    async fn create_email_change(&self, change: &EmailChange) -> Result<()> {
+        query(INSERT_EMAIL_CHANGE)
+            .bind(change.user_id)
+            .bind(&change.id)
+            .bind(&change.email)
+            .bind(&change.hash)
+            .bind(change.expires)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+    async fn get_email_change(&self, change_id: &str) -> Result<EmailChange> {
+        let change = query_as(SELECT_EMAIL_CHANGE)
+            .bind(change_id)
+            .fetch_one(self)
+            .await?;
+        Ok(change)
+    }
+    async fn delete_email_change(&self, user_id: i32) -> Result<()> {
+        query(REMOVE_EMAIL_CHANGE)
+            .bind(user_id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+    async fn consume_email_change(&self, change_id: &str) -> Result<bool> {
+        let result = query(CONSUME_EMAIL_CHANGE)
+            .bind(change_id)
+            .execute(self)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+    async fn set_totp_secret(&self, secret: &TotpSecret) -> Result<()> {
+        query(INSERT_TOTP_SECRET)
+            .bind(secret.user_id)
+            .bind(&secret.secret)
+            .bind(secret.enabled)
+            .bind(secret.last_step)
+            .bind(secret.failures)
+            .bind(secret.last_attempt)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+    async fn get_totp_secret(&self, user_id: i32) -> Result<Option<TotpSecret>> {
+        let secret = query_as(SELECT_TOTP_SECRET)
+            .bind(user_id)
+            .fetch_optional(self)
+            .await?;
+        Ok(secret)
+    }
+    async fn use_totp_step(&self, user_id: i32, step: i64) -> Result<bool> {
+        let result = query(USE_TOTP_STEP)
+            .bind(user_id)
+            .bind(step)
+            .execute(self)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+    async fn count_totp_attempt(&self, user_id: i32, max: i32, since: i64) -> Result<bool> {
+        let result = query(COUNT_TOTP_ATTEMPT)
+            .bind(user_id)
+            .bind(max)
+            .bind(since)
+            .bind(now())
+            .execute(self)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+    async fn delete_totp_secret(&self, user_id: i32) -> Result<()> {
+        query(REMOVE_TOTP_SECRET)
+            .bind(user_id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl SessionManager for PgPool {
+    async fn insert(&self, session_id: &str, key: AuthKey) -> Result<()> {
+        self.insert_for(session_id, key, Duration::from_secs(YEAR_IN_SECS))
+            .await
+    }
+    async fn insert_for(&self, session_id: &str, mut key: AuthKey, time: Duration) -> Result<()> {
+        key.expire_in(time);
+        query(INSERT_SESSION)
+            .bind(session_id)
+            .bind(key.user_id)
+            .bind(&key.secret)
+            .bind(key.expires)
+            .bind(key.idle_expires)
+            .bind(key.time_stamp)
+            .bind(key.last_active)
+            .bind(&key.ip)
+            .bind(&key.user_agent)
+            .bind(key.rotate)
+            .bind(&key.used_secrets)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+    async fn remove(&self, session_id: &str) -> Result<()> {
+        query(REMOVE_SESSION).bind(session_id).execute(self).await?;
+        Ok(())
+    }
+    async fn take(&self, session_id: &str) -> Result<Option<AuthKey>> {
+        let Some(key) = SessionManager::get(self, session_id).await else {
+            return Ok(None);
+        };
+        let result = query(TAKE_SESSION)
+            .bind(session_id)
+            .bind(&key.secret)
+            .execute(self)
+            .await?;
+        Ok((result.rows_affected() == 1).then_some(key))
+    }
+    async fn remove_user(&self, user_id: i32) -> Result<()> {
+        query(REMOVE_SESSIONS_BY_USER)
+            .bind(user_id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+    async fn get(&self, session_id: &str) -> Option<AuthKey> {
+        query_as(SELECT_SESSION)
+            .bind(session_id)
+            .bind(now())
+            .fetch_optional(self)
+            .await
+            .ok()?
+    }
+    async fn list(&self, user_id: i32) -> Result<Vec<(String, AuthKey)>> {
+        let rows = query(SELECT_SESSIONS_BY_USER)
+            .bind(user_id)
+            .bind(now())
+            .fetch_all(self)
+            .await?;
+        let mut sessions = vec![];
+        for row in rows {
+            sessions.push((row.try_get("id")?, AuthKey::from_row(&row)?));
+        }
+        Ok(sessions)
+    }
+    async fn touch(&self, session_id: &str, idle_timeout: Option<Duration>) -> Result<()> {
+        query(TOUCH_SESSION)
+            .bind(session_id)
+            .bind(now())
+            .bind(idle_deadline(idle_timeout))
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+    async fn clear_all(&self) -> Result<()> {
+        query(REMOVE_ALL_SESSIONS).execute(self).await?;
+        Ok(())
+    }
+    async fn clear_expired(&self) -> Result<usize> {
+        let now = now();
+        let removed = query(REMOVE_EXPIRED_SESSIONS)
+            .bind(now)
+            .execute(self)
+            .await?
+            .rows_affected();
+        Ok(removed as usize)
+    }
 }
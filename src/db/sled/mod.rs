@@ -1,16 +1,72 @@
 use crate::prelude::*;
+use crate::user::api_keys::{ApiKey, Scopes};
+use crate::user::email_change::EmailChange;
+use crate::user::password_reset::PasswordReset;
 use crate::user::roles::Roles;
+use crate::user::totp::TotpSecret;
 use sled::transaction::{ConflictableTransactionError, ConflictableTransactionResult};
 use sled::Transactional;
 
 const TABLE_NAME: &str = "users";
 const EMAIL_INDEX_NAME: &str = "users_emails";
+const SESSION_VERSIONS_NAME: &str = "session_versions";
+const API_KEYS_NAME: &str = "api_keys";
+/// API key ids, grouped by the user they belong to.
+const API_KEYS_USER_INDEX_NAME: &str = "api_keys_users";
+const PASSWORD_RESETS_NAME: &str = "password_resets";
+/// The id of the pending password reset of each user.
+const PASSWORD_RESETS_USER_INDEX_NAME: &str = "password_resets_users";
+const EMAIL_CHANGES_NAME: &str = "email_changes";
+/// The id of the pending email change of each user.
+const EMAIL_CHANGES_USER_INDEX_NAME: &str = "email_changes_users";
+const TOTP_SECRETS_NAME: &str = "totp_secrets";
 
 #[derive(Deserialize, Serialize)]
 struct UserData {
     email: String,
     hash: String,
     roles: Roles,
+    #[serde(default)]
+    verified: bool,
+}
+
+/// `ApiKey` does not serialize its hash, so the keys are stored through this struct.
+#[derive(Deserialize, Serialize)]
+struct ApiKeyData {
+    user_id: i32,
+    name: String,
+    hash: String,
+    scopes: Scopes,
+    created: i64,
+    expires: Option<i64>,
+    last_used: Option<i64>,
+}
+
+impl ApiKeyData {
+    fn new(key: &ApiKey) -> Self {
+        ApiKeyData {
+            user_id: key.user_id,
+            name: key.name.clone(),
+            hash: key.hash.clone(),
+            scopes: key.scopes.clone(),
+            created: key.created,
+            expires: key.expires,
+            last_used: key.last_used,
+        }
+    }
+
+    fn into_key(self, id: String) -> ApiKey {
+        ApiKey {
+            id,
+            user_id: self.user_id,
+            name: self.name,
+            hash: self.hash,
+            scopes: self.scopes,
+            created: self.created,
+            expires: self.expires,
+            last_used: self.last_used,
+        }
+    }
 }
 
 fn map_error(e: impl Into<Error>) -> ConflictableTransactionError<Error> {
@@ -25,6 +81,46 @@ fn deserialize_id(id: &[u8]) -> i32 {
     i32::from_be_bytes(id[..].try_into().unwrap())
 }
 
+fn deserialize_version(version: &[u8]) -> i64 {
+    i64::from_be_bytes(version.try_into().unwrap())
+}
+
+fn api_key_user_key(user_id: i32, key_id: &str) -> Vec<u8> {
+    [&serialize_id(user_id)[..], key_id.as_bytes()].concat()
+}
+
+fn serialize_api_key(key: &ApiKeyData) -> Result<Vec<u8>> {
+    Ok(bson::to_vec(key)?)
+}
+
+fn deserialize_api_key(key: &[u8]) -> Result<ApiKeyData> {
+    Ok(bson::from_slice(key)?)
+}
+
+fn serialize_password_reset(reset: &PasswordReset) -> Result<Vec<u8>> {
+    Ok(bson::to_vec(reset)?)
+}
+
+fn deserialize_password_reset(reset: &[u8]) -> Result<PasswordReset> {
+    Ok(bson::from_slice(reset)?)
+}
+
+fn serialize_email_change(change: &EmailChange) -> Result<Vec<u8>> {
+    Ok(bson::to_vec(change)?)
+}
+
+fn deserialize_email_change(change: &[u8]) -> Result<EmailChange> {
+    Ok(bson::from_slice(change)?)
+}
+
+fn serialize_totp_secret(secret: &TotpSecret) -> Result<Vec<u8>> {
+    Ok(bson::to_vec(secret)?)
+}
+
+fn deserialize_totp_secret(secret: &[u8]) -> Result<TotpSecret> {
+    Ok(bson::from_slice(secret)?)
+}
+
 fn serialize_email(email: &str) -> &[u8] {
     email.as_bytes()
 }
@@ -42,6 +138,14 @@ impl DBConnection for sled::Db {
     async fn init(&self) -> Result<()> {
         self.open_tree(TABLE_NAME)?;
         self.open_tree(EMAIL_INDEX_NAME)?;
+        self.open_tree(SESSION_VERSIONS_NAME)?;
+        self.open_tree(API_KEYS_NAME)?;
+        self.open_tree(API_KEYS_USER_INDEX_NAME)?;
+        self.open_tree(PASSWORD_RESETS_NAME)?;
+        self.open_tree(PASSWORD_RESETS_USER_INDEX_NAME)?;
+        self.open_tree(EMAIL_CHANGES_NAME)?;
+        self.open_tree(EMAIL_CHANGES_USER_INDEX_NAME)?;
+        self.open_tree(TOTP_SECRETS_NAME)?;
         Ok(())
     }
 
@@ -66,6 +170,7 @@ impl DBConnection for sled::Db {
                     email: email.to_string(),
                     hash: hash.to_string(),
                     roles: roles.clone(),
+                    verified: false,
                 };
                 tree.insert(&serialize_id(id), serialize_data(&data))?;
 
@@ -82,10 +187,20 @@ impl DBConnection for sled::Db {
 
         (&tree, &index).transaction(
             |(tree, index)| -> ConflictableTransactionResult<(), Error> {
+                let serialized_email = serialize_email(&user.email);
+                if let Some(owner) = index.get(serialized_email)? {
+                    if deserialize_id(&owner) != user.id {
+                        return Err(ConflictableTransactionError::Abort(
+                            Error::EmailAlreadyExists,
+                        ));
+                    }
+                }
+
                 let data = UserData {
                     email: user.email.clone(),
                     hash: user.password.clone(),
                     roles: user.roles.clone(),
+                    verified: user.verified,
                 };
 
                 let old_entry = tree.insert(&serialize_id(user.id), serialize_data(&data))?;
@@ -95,7 +210,7 @@ impl DBConnection for sled::Db {
                     index.remove(serialize_email(&old_user.email))?;
                 }
 
-                index.insert(serialize_email(&user.email), &serialize_id(user.id))?;
+                index.insert(serialized_email, &serialize_id(user.id))?;
 
                 Ok(())
             },
@@ -156,6 +271,7 @@ impl DBConnection for sled::Db {
             email: user.email,
             roles: user.roles,
             password: user.hash,
+            verified: user.verified,
         })
     }
 
@@ -178,6 +294,7 @@ impl DBConnection for sled::Db {
                     email: user.email,
                     roles: user.roles,
                     password: user.hash,
+                    verified: user.verified,
                 })
             },
         )?;
@@ -194,4 +311,271 @@ impl DBConnection for sled::Db {
             .map(|id| deserialize_id(&id))
             .collect())
     }
+
+    async fn get_session_version(&self, user_id: i32) -> Result<i64> {
+        let tree = self.open_tree(TABLE_NAME)?;
+        let versions = self.open_tree(SESSION_VERSIONS_NAME)?;
+
+        if !tree.contains_key(serialize_id(user_id))? {
+            return Err(Error::UserNotFoundError);
+        }
+        Ok(versions
+            .get(serialize_id(user_id))?
+            .map_or(0, |version| deserialize_version(&version)))
+    }
+
+    async fn bump_session_version(&self, user_id: i32) -> Result<()> {
+        let versions = self.open_tree(SESSION_VERSIONS_NAME)?;
+        versions.update_and_fetch(serialize_id(user_id), |old| {
+            let version = old.map_or(0, deserialize_version);
+            Some((version + 1).to_be_bytes().to_vec())
+        })?;
+        Ok(())
+    }
+
+    async fn create_api_key(&self, key: &ApiKey) -> Result<()> {
+        let data = serialize_api_key(&ApiKeyData::new(key))?;
+        let tree = self.open_tree(API_KEYS_NAME)?;
+        let index = self.open_tree(API_KEYS_USER_INDEX_NAME)?;
+
+        (&tree, &index).transaction(
+            |(tree, index)| -> ConflictableTransactionResult<(), Error> {
+                tree.insert(key.id.as_bytes(), data.as_slice())?;
+                index.insert(api_key_user_key(key.user_id, &key.id), &[])?;
+                Ok(())
+            },
+        )?;
+        Ok(())
+    }
+
+    async fn get_api_key(&self, key_id: &str) -> Result<ApiKey> {
+        let tree = self.open_tree(API_KEYS_NAME)?;
+        let data = tree
+            .get(key_id.as_bytes())?
+            .ok_or(Error::ApiKeyNotFoundError)?;
+        Ok(deserialize_api_key(&data)?.into_key(key_id.into()))
+    }
+
+    async fn get_api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>> {
+        let index = self.open_tree(API_KEYS_USER_INDEX_NAME)?;
+        let mut keys = vec![];
+        for entry in index.scan_prefix(serialize_id(user_id)).keys() {
+            let entry = entry?;
+            let key_id = String::from_utf8_lossy(&entry[size_of::<i32>()..]);
+            keys.push(self.get_api_key(&key_id).await?);
+        }
+        keys.sort_by_key(|key| key.created);
+        Ok(keys)
+    }
+
+    async fn touch_api_key(&self, key_id: &str, last_used: i64) -> Result<()> {
+        let tree = self.open_tree(API_KEYS_NAME)?;
+        tree.transaction(|tree| -> ConflictableTransactionResult<(), Error> {
+            let Some(entry) = tree.get(key_id.as_bytes())? else {
+                return Ok(());
+            };
+            let mut data = deserialize_api_key(&entry).map_err(map_error)?;
+            data.last_used = Some(last_used);
+            tree.insert(
+                key_id.as_bytes(),
+                serialize_api_key(&data).map_err(map_error)?,
+            )?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    async fn delete_api_key(&self, key_id: &str) -> Result<()> {
+        let tree = self.open_tree(API_KEYS_NAME)?;
+        let index = self.open_tree(API_KEYS_USER_INDEX_NAME)?;
+
+        (&tree, &index).transaction(
+            |(tree, index)| -> ConflictableTransactionResult<(), Error> {
+                if let Some(entry) = tree.remove(key_id.as_bytes())? {
+                    let data = deserialize_api_key(&entry).map_err(map_error)?;
+                    index.remove(api_key_user_key(data.user_id, key_id))?;
+                }
+                Ok(())
+            },
+        )?;
+        Ok(())
+    }
+
+    async fn create_password_reset(&self, reset: &PasswordReset) -> Result<()> {
+        let data = serialize_password_reset(reset)?;
+        let tree = self.open_tree(PASSWORD_RESETS_NAME)?;
+        let index = self.open_tree(PASSWORD_RESETS_USER_INDEX_NAME)?;
+
+        (&tree, &index).transaction(
+            |(tree, index)| -> ConflictableTransactionResult<(), Error> {
+                let user_id = serialize_id(reset.user_id);
+                if let Some(previous) = index.insert(&user_id, reset.id.as_bytes())? {
+                    tree.remove(previous)?;
+                }
+                tree.insert(reset.id.as_bytes(), data.as_slice())?;
+                Ok(())
+            },
+        )?;
+        Ok(())
+    }
+
+    async fn get_password_reset(&self, reset_id: &str) -> Result<PasswordReset> {
+        let tree = self.open_tree(PASSWORD_RESETS_NAME)?;
+        let data = tree
+            .get(reset_id.as_bytes())?
+            .ok_or(Error::InvalidTokenError)?;
+        deserialize_password_reset(&data)
+    }
+
+    async fn delete_password_reset(&self, user_id: i32) -> Result<()> {
+        let tree = self.open_tree(PASSWORD_RESETS_NAME)?;
+        let index = self.open_tree(PASSWORD_RESETS_USER_INDEX_NAME)?;
+
+        (&tree, &index).transaction(
+            |(tree, index)| -> ConflictableTransactionResult<(), Error> {
+                if let Some(reset_id) = index.remove(&serialize_id(user_id))? {
+                    tree.remove(reset_id)?;
+                }
+                Ok(())
+            },
+        )?;
+        Ok(())
+    }
+
+    async fn consume_password_reset(&self, reset_id: &str) -> Result<bool> {
+        let tree = self.open_tree(PASSWORD_RESETS_NAME)?;
+        let index = self.open_tree(PASSWORD_RESETS_USER_INDEX_NAME)?;
+
+        let consumed = (&tree, &index).transaction(
+            |(tree, index)| -> ConflictableTransactionResult<bool, Error> {
+                let Some(data) = tree.remove(reset_id.as_bytes())? else {
+                    return Ok(false);
+                };
+                let reset = deserialize_password_reset(&data).map_err(map_error)?;
+                index.remove(&serialize_id(reset.user_id))?;
+                Ok(true)
+            },
+        )?;
+        Ok(consumed)
+    }
+
+    async fn create_email_change(&self, change: &EmailChange) -> Result<()> {
+        let data = serialize_email_change(change)?;
+        let tree = self.open_tree(EMAIL_CHANGES_NAME)?;
+        let index = self.open_tree(EMAIL_CHANGES_USER_INDEX_NAME)?;
+
+        (&tree, &index).transaction(
+            |(tree, index)| -> ConflictableTransactionResult<(), Error> {
+                let user_id = serialize_id(change.user_id);
+                if let Some(previous) = index.insert(&user_id, change.id.as_bytes())? {
+                    tree.remove(previous)?;
+                }
+                tree.insert(change.id.as_bytes(), data.as_slice())?;
+                Ok(())
+            },
+        )?;
+        Ok(())
+    }
+
+    async fn get_email_change(&self, change_id: &str) -> Result<EmailChange> {
+        let tree = self.open_tree(EMAIL_CHANGES_NAME)?;
+        let data = tree
+            .get(change_id.as_bytes())?
+            .ok_or(Error::InvalidTokenError)?;
+        deserialize_email_change(&data)
+    }
+
+    async fn delete_email_change(&self, user_id: i32) -> Result<()> {
+        let tree = self.open_tree(EMAIL_CHANGES_NAME)?;
+        let index = self.open_tree(EMAIL_CHANGES_USER_INDEX_NAME)?;
+
+        (&tree, &index).transaction(
+            |(tree, index)| -> ConflictableTransactionResult<(), Error> {
+                if let Some(change_id) = index.remove(&serialize_id(user_id))? {
+                    tree.remove(change_id)?;
+                }
+                Ok(())
+            },
+        )?;
+        Ok(())
+    }
+
+    async fn consume_email_change(&self, change_id: &str) -> Result<bool> {
+        let tree = self.open_tree(EMAIL_CHANGES_NAME)?;
+        let index = self.open_tree(EMAIL_CHANGES_USER_INDEX_NAME)?;
+
+        let consumed = (&tree, &index).transaction(
+            |(tree, index)| -> ConflictableTransactionResult<bool, Error> {
+                let Some(data) = tree.remove(change_id.as_bytes())? else {
+                    return Ok(false);
+                };
+                let change = deserialize_email_change(&data).map_err(map_error)?;
+                index.remove(&serialize_id(change.user_id))?;
+                Ok(true)
+            },
+        )?;
+        Ok(consumed)
+    }
+
+    async fn set_totp_secret(&self, secret: &TotpSecret) -> Result<()> {
+        let data = serialize_totp_secret(secret)?;
+        let tree = self.open_tree(TOTP_SECRETS_NAME)?;
+        tree.insert(serialize_id(secret.user_id), data)?;
+        Ok(())
+    }
+
+    async fn get_totp_secret(&self, user_id: i32) -> Result<Option<TotpSecret>> {
+        let tree = self.open_tree(TOTP_SECRETS_NAME)?;
+        tree.get(serialize_id(user_id))?
+            .map(|data| deserialize_totp_secret(&data))
+            .transpose()
+    }
+
+    async fn use_totp_step(&self, user_id: i32, step: i64) -> Result<bool> {
+        let tree = self.open_tree(TOTP_SECRETS_NAME)?;
+        let used = tree.transaction(|tree| -> ConflictableTransactionResult<bool, Error> {
+            let key = serialize_id(user_id);
+            let Some(data) = tree.get(key)? else {
+                return Ok(false);
+            };
+            let mut secret = deserialize_totp_secret(&data).map_err(map_error)?;
+            if secret.last_step.is_some_and(|last| last >= step) {
+                return Ok(false);
+            }
+            secret.last_step = Some(step);
+            secret.failures = 0;
+            let data = serialize_totp_secret(&secret).map_err(map_error)?;
+            tree.insert(&key, data)?;
+            Ok(true)
+        })?;
+        Ok(used)
+    }
+
+    async fn count_totp_attempt(&self, user_id: i32, max: i32, since: i64) -> Result<bool> {
+        let tree = self.open_tree(TOTP_SECRETS_NAME)?;
+        let counted = tree.transaction(|tree| -> ConflictableTransactionResult<bool, Error> {
+            let key = serialize_id(user_id);
+            let Some(data) = tree.get(key)? else {
+                return Ok(false);
+            };
+            let mut secret = deserialize_totp_secret(&data).map_err(map_error)?;
+            if secret.last_attempt.is_none_or(|time| time < since) {
+                secret.failures = 0;
+            } else if secret.failures >= max {
+                return Ok(false);
+            }
+            secret.failures += 1;
+            secret.last_attempt = Some(now());
+            let data = serialize_totp_secret(&secret).map_err(map_error)?;
+            tree.insert(&key, data)?;
+            Ok(true)
+        })?;
+        Ok(counted)
+    }
+
+    async fn delete_totp_secret(&self, user_id: i32) -> Result<()> {
+        let tree = self.open_tree(TOTP_SECRETS_NAME)?;
+        tree.remove(serialize_id(user_id))?;
+        Ok(())
+    }
 }
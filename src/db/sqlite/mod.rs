@@ -1,7 +1,12 @@
 mod sql;
 
+use crate::db::email_conflict;
 use crate::prelude::{Result, *};
+use crate::user::api_keys::ApiKey;
+use crate::user::email_change::EmailChange;
+use crate::user::password_reset::PasswordReset;
 use crate::user::roles::Roles;
+use crate::user::totp::TotpSecret;
 use rocket::async_trait;
 use sql::*;
 use std::borrow::Cow;
@@ -47,6 +52,66 @@ impl<'a> TryFrom<&rusqlite::Row<'a>> for crate::User {
             email: row.get(1)?,
             password: row.get(2)?,
             roles: row.get(3)?,
+            verified: row.get(4)?,
+        })
+    }
+}
+
+#[cfg(feature = "rusqlite")]
+impl<'a> TryFrom<&rusqlite::Row<'a>> for ApiKey {
+    type Error = rusqlite::Error;
+    fn try_from(row: &Row) -> Result<ApiKey, rusqlite::Error> {
+        Ok(ApiKey {
+            id: row.get("id")?,
+            user_id: row.get("user_id")?,
+            name: row.get("name")?,
+            hash: row.get("hash")?,
+            scopes: row.get::<_, String>("scopes")?.into(),
+            created: row.get("created")?,
+            expires: row.get("expires")?,
+            last_used: row.get("last_used")?,
+        })
+    }
+}
+
+#[cfg(feature = "rusqlite")]
+impl<'a> TryFrom<&rusqlite::Row<'a>> for EmailChange {
+    type Error = rusqlite::Error;
+    fn try_from(row: &Row) -> Result<EmailChange, rusqlite::Error> {
+        Ok(EmailChange {
+            id: row.get("id")?,
+            user_id: row.get("user_id")?,
+            email: row.get("email")?,
+            hash: row.get("hash")?,
+            expires: row.get("expires")?,
+        })
+    }
+}
+
+#[cfg(feature = "rusqlite")]
+impl<'a> TryFrom<&rusqlite::Row<'a>> for TotpSecret {
+    type Error = rusqlite::Error;
+    fn try_from(row: &Row) -> Result<TotpSecret, rusqlite::Error> {
+        Ok(TotpSecret {
+            user_id: row.get("user_id")?,
+            secret: row.get("secret")?,
+            enabled: row.get("enabled")?,
+            last_step: row.get("last_step")?,
+            failures: row.get("failures")?,
+            last_attempt: row.get("last_attempt")?,
+        })
+    }
+}
+
+#[cfg(feature = "rusqlite")]
+impl<'a> TryFrom<&rusqlite::Row<'a>> for PasswordReset {
+    type Error = rusqlite::Error;
+    fn try_from(row: &Row) -> Result<PasswordReset, rusqlite::Error> {
+        Ok(PasswordReset {
+            id: row.get("id")?,
+            user_id: row.get("user_id")?,
+            hash: row.get("hash")?,
+            expires: row.get("expires")?,
         })
     }
 }
@@ -57,12 +122,24 @@ impl DBConnection for Mutex<rusqlite::Connection> {
     async fn init(&self) -> Result<()> {
         let conn = self.lock().await;
         block_in_place(|| conn.execute(CREATE_TABLE, []))?;
+        let has_verified: i64 =
+            block_in_place(|| conn.query_row(HAS_VERIFIED_COLUMN, [], |row| row.get(0)))?;
+        if has_verified == 0 {
+            block_in_place(|| conn.execute(ADD_VERIFIED_COLUMN, []))?;
+        }
+        block_in_place(|| conn.execute(CREATE_SESSION_VERSIONS_TABLE, []))?;
+        block_in_place(|| conn.execute(CREATE_API_KEYS_TABLE, []))?;
+        block_in_place(|| conn.execute(CREATE_API_KEYS_INDEX, []))?;
+        block_in_place(|| conn.execute(CREATE_PASSWORD_RESETS_TABLE, []))?;
+        block_in_place(|| conn.execute(CREATE_EMAIL_CHANGES_TABLE, []))?;
+        block_in_place(|| conn.execute(CREATE_TOTP_SECRETS_TABLE, []))?;
         Ok(())
     }
 
     async fn create_user(&self, email: &str, hash: &str, roles: &Roles) -> Result<()> {
         let conn = self.lock().await;
-        block_in_place(|| conn.execute(INSERT_USER, params![email, hash, roles]))?;
+        block_in_place(|| conn.execute(INSERT_USER, params![email, hash, roles]))
+            .map_err(email_conflict)?;
 
         Ok(())
     }
@@ -72,9 +149,16 @@ impl DBConnection for Mutex<rusqlite::Connection> {
         block_in_place(|| {
             conn.execute(
                 UPDATE_USER,
-                params![user.id, user.email, user.password, user.roles],
+                params![
+                    user.id,
+                    user.email,
+                    user.password,
+                    user.roles,
+                    user.verified
+                ],
             )
-        })?;
+        })
+        .map_err(email_conflict)?;
         Ok(())
     }
 
@@ -124,14 +208,213 @@ impl DBConnection for Mutex<rusqlite::Connection> {
         })?;
         Ok(ids)
     }
+
+    async fn get_session_version(&self, user_id: i32) -> Result<i64> {
+        let conn = self.lock().await;
+        let version = block_in_place(|| {
+            conn.query_row(SELECT_SESSION_VERSION, params![user_id], |row| row.get(0))
+        })?;
+        Ok(version)
+    }
+
+    async fn bump_session_version(&self, user_id: i32) -> Result<()> {
+        let conn = self.lock().await;
+        block_in_place(|| conn.execute(BUMP_SESSION_VERSION, params![user_id]))?;
+        Ok(())
+    }
+
+    async fn create_api_key(&self, key: &ApiKey) -> Result<()> {
+        let conn = self.lock().await;
+        block_in_place(|| {
+            conn.execute(
+                INSERT_API_KEY,
+                params![
+                    key.id,
+                    key.user_id,
+                    key.name,
+                    key.hash,
+                    key.scopes.to_string(),
+                    key.created,
+                    key.expires,
+                    key.last_used
+                ],
+            )
+        })?;
+        Ok(())
+    }
+
+    async fn get_api_key(&self, key_id: &str) -> Result<ApiKey> {
+        let conn = self.lock().await;
+        let key = block_in_place(|| {
+            conn.query_row(
+                SELECT_API_KEY, //
+                params![key_id],
+                |row| row.try_into(),
+            )
+        })?;
+        Ok(key)
+    }
+
+    async fn get_api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>> {
+        let conn = self.lock().await;
+        let mut stmt = conn.prepare(SELECT_API_KEYS_BY_USER)?;
+        let keys = block_in_place(|| -> Result<Vec<ApiKey>> {
+            Ok(stmt
+                .query_map(params![user_id], |row| row.try_into())?
+                .collect::<rusqlite::Result<_>>()?)
+        })?;
+        Ok(keys)
+    }
+
+    async fn touch_api_key(&self, key_id: &str, last_used: i64) -> Result<()> {
+        let conn = self.lock().await;
+        block_in_place(|| conn.execute(TOUCH_API_KEY, params![key_id, last_used]))?;
+        Ok(())
+    }
+
+    async fn delete_api_key(&self, key_id: &str) -> Result<()> {
+        let conn = self.lock().await;
+        block_in_place(|| conn.execute(REMOVE_API_KEY, params![key_id]))?;
+        Ok(())
+    }
+
+    async fn create_password_reset(&self, reset: &PasswordReset) -> Result<()> {
+        let conn = self.lock().await;
+        block_in_place(|| {
+            conn.execute(
+                INSERT_PASSWORD_RESET,
+                params![reset.user_id, reset.id, reset.hash, reset.expires],
+            )
+        })?;
+        Ok(())
+    }
+
+    async fn get_password_reset(&self, reset_id: &str) -> Result<PasswordReset> {
+        let conn = self.lock().await;
+        let reset = block_in_place(|| {
+            conn.query_row(
+                SELECT_PASSWORD_RESET, //
+                params![reset_id],
+                |row| row.try_into(),
+            )
+        })?;
+        Ok(reset)
+    }
+
+    async fn delete_password_reset(&self, user_id: i32) -> Result<()> {
+        let conn = self.lock().await;
+        block_in_place(|| conn.execute(REMOVE_PASSWORD_RESET, params![user_id]))?;
+        Ok(())
+    }
+
+    async fn consume_password_reset(&self, reset_id: &str) -> Result<bool> {
+        let conn = self.lock().await;
+        let deleted = block_in_place(|| conn.execute(CONSUME_PASSWORD_RESET, params![reset_id]))?;
+        Ok(deleted == 1)
+    }
+
+    async fn create_email_change(&self, change: &EmailChange) -> Result<()> {
+        let conn = self.lock().await;
+        block_in_place(|| {
+            conn.execute(
+                INSERT_EMAIL_CHANGE,
+                params![
+                    change.user_id,
+                    change.id,
+                    change.email,
+                    change.hash,
+                    change.expires
+                ],
+            )
+        })?;
+        Ok(())
+    }
+
+    async fn get_email_change(&self, change_id: &str) -> Result<EmailChange> {
+        let conn = self.lock().await;
+        let change = block_in_place(|| {
+            conn.query_row(
+                SELECT_EMAIL_CHANGE, //
+                params![change_id],
+                |row| row.try_into(),
+            )
+        })?;
+        Ok(change)
+    }
+
+    async fn delete_email_change(&self, user_id: i32) -> Result<()> {
+        let conn = self.lock().await;
+        block_in_place(|| conn.execute(REMOVE_EMAIL_CHANGE, params![user_id]))?;
+        Ok(())
+    }
+    async fn consume_email_change(&self, change_id: &str) -> Result<bool> {
+        let conn = self.lock().await;
+        let deleted = block_in_place(|| conn.execute(CONSUME_EMAIL_CHANGE, params![change_id]))?;
+        Ok(deleted == 1)
+    }
+
+    async fn set_totp_secret(&self, secret: &TotpSecret) -> Result<()> {
+        let conn = self.lock().await;
+        block_in_place(|| {
+            conn.execute(
+                INSERT_TOTP_SECRET,
+                params![
+                    secret.user_id,
+                    secret.secret,
+                    secret.enabled,
+                    secret.last_step,
+                    secret.failures,
+                    secret.last_attempt
+                ],
+            )
+        })?;
+        Ok(())
+    }
+
+    async fn get_totp_secret(&self, user_id: i32) -> Result<Option<TotpSecret>> {
+        let conn = self.lock().await;
+        let secret = block_in_place(|| {
+            conn.query_row(
+                SELECT_TOTP_SECRET, //
+                params![user_id],
+                |row| row.try_into(),
+            )
+            .optional()
+        })?;
+        Ok(secret)
+    }
+
+    async fn use_totp_step(&self, user_id: i32, step: i64) -> Result<bool> {
+        let conn = self.lock().await;
+        let changed = block_in_place(|| conn.execute(USE_TOTP_STEP, params![user_id, step]))?;
+        Ok(changed == 1)
+    }
+
+    async fn count_totp_attempt(&self, user_id: i32, max: i32, since: i64) -> Result<bool> {
+        let conn = self.lock().await;
+        let changed = block_in_place(|| {
+            conn.execute(COUNT_TOTP_ATTEMPT, params![user_id, max, since, now()])
+        })?;
+        Ok(changed == 1)
+    }
+
+    async fn delete_totp_secret(&self, user_id: i32) -> Result<()> {
+        let conn = self.lock().await;
+        block_in_place(|| conn.execute(REMOVE_TOTP_SECRET, params![user_id]))?;
+        Ok(())
+    }
 }
 
+#[cfg(feature = "sqlx-sqlite")]
+use crate::session::{idle_deadline, AuthKey, YEAR_IN_SECS};
 #[cfg(feature = "sqlx-sqlite")]
 use sqlx::encode::IsNull;
 #[cfg(feature = "sqlx-sqlite")]
 use sqlx::error::BoxDynError;
 use sqlx::sqlite::SqliteArgumentValue;
 #[cfg(feature = "sqlx-sqlite")]
+use sqlx::Row as _;
+#[cfg(feature = "sqlx-sqlite")]
 use sqlx::{sqlite::SqliteConnection, *};
 
 #[cfg(feature = "sqlx-sqlite")]
@@ -167,6 +450,22 @@ impl DBConnection for Mutex<SqliteConnection> {
     async fn init(&self) -> Result<()> {
         let mut db = self.lock().await;
         query(CREATE_TABLE).execute(&mut *db).await?;
+        let has_verified: i64 = query_scalar(HAS_VERIFIED_COLUMN)
+            .fetch_one(&mut *db)
+            .await?;
+        if has_verified == 0 {
+            query(ADD_VERIFIED_COLUMN).execute(&mut *db).await?;
+        }
+        query(CREATE_SESSION_VERSIONS_TABLE)
+            .execute(&mut *db)
+            .await?;
+        query(CREATE_API_KEYS_TABLE).execute(&mut *db).await?;
+        query(CREATE_API_KEYS_INDEX).execute(&mut *db).await?;
+        query(CREATE_PASSWORD_RESETS_TABLE)
+            .execute(&mut *db)
+            .await?;
+        query(CREATE_EMAIL_CHANGES_TABLE).execute(&mut *db).await?;
+        query(CREATE_TOTP_SECRETS_TABLE).execute(&mut *db).await?;
         println!("table created");
         Ok(())
     }
@@ -177,7 +476,8 @@ impl DBConnection for Mutex<SqliteConnection> {
             .bind(hash)
             .bind(roles)
             .execute(&mut *db)
-            .await?;
+            .await
+            .map_err(email_conflict)?;
         Ok(())
     }
     async fn update_user(&self, user: &User) -> Result<()> {
@@ -187,8 +487,10 @@ impl DBConnection for Mutex<SqliteConnection> {
             .bind(&user.email)
             .bind(&user.password)
             .bind(&user.roles)
+            .bind(user.verified)
             .execute(&mut *db)
-            .await?;
+            .await
+            .map_err(email_conflict)?;
         Ok(())
     }
     async fn delete_user_by_id(&self, user_id: i32) -> Result<()> {
@@ -228,6 +530,177 @@ impl DBConnection for Mutex<SqliteConnection> {
         let ids = query_scalar(GET_ALL).fetch_all(&mut *db).await?;
         Ok(ids)
     }
+    async fn get_session_version(&self, user_id: i32) -> Result<i64> {
+        let mut db = self.lock().await;
+        let version = query_scalar(SELECT_SESSION_VERSION)
+            .bind(user_id)
+            .fetch_one(&mut *db)
+            .await?;
+        Ok(version)
+    }
+    async fn bump_session_version(&self, user_id: i32) -> Result<()> {
+        query(BUMP_SESSION_VERSION)
+            .bind(user_id)
+            .execute(&mut *self.lock().await)
+            .await?;
+        Ok(())
+    }
+    async fn create_api_key(&self, key: &ApiKey) -> Result<()> {
+        let mut db = self.lock().await;
+        query(INSERT_API_KEY)
+            .bind(&key.id)
+            .bind(key.user_id)
+            .bind(&key.name)
+            .bind(&key.hash)
+            .bind(key.scopes.to_string())
+            .bind(key.created)
+            .bind(key.expires)
+            .bind(key.last_used)
+            .execute(&mut *db)
+            .await?;
+        Ok(())
+    }
+    async fn get_api_key(&self, key_id: &str) -> Result<ApiKey> {
+        let mut db = self.lock().await;
+        let key = query_as(SELECT_API_KEY)
+            .bind(key_id)
+            .fetch_one(&mut *db)
+            .await?;
+        Ok(key)
+    }
+    async fn get_api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>> {
+        let mut db = self.lock().await;
+        let keys = query_as(SELECT_API_KEYS_BY_USER)
+            .bind(user_id)
+            .fetch_all(&mut *db)
+            .await?;
+        Ok(keys)
+    }
+    async fn touch_api_key(&self, key_id: &str, last_used: i64) -> Result<()> {
+        query(TOUCH_API_KEY)
+            .bind(key_id)
+            .bind(last_used)
+            .execute(&mut *self.lock().await)
+            .await?;
+        Ok(())
+    }
+    async fn delete_api_key(&self, key_id: &str) -> Result<()> {
+        query(REMOVE_API_KEY)
+            .bind(key_id)
+            .execute(&mut *self.lock().await)
+            .await?;
+        Ok(())
+    }
+    async fn create_password_reset(&self, reset: &PasswordReset) -> Result<()> {
+        query(INSERT_PASSWORD_RESET)
+            .bind(reset.user_id)
+            .bind(&reset.id)
+            .bind(&reset.hash)
+            .bind(reset.expires)
+            .execute(&mut *self.lock().await)
+            .await?;
+        Ok(())
+    }
+    async fn get_password_reset(&self, reset_id: &str) -> Result<PasswordReset> {
+        let mut db = self.lock().await;
+        let reset = query_as(SELECT_PASSWORD_RESET)
+            .bind(reset_id)
+            .fetch_one(&mut *db)
+            .await?;
+        Ok(reset)
+    }
+    async fn delete_password_reset(&self, user_id: i32) -> Result<()> {
+        query(REMOVE_PASSWORD_RESET)
+            .bind(user_id)
+            .execute(&mut *self.lock().await)
+            .await?;
+        Ok(())
+    }
+    async fn consume_password_reset(&self, reset_id: &str) -> Result<bool> {
+        let result = query(CONSUME_PASSWORD_RESET)
+            .bind(reset_id)
+            .execute(&mut *self.lock().await)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+    async fn create_email_change(&self, change: &EmailChange) -> Result<()> {
+        query(INSERT_EMAIL_CHANGE)
+            .bind(change.user_id)
+            .bind(&change.id)
+            .bind(&change.email)
+            .bind(&change.hash)
+            .bind(change.expires)
+            .execute(&mut *self.lock().await)
+            .await?;
+        Ok(())
+    }
+    async fn get_email_change(&self, change_id: &str) -> Result<EmailChange> {
+        let mut db = self.lock().await;
+        let change = query_as(SELECT_EMAIL_CHANGE)
+            .bind(change_id)
+            .fetch_one(&mut *db)
+            .await?;
+        Ok(change)
+    }
+    async fn delete_email_change(&self, user_id: i32) -> Result<()> {
+        query(REMOVE_EMAIL_CHANGE)
+            .bind(user_id)
+            .execute(&mut *self.lock().await)
+            .await?;
+        Ok(())
+    }
+    async fn consume_email_change(&self, change_id: &str) -> Result<bool> {
+        let result = query(CONSUME_EMAIL_CHANGE)
+            .bind(change_id)
+            .execute(&mut *self.lock().await)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+    async fn set_totp_secret(&self, secret: &TotpSecret) -> Result<()> {
+        query(INSERT_TOTP_SECRET)
+            .bind(secret.user_id)
+            .bind(&secret.secret)
+            .bind(secret.enabled)
+            .bind(secret.last_step)
+            .bind(secret.failures)
+            .bind(secret.last_attempt)
+            .execute(&mut *self.lock().await)
+            .await?;
+        Ok(())
+    }
+    async fn get_totp_secret(&self, user_id: i32) -> Result<Option<TotpSecret>> {
+        let mut db = self.lock().await;
+        let secret = query_as(SELECT_TOTP_SECRET)
+            .bind(user_id)
+            .fetch_optional(&mut *db)
+            .await?;
+        Ok(secret)
+    }
+    async fn use_totp_step(&self, user_id: i32, step: i64) -> Result<bool> {
+        let result = query(USE_TOTP_STEP)
+            .bind(user_id)
+            .bind(step)
+            .execute(&mut *self.lock().await)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+    async fn count_totp_attempt(&self, user_id: i32, max: i32, since: i64) -> Result<bool> {
+        let result = query(COUNT_TOTP_ATTEMPT)
+            .bind(user_id)
+            .bind(max)
+            .bind(since)
+            .bind(now())
+            .execute(&mut *self.lock().await)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+    async fn delete_totp_secret(&self, user_id: i32) -> Result<()> {
+        query(REMOVE_TOTP_SECRET)
+            .bind(user_id)
+            .execute(&mut *self.lock().await)
+            .await?;
+        Ok(())
+    }
 }
 #[cfg(feature = "sqlx-sqlite")]
 #[rocket::async_trait]
@@ -236,6 +709,18 @@ impl DBConnection for SqlitePool {
         query(CREATE_TABLE) //
             .execute(self)
             .await?;
+        let has_verified: i64 = query_scalar(HAS_VERIFIED_COLUMN).fetch_one(self).await?;
+        if has_verified == 0 {
+            query(ADD_VERIFIED_COLUMN).execute(self).await?;
+        }
+        query(CREATE_SESSIONS_TABLE).execute(self).await?;
+        query(CREATE_SESSIONS_INDEX).execute(self).await?;
+        query(CREATE_SESSION_VERSIONS_TABLE).execute(self).await?;
+        query(CREATE_API_KEYS_TABLE).execute(self).await?;
+        query(CREATE_API_KEYS_INDEX).execute(self).await?;
+        query(CREATE_PASSWORD_RESETS_TABLE).execute(self).await?;
+        query(CREATE_EMAIL_CHANGES_TABLE).execute(self).await?;
+        query(CREATE_TOTP_SECRETS_TABLE).execute(self).await?;
         Ok(())
     }
     async fn create_user(&self, email: &str, hash: &str, roles: &Roles) -> Result<()> {
@@ -244,7 +729,8 @@ impl DBConnection for SqlitePool {
             .bind(hash)
             .bind(roles)
             .execute(self)
-            .await?;
+            .await
+            .map_err(email_conflict)?;
         Ok(())
     }
     async fn update_user(&self, user: &User) -> Result<()> {
@@ -253,8 +739,10 @@ impl DBConnection for SqlitePool {
             .bind(&user.email)
             .bind(&user.password)
             .bind(&user.roles)
+            .bind(user.verified)
             .execute(self)
-            .await?;
+            .await
+            .map_err(email_conflict)?;
         Ok(())
     }
     async fn delete_user_by_id(&self, user_id: i32) -> Result<()> {
@@ -287,4 +775,372 @@ impl DBConnection for SqlitePool {
         let ids = query_scalar(GET_ALL).fetch_all(self).await?;
         Ok(ids)
     }
+    async fn get_session_version(&self, user_id: i32) -> Result<i64> {
+        let version = query_scalar(SELECT_SESSION_VERSION)
+            .bind(user_id)
+            .fetch_one(self)
+            .await?;
+        Ok(version)
+    }
+    async fn bump_session_version(&self, user_id: i32) -> Result<()> {
+        query(BUMP_SESSION_VERSION)
+            .bind(user_id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+    async fn create_api_key(&self, key: &ApiKey) -> Result<()> {
+        query(INSERT_API_KEY)
+            .bind(&key.id)
+            .bind(key.user_id)
+            .bind(&key.name)
+            .bind(&key.hash)
+            .bind(key.scopes.to_string())
+            .bind(key.created)
+            .bind(key.expires)
+            .bind(key.last_used)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+    async fn get_api_key(&self, key_id: &str) -> Result<ApiKey> {
+        let key = query_as(SELECT_API_KEY)
+            .bind(key_id)
+            .fetch_one(self)
+            .await?;
+        Ok(key)
+    }
+    async fn get_api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>> {
+        let keys = query_as(SELECT_API_KEYS_BY_USER)
+            .bind(user_id)
+            .fetch_all(self)
+            .await?;
+        Ok(keys)
+    }
+    async fn touch_api_key(&self, key_id: &str, last_used: i64) -> Result<()> {
+        query(TOUCH_API_KEY)
+            .bind(key_id)
+            .bind(last_used)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+    async fn delete_api_key(&self, key_id: &str) -> Result<()> {
+        query(REMOVE_API_KEY).bind(key_id).execute(self).await?;
+        Ok(())
+    }
+    async fn create_password_reset(&self, reset: &PasswordReset) -> Result<()> {
+        query(INSERT_PASSWORD_RESET)
+            .bind(reset.user_id)
+            .bind(&reset.id)
+            .bind(&reset.hash)
+            .bind(reset.expires)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+    async fn get_password_reset(&self, reset_id: &str) -> Result<PasswordReset> {
+        let reset = query_as(SELECT_PASSWORD_RESET)
+            .bind(reset_id)
+            .fetch_one(self)
+            .await?;
+        Ok(reset)
+    }
+    async fn delete_password_reset(&self, user_id: i32) -> Result<()> {
+        query(REMOVE_PASSWORD_RESET)
+            .bind(user_id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+    async fn consume_password_reset(&self, reset_id: &str) -> Result<bool> {
+        let result = query(CONSUME_PASSWORD_RESET)
+            .bind(reset_id)
+            .execute(self)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+    async fn create_email_change(&self, change: &EmailChange) -> Result<()> {
+        query(INSERT_EMAIL_CHANGE)
+            .bind(change.user_id)
+            .bind(&change.id)
+            .bind(&change.email)
+            .bind(&change.hash)
+            .bind(change.expires)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+    async fn get_email_change(&self, change_id: &str) -> Result<EmailChange> {
+        let change = query_as(SELECT_EMAIL_CHANGE)
+            .bind(change_id)
+            .fetch_one(self)
+            .await?;
+        Ok(change)
+    }
+    async fn delete_email_change(&self, user_id: i32) -> Result<()> {
+        query(REMOVE_EMAIL_CHANGE)
+            .bind(user_id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+    async fn consume_email_change(&self, change_id: &str) -> Result<bool> {
+        let result = query(CONSUME_EMAIL_CHANGE)
+            .bind(change_id)
+            .execute(self)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+    async fn set_totp_secret(&self, secret: &TotpSecret) -> Result<()> {
+        query(INSERT_TOTP_SECRET)
+            .bind(secret.user_id)
+            .bind(&secret.secret)
+            .bind(secret.enabled)
+            .bind(secret.last_step)
+            .bind(secret.failures)
+            .bind(secret.last_attempt)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+    async fn get_totp_secret(&self, user_id: i32) -> Result<Option<TotpSecret>> {
+        let secret = query_as(SELECT_TOTP_SECRET)
+            .bind(user_id)
+            .fetch_optional(self)
+            .await?;
+        Ok(secret)
+    }
+    async fn use_totp_step(&self, user_id: i32, step: i64) -> Result<bool> {
+        let result = query(USE_TOTP_STEP)
+            .bind(user_id)
+            .bind(step)
+            .execute(self)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+    async fn count_totp_attempt(&self, user_id: i32, max: i32, since: i64) -> Result<bool> {
+        let result = query(COUNT_TOTP_ATTEMPT)
+            .bind(user_id)
+            .bind(max)
+            .bind(since)
+            .bind(now())
+            .execute(self)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+    async fn delete_totp_secret(&self, user_id: i32) -> Result<()> {
+        query(REMOVE_TOTP_SECRET)
+            .bind(user_id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlx-sqlite")]
+#[rocket::async_trait]
+impl SessionManager for SqlitePool {
+    async fn insert(&self, session_id: &str, key: AuthKey) -> Result<()> {
+        self.insert_for(session_id, key, Duration::from_secs(YEAR_IN_SECS))
+            .await
+    }
+    async fn insert_for(&self, session_id: &str, mut key: AuthKey, time: Duration) -> Result<()> {
+        key.expire_in(time);
+        query(INSERT_SESSION)
+            .bind(session_id)
+            .bind(key.user_id)
+            .bind(&key.secret)
+            .bind(key.expires)
+            .bind(key.idle_expires)
+            .bind(key.time_stamp)
+            .bind(key.last_active)
+            .bind(&key.ip)
+            .bind(&key.user_agent)
+            .bind(key.rotate)
+            .bind(&key.used_secrets)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+    async fn remove(&self, session_id: &str) -> Result<()> {
+        query(REMOVE_SESSION).bind(session_id).execute(self).await?;
+        Ok(())
+    }
+    async fn take(&self, session_id: &str) -> Result<Option<AuthKey>> {
+        let Some(key) = SessionManager::get(self, session_id).await else {
+            return Ok(None);
+        };
+        let result = query(TAKE_SESSION)
+            .bind(session_id)
+            .bind(&key.secret)
+            .execute(self)
+            .await?;
+        Ok((result.rows_affected() == 1).then_some(key))
+    }
+    async fn remove_user(&self, user_id: i32) -> Result<()> {
+        query(REMOVE_SESSIONS_BY_USER)
+            .bind(user_id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+    async fn get(&self, session_id: &str) -> Option<AuthKey> {
+        query_as(SELECT_SESSION)
+            .bind(session_id)
+            .bind(now())
+            .fetch_optional(self)
+            .await
+            .ok()?
+    }
+    async fn list(&self, user_id: i32) -> Result<Vec<(String, AuthKey)>> {
+        let rows = query(SELECT_SESSIONS_BY_USER)
+            .bind(user_id)
+            .bind(now())
+            .fetch_all(self)
+            .await?;
+        let mut sessions = vec![];
+        for row in rows {
+            sessions.push((row.try_get("id")?, AuthKey::from_row(&row)?));
+        }
+        Ok(sessions)
+    }
+    async fn touch(&self, session_id: &str, idle_timeout: Option<Duration>) -> Result<()> {
+        query(TOUCH_SESSION)
+            .bind(session_id)
+            .bind(now())
+            .bind(idle_deadline(idle_timeout))
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+    async fn clear_all(&self) -> Result<()> {
+        query(REMOVE_ALL_SESSIONS).execute(self).await?;
+        Ok(())
+    }
+    async fn clear_expired(&self) -> Result<usize> {
+        let now = now();
+        let removed = query(REMOVE_EXPIRED_SESSIONS)
+            .bind(now)
+            .execute(self)
+            .await?
+            .rows_affected();
+        Ok(removed as usize)
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-sqlite"))]
+mod test {
+    use super::*;
+    use crate::session::ClientInfo;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    /// The `users` table as it was created before emails could be verified.
+    const OLD_CREATE_TABLE: &str = "
+    CREATE TABLE users (
+        id INTEGER PRIMARY KEY,
+        email TEXT UNIQUE,
+        password TEXT NOT NULL,
+        roles BLOB NOT NULL
+    );";
+
+    async fn empty_pool() -> SqlitePool {
+        // every connection to `sqlite::memory:` opens a separate database.
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap()
+    }
+
+    async fn pool() -> SqlitePool {
+        let pool = empty_pool().await;
+        DBConnection::init(&pool).await.unwrap();
+        pool
+    }
+
+    fn key(user_id: i32, secret: &str) -> AuthKey {
+        AuthKey::new(user_id, secret.into(), &ClientInfo::default())
+    }
+
+    #[tokio::test]
+    async fn test_sessions_are_stored_in_sql() {
+        let pool = pool().await;
+        SessionManager::insert(&pool, "laptop", key(1, "first"))
+            .await
+            .unwrap();
+        SessionManager::insert(&pool, "phone", key(1, "second"))
+            .await
+            .unwrap();
+        SessionManager::insert(&pool, "other", key(2, "third"))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            SessionManager::get(&pool, "laptop").await.unwrap().secret,
+            "first"
+        );
+        assert_eq!(SessionManager::list(&pool, 1).await.unwrap().len(), 2);
+
+        SessionManager::remove_user(&pool, 1).await.unwrap();
+        assert!(SessionManager::get(&pool, "laptop").await.is_none());
+        assert!(SessionManager::get(&pool, "other").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_sessions_are_taken_once() {
+        let pool = pool().await;
+        SessionManager::insert(&pool, "laptop", key(1, "first"))
+            .await
+            .unwrap();
+
+        let taken = SessionManager::take(&pool, "laptop").await.unwrap();
+        assert_eq!(taken.unwrap().secret, "first");
+        assert!(SessionManager::take(&pool, "laptop")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(SessionManager::get(&pool, "laptop").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_expired_sessions_are_removed_in_sql() {
+        let pool = pool().await;
+        SessionManager::insert_for(&pool, "expired", key(1, "first"), Duration::ZERO)
+            .await
+            .unwrap();
+        SessionManager::insert(&pool, "live", key(1, "second"))
+            .await
+            .unwrap();
+
+        assert!(SessionManager::get(&pool, "expired").await.is_none());
+        assert_eq!(SessionManager::clear_expired(&pool).await.unwrap(), 1);
+        assert!(SessionManager::get(&pool, "live").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_init_adds_verified_column_to_old_tables() {
+        let pool = empty_pool().await;
+        query(OLD_CREATE_TABLE).execute(&pool).await.unwrap();
+        DBConnection::create_user(&pool, "old@example.com", "hash", &Roles::default())
+            .await
+            .unwrap();
+
+        DBConnection::init(&pool).await.unwrap();
+        // a second run finds the column and leaves the table alone.
+        DBConnection::init(&pool).await.unwrap();
+
+        let mut user = DBConnection::get_user_by_email(&pool, "old@example.com")
+            .await
+            .unwrap();
+        assert!(!user.verified);
+        user.verified = true;
+        DBConnection::update_user(&pool, &user).await.unwrap();
+        assert!(
+            DBConnection::get_user_by_id(&pool, user.id)
+                .await
+                .unwrap()
+                .verified
+        );
+    }
 }
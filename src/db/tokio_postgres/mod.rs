@@ -1,6 +1,12 @@
 use crate::prelude::*;
 mod sql;
+use crate::db::email_conflict;
+use crate::session::{idle_deadline, AuthKey, YEAR_IN_SECS};
+use crate::user::api_keys::ApiKey;
+use crate::user::email_change::EmailChange;
+use crate::user::password_reset::PasswordReset;
 use crate::user::roles::Roles;
+use crate::user::totp::TotpSecret;
 use std::convert::{TryFrom, TryInto};
 use tokio_postgres::types::private::BytesMut;
 use tokio_postgres::types::{FromSql, IsNull, ToSql, Type};
@@ -54,19 +60,37 @@ impl<'a> FromSql<'a> for Roles {
 impl DBConnection for Client {
     async fn init(&self) -> Result<()> {
         self.execute(sql::CREATE_TABLE, &[]).await?;
+        self.execute(sql::ADD_VERIFIED_COLUMN, &[]).await?;
+        self.execute(sql::CREATE_SESSIONS_TABLE, &[]).await?;
+        self.execute(sql::CREATE_SESSIONS_INDEX, &[]).await?;
+        self.execute(sql::CREATE_SESSION_VERSIONS_TABLE, &[])
+            .await?;
+        self.execute(sql::CREATE_API_KEYS_TABLE, &[]).await?;
+        self.execute(sql::CREATE_API_KEYS_INDEX, &[]).await?;
+        self.execute(sql::CREATE_PASSWORD_RESETS_TABLE, &[]).await?;
+        self.execute(sql::CREATE_EMAIL_CHANGES_TABLE, &[]).await?;
+        self.execute(sql::CREATE_TOTP_SECRETS_TABLE, &[]).await?;
         Ok(())
     }
     async fn create_user(&self, email: &str, hash: &str, roles: &Roles) -> Result<(), Error> {
         self.execute(sql::INSERT_USER, &[&email, &hash, roles])
-            .await?;
+            .await
+            .map_err(email_conflict)?;
         Ok(())
     }
     async fn update_user(&self, user: &User) -> Result<()> {
         self.execute(
             sql::UPDATE_USER,
-            &[&user.email, &user.password, &user.roles],
+            &[
+                &user.id,
+                &user.email,
+                &user.password,
+                &user.roles,
+                &user.verified,
+            ],
         )
-        .await?;
+        .await
+        .map_err(email_conflict)?;
         Ok(())
     }
     async fn delete_user_by_id(&self, user_id: i32) -> Result<()> {
@@ -95,6 +119,159 @@ impl DBConnection for Client {
             .collect();
         Ok(ids)
     }
+
+    async fn get_session_version(&self, user_id: i32) -> Result<i64> {
+        let row = self
+            .query_one(sql::SELECT_SESSION_VERSION, &[&user_id])
+            .await?;
+        Ok(row.try_get(0)?)
+    }
+
+    async fn bump_session_version(&self, user_id: i32) -> Result<()> {
+        self.execute(sql::BUMP_SESSION_VERSION, &[&user_id]).await?;
+        Ok(())
+    }
+
+    async fn create_api_key(&self, key: &ApiKey) -> Result<()> {
+        self.execute(
+            sql::INSERT_API_KEY,
+            &[
+                &key.id,
+                &key.user_id,
+                &key.name,
+                &key.hash,
+                &key.scopes.to_string(),
+                &key.created,
+                &key.expires,
+                &key.last_used,
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn get_api_key(&self, key_id: &str) -> Result<ApiKey> {
+        let key = self.query_one(sql::SELECT_API_KEY, &[&key_id]).await?;
+        key.try_into()
+    }
+
+    async fn get_api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>> {
+        let rows = self
+            .query(sql::SELECT_API_KEYS_BY_USER, &[&user_id])
+            .await?;
+        rows.into_iter().map(TryInto::try_into).collect()
+    }
+
+    async fn touch_api_key(&self, key_id: &str, last_used: i64) -> Result<()> {
+        self.execute(sql::TOUCH_API_KEY, &[&key_id, &last_used])
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_api_key(&self, key_id: &str) -> Result<()> {
+        self.execute(sql::REMOVE_API_KEY, &[&key_id]).await?;
+        Ok(())
+    }
+
+    async fn create_password_reset(&self, reset: &PasswordReset) -> Result<()> {
+        self.execute(
+            sql::INSERT_PASSWORD_RESET,
+            &[&reset.user_id, &reset.id, &reset.hash, &reset.expires],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn get_password_reset(&self, reset_id: &str) -> Result<PasswordReset> {
+        let reset = self
+            .query_one(sql::SELECT_PASSWORD_RESET, &[&reset_id])
+            .await?;
+        reset.try_into()
+    }
+
+    async fn delete_password_reset(&self, user_id: i32) -> Result<()> {
+        self.execute(sql::REMOVE_PASSWORD_RESET, &[&user_id])
+            .await?;
+        Ok(())
+    }
+
+    async fn consume_password_reset(&self, reset_id: &str) -> Result<bool> {
+        let deleted = self
+            .execute(sql::CONSUME_PASSWORD_RESET, &[&reset_id])
+            .await?;
+        Ok(deleted == 1)
+    }
+
+    async fn create_email_change(&self, change: &EmailChange) -> Result<()> {
+        self.execute(
+            sql::INSERT_EMAIL_CHANGE,
+            &[
+                &change.user_id,
+                &change.id,
+                &change.email,
+                &change.hash,
+                &change.expires,
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn get_email_change(&self, change_id: &str) -> Result<EmailChange> {
+        let change = self
+            .query_one(sql::SELECT_EMAIL_CHANGE, &[&change_id])
+            .await?;
+        change.try_into()
+    }
+
+    async fn delete_email_change(&self, user_id: i32) -> Result<()> {
+        self.execute(sql::REMOVE_EMAIL_CHANGE, &[&user_id]).await?;
+        Ok(())
+    }
+    async fn consume_email_change(&self, change_id: &str) -> Result<bool> {
+        let deleted = self
+            .execute(sql::CONSUME_EMAIL_CHANGE, &[&change_id])
+            .await?;
+        Ok(deleted == 1)
+    }
+
+    async fn set_totp_secret(&self, secret: &TotpSecret) -> Result<()> {
+        self.execute(
+            sql::INSERT_TOTP_SECRET,
+            &[
+                &secret.user_id,
+                &secret.secret,
+                &secret.enabled,
+                &secret.last_step,
+                &secret.failures,
+                &secret.last_attempt,
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn get_totp_secret(&self, user_id: i32) -> Result<Option<TotpSecret>> {
+        let secret = self.query_opt(sql::SELECT_TOTP_SECRET, &[&user_id]).await?;
+        secret.map(TryInto::try_into).transpose()
+    }
+
+    async fn use_totp_step(&self, user_id: i32, step: i64) -> Result<bool> {
+        let changed = self.execute(sql::USE_TOTP_STEP, &[&user_id, &step]).await?;
+        Ok(changed == 1)
+    }
+
+    async fn count_totp_attempt(&self, user_id: i32, max: i32, since: i64) -> Result<bool> {
+        let changed = self
+            .execute(sql::COUNT_TOTP_ATTEMPT, &[&user_id, &max, &since, &now()])
+            .await?;
+        Ok(changed == 1)
+    }
+
+    async fn delete_totp_secret(&self, user_id: i32) -> Result<()> {
+        self.execute(sql::REMOVE_TOTP_SECRET, &[&user_id]).await?;
+        Ok(())
+    }
 }
 
 impl TryFrom<tokio_postgres::Row> for User {
@@ -105,6 +282,162 @@ impl TryFrom<tokio_postgres::Row> for User {
             email: row.get(1),
             password: row.get(2),
             roles: row.get(3),
+            verified: row.get(4),
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl SessionManager for Client {
+    async fn insert(&self, session_id: &str, key: AuthKey) -> Result<()> {
+        self.insert_for(session_id, key, Duration::from_secs(YEAR_IN_SECS))
+            .await
+    }
+    async fn insert_for(&self, session_id: &str, mut key: AuthKey, time: Duration) -> Result<()> {
+        key.expire_in(time);
+        self.execute(
+            sql::INSERT_SESSION,
+            &[
+                &session_id,
+                &key.user_id,
+                &key.secret,
+                &key.expires,
+                &key.idle_expires,
+                &key.time_stamp,
+                &key.last_active,
+                &key.ip,
+                &key.user_agent,
+                &key.rotate,
+                &key.used_secrets,
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+    async fn remove(&self, session_id: &str) -> Result<()> {
+        self.execute(sql::REMOVE_SESSION, &[&session_id]).await?;
+        Ok(())
+    }
+    async fn take(&self, session_id: &str) -> Result<Option<AuthKey>> {
+        let Some(key) = SessionManager::get(self, session_id).await else {
+            return Ok(None);
+        };
+        let removed = self
+            .execute(sql::TAKE_SESSION, &[&session_id, &key.secret])
+            .await?;
+        Ok((removed == 1).then_some(key))
+    }
+    async fn remove_user(&self, user_id: i32) -> Result<()> {
+        self.execute(sql::REMOVE_SESSIONS_BY_USER, &[&user_id])
+            .await?;
+        Ok(())
+    }
+    async fn get(&self, session_id: &str) -> Option<AuthKey> {
+        let key = self
+            .query_opt(sql::SELECT_SESSION, &[&session_id, &now()])
+            .await
+            .ok()??;
+        key.try_into().ok()
+    }
+    async fn list(&self, user_id: i32) -> Result<Vec<(String, AuthKey)>> {
+        let rows = self
+            .query(sql::SELECT_SESSIONS_BY_USER, &[&user_id, &now()])
+            .await?;
+        let mut sessions = vec![];
+        for row in rows {
+            sessions.push((row.get("id"), row.try_into()?));
+        }
+        Ok(sessions)
+    }
+    async fn touch(&self, session_id: &str, idle_timeout: Option<Duration>) -> Result<()> {
+        self.execute(
+            sql::TOUCH_SESSION,
+            &[&session_id, &now(), &idle_deadline(idle_timeout)],
+        )
+        .await?;
+        Ok(())
+    }
+    async fn clear_all(&self) -> Result<()> {
+        self.execute(sql::REMOVE_ALL_SESSIONS, &[]).await?;
+        Ok(())
+    }
+    async fn clear_expired(&self) -> Result<usize> {
+        let removed = self
+            .execute(sql::REMOVE_EXPIRED_SESSIONS, &[&now()])
+            .await?;
+        Ok(removed as usize)
+    }
+}
+
+impl TryFrom<tokio_postgres::Row> for AuthKey {
+    type Error = Error;
+    fn try_from(row: tokio_postgres::Row) -> Result<AuthKey> {
+        Ok(AuthKey {
+            user_id: row.get("user_id"),
+            expires: row.get("expires"),
+            idle_expires: row.get("idle_expires"),
+            secret: row.get("secret"),
+            time_stamp: row.get("time_stamp"),
+            last_active: row.get("last_active"),
+            ip: row.get("ip"),
+            user_agent: row.get("user_agent"),
+            rotate: row.get("rotate"),
+            used_secrets: row.get("used_secrets"),
+        })
+    }
+}
+
+impl TryFrom<tokio_postgres::Row> for ApiKey {
+    type Error = Error;
+    fn try_from(row: tokio_postgres::Row) -> Result<ApiKey> {
+        Ok(ApiKey {
+            id: row.get("id"),
+            user_id: row.get("user_id"),
+            name: row.get("name"),
+            hash: row.get("hash"),
+            scopes: row.get::<_, String>("scopes").into(),
+            created: row.get("created"),
+            expires: row.get("expires"),
+            last_used: row.get("last_used"),
+        })
+    }
+}
+
+impl TryFrom<tokio_postgres::Row> for PasswordReset {
+    type Error = Error;
+    fn try_from(row: tokio_postgres::Row) -> Result<PasswordReset> {
+        Ok(PasswordReset {
+            id: row.get("id"),
+            user_id: row.get("user_id"),
+            hash: row.get("hash"),
+            expires: row.get("expires"),
+        })
+    }
+}
+
+impl TryFrom<tokio_postgres::Row> for EmailChange {
+    type Error = Error;
+    fn try_from(row: tokio_postgres::Row) -> Result<EmailChange> {
+        Ok(EmailChange {
+            id: row.get("id"),
+            user_id: row.get("user_id"),
+            email: row.get("email"),
+            hash: row.get("hash"),
+            expires: row.get("expires"),
+        })
+    }
+}
+
+impl TryFrom<tokio_postgres::Row> for TotpSecret {
+    type Error = Error;
+    fn try_from(row: tokio_postgres::Row) -> Result<TotpSecret> {
+        Ok(TotpSecret {
+            user_id: row.get("user_id"),
+            secret: row.get("secret"),
+            enabled: row.get("enabled"),
+            last_step: row.get("last_step"),
+            failures: row.get("failures"),
+            last_attempt: row.get("last_attempt"),
         })
     }
 }
@@ -3,19 +3,26 @@ CREATE TABLE IF NOT EXISTS users (
     id SERIAL PRIMARY KEY,
     email VARCHAR (254) UNIQUE NOT NULL,
 	password VARCHAR ( 255 ) NOT NULL,
-    roles BYTEA NOT NULL
+    roles BYTEA NOT NULL,
+    verified BOOLEAN NOT NULL DEFAULT FALSE
 );
 ";
 
+/// Databases created before emails could be verified lack the `verified` column.
+pub(crate) const ADD_VERIFIED_COLUMN: &str = "
+ALTER TABLE users ADD COLUMN IF NOT EXISTS verified BOOLEAN NOT NULL DEFAULT FALSE;
+";
+
 pub(crate) const INSERT_USER: &str = "
 INSERT INTO users (email, password, roles) VALUES ($1, $2, $3);
 ";
 
 pub(crate) const UPDATE_USER: &str = "
-UPDATE table SET 
+UPDATE users SET
     email = $2,
     password = $3,
     roles = $4,
+    verified = $5
 WHERE
     id = $1
 ";
@@ -37,3 +44,217 @@ DELETE FROM users WHERE email =$1;
 pub(crate) const GET_ALL: &str = "
 SELECT id FROM users;
 ";
+
+pub(crate) const CREATE_SESSIONS_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS sessions (
+    id VARCHAR (255) PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    secret VARCHAR (255) NOT NULL,
+    expires BIGINT NOT NULL,
+    idle_expires BIGINT,
+    time_stamp BIGINT NOT NULL,
+    last_active BIGINT NOT NULL,
+    ip VARCHAR (45),
+    user_agent TEXT,
+    rotate BOOLEAN NOT NULL DEFAULT FALSE,
+    used_secrets TEXT NOT NULL
+);
+";
+pub(crate) const CREATE_SESSIONS_INDEX: &str = "
+CREATE INDEX IF NOT EXISTS sessions_user_id ON sessions (user_id);
+";
+
+pub(crate) const INSERT_SESSION: &str = "
+INSERT INTO sessions (id, user_id, secret, expires, idle_expires, time_stamp, last_active, ip, user_agent, rotate, used_secrets)
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+ON CONFLICT (id) DO UPDATE SET
+    user_id = EXCLUDED.user_id,
+    secret = EXCLUDED.secret,
+    expires = EXCLUDED.expires,
+    idle_expires = EXCLUDED.idle_expires,
+    time_stamp = EXCLUDED.time_stamp,
+    last_active = EXCLUDED.last_active,
+    ip = EXCLUDED.ip,
+    user_agent = EXCLUDED.user_agent,
+    rotate = EXCLUDED.rotate,
+    used_secrets = EXCLUDED.used_secrets;
+";
+
+pub(crate) const SELECT_SESSION: &str = "
+SELECT * FROM sessions
+WHERE id = $1 AND expires > $2 AND (idle_expires IS NULL OR idle_expires > $2);
+";
+
+pub(crate) const SELECT_SESSIONS_BY_USER: &str = "
+SELECT * FROM sessions
+WHERE user_id = $1 AND expires > $2 AND (idle_expires IS NULL OR idle_expires > $2);
+";
+
+pub(crate) const TOUCH_SESSION: &str = "
+UPDATE sessions SET
+    last_active = $2,
+    idle_expires = $3
+WHERE
+    id = $1;
+";
+
+pub(crate) const REMOVE_SESSION: &str = "
+DELETE FROM sessions WHERE id = $1;
+";
+/// Removes a session only if its secret did not change since it was read.
+pub(crate) const TAKE_SESSION: &str = "
+DELETE FROM sessions WHERE id = $1 AND secret = $2;
+";
+pub(crate) const REMOVE_SESSIONS_BY_USER: &str = "
+DELETE FROM sessions WHERE user_id = $1;
+";
+pub(crate) const REMOVE_ALL_SESSIONS: &str = "
+DELETE FROM sessions;
+";
+pub(crate) const REMOVE_EXPIRED_SESSIONS: &str = "
+DELETE FROM sessions WHERE expires <= $1 OR idle_expires <= $1;
+";
+
+pub(crate) const CREATE_SESSION_VERSIONS_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS session_versions (
+    user_id INTEGER PRIMARY KEY,
+    version BIGINT NOT NULL
+);
+";
+
+pub(crate) const SELECT_SESSION_VERSION: &str = "
+SELECT COALESCE(session_versions.version, 0) FROM users
+LEFT JOIN session_versions ON session_versions.user_id = users.id
+WHERE users.id = $1;
+";
+
+pub(crate) const BUMP_SESSION_VERSION: &str = "
+INSERT INTO session_versions (user_id, version) VALUES ($1, 1)
+ON CONFLICT (user_id) DO UPDATE SET version = session_versions.version + 1;
+";
+
+pub(crate) const CREATE_API_KEYS_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS api_keys (
+    id TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    scopes TEXT NOT NULL,
+    created BIGINT NOT NULL,
+    expires BIGINT,
+    last_used BIGINT
+);
+";
+
+pub(crate) const CREATE_API_KEYS_INDEX: &str = "
+CREATE INDEX IF NOT EXISTS api_keys_user_id ON api_keys (user_id);
+";
+
+pub(crate) const INSERT_API_KEY: &str = "
+INSERT INTO api_keys (id, user_id, name, hash, scopes, created, expires, last_used)
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8);
+";
+
+pub(crate) const SELECT_API_KEY: &str = "
+SELECT * FROM api_keys WHERE id = $1;
+";
+
+pub(crate) const SELECT_API_KEYS_BY_USER: &str = "
+SELECT * FROM api_keys WHERE user_id = $1 ORDER BY created;
+";
+
+pub(crate) const TOUCH_API_KEY: &str = "
+UPDATE api_keys SET last_used = $2 WHERE id = $1;
+";
+
+pub(crate) const REMOVE_API_KEY: &str = "
+DELETE FROM api_keys WHERE id = $1;
+";
+
+pub(crate) const CREATE_PASSWORD_RESETS_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS password_resets (
+    user_id INTEGER PRIMARY KEY,
+    id TEXT NOT NULL UNIQUE,
+    hash TEXT NOT NULL,
+    expires BIGINT NOT NULL
+);
+";
+
+pub(crate) const INSERT_PASSWORD_RESET: &str = "
+INSERT INTO password_resets (user_id, id, hash, expires) VALUES ($1, $2, $3, $4)
+ON CONFLICT (user_id) DO UPDATE SET id = excluded.id, hash = excluded.hash, expires = excluded.expires;
+";
+
+pub(crate) const SELECT_PASSWORD_RESET: &str = "
+SELECT * FROM password_resets WHERE id = $1;
+";
+
+pub(crate) const REMOVE_PASSWORD_RESET: &str = "
+DELETE FROM password_resets WHERE user_id = $1;
+";
+
+pub(crate) const CONSUME_PASSWORD_RESET: &str = "
+DELETE FROM password_resets WHERE id = $1;
+";
+
+pub(crate) const CREATE_EMAIL_CHANGES_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS email_changes (
+    user_id INTEGER PRIMARY KEY,
+    id TEXT NOT NULL UNIQUE,
+    email VARCHAR (254) NOT NULL,
+    hash TEXT NOT NULL,
+    expires BIGINT NOT NULL
+);
+";
+
+pub(crate) const INSERT_EMAIL_CHANGE: &str = "
+INSERT INTO email_changes (user_id, id, email, hash, expires) VALUES ($1, $2, $3, $4, $5)
+ON CONFLICT (user_id) DO UPDATE SET id = excluded.id, email = excluded.email, hash = excluded.hash, expires = excluded.expires;
+";
+
+pub(crate) const SELECT_EMAIL_CHANGE: &str = "
+SELECT * FROM email_changes WHERE id = $1;
+";
+
+pub(crate) const REMOVE_EMAIL_CHANGE: &str = "
+DELETE FROM email_changes WHERE user_id = $1;
+";
+
+pub(crate) const CONSUME_EMAIL_CHANGE: &str = "
+DELETE FROM email_changes WHERE id = $1;
+";
+
+pub(crate) const CREATE_TOTP_SECRETS_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS totp_secrets (
+    user_id INTEGER PRIMARY KEY,
+    secret TEXT NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT FALSE,
+    last_step BIGINT,
+    failures INTEGER NOT NULL DEFAULT 0,
+    last_attempt BIGINT
+);
+";
+
+pub(crate) const INSERT_TOTP_SECRET: &str = "
+INSERT INTO totp_secrets (user_id, secret, enabled, last_step, failures, last_attempt) VALUES ($1, $2, $3, $4, $5, $6)
+ON CONFLICT (user_id) DO UPDATE SET secret = excluded.secret, enabled = excluded.enabled, last_step = excluded.last_step, failures = excluded.failures, last_attempt = excluded.last_attempt;
+";
+
+pub(crate) const SELECT_TOTP_SECRET: &str = "
+SELECT * FROM totp_secrets WHERE user_id = $1;
+";
+
+pub(crate) const USE_TOTP_STEP: &str = "
+UPDATE totp_secrets SET last_step = $2, failures = 0 WHERE user_id = $1 AND (last_step IS NULL OR last_step < $2);
+";
+
+pub(crate) const COUNT_TOTP_ATTEMPT: &str = "
+UPDATE totp_secrets SET
+    failures = CASE WHEN last_attempt IS NULL OR last_attempt < $3 THEN 1 ELSE failures + 1 END,
+    last_attempt = $4
+WHERE user_id = $1 AND (failures < $2 OR last_attempt IS NULL OR last_attempt < $3);
+";
+
+pub(crate) const REMOVE_TOTP_SECRET: &str = "
+DELETE FROM totp_secrets WHERE user_id = $1;
+";
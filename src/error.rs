@@ -33,6 +33,67 @@ pub enum Error {
     #[error("Incorrect email or password")]
     UnauthorizedError,
 
+    /// This error occurs when a user whose email is not verified tries to log in,
+    /// while verification is required by [`Users::set_require_verified_email`](crate::Users::set_require_verified_email).
+    #[error("The email address has not been verified yet.")]
+    UnverifiedEmailError,
+
+    /// This error is thrown when a verification is requested for an email that is already verified.
+    #[error("That email address is already verified.")]
+    EmailAlreadyVerified,
+
+    /// Thrown when a token sent to a user is malformed, expired, or was already used.
+    #[error("InvalidTokenError: the token is invalid, expired or was already used.")]
+    InvalidTokenError,
+
+    /// Thrown when a token is hashed or signed before a key was set with [`Users::set_token_key`](crate::Users::set_token_key).
+    #[error("TokenKeyMissingError: tokens require a key, set with `Users::set_token_key`.")]
+    TokenKeyMissingError,
+
+    /// Thrown when a session is used by a client that does not match the one that logged in,
+    /// see [`SessionPolicy::client_binding`](crate::SessionPolicy::client_binding). The session is ended.
+    #[error("ClientMismatchError: the session was created by a different client.")]
+    ClientMismatchError,
+
+    /// Thrown when a form or a request lacks the CSRF token of the client.
+    #[error("CsrfError: the CSRF token is missing or invalid.")]
+    CsrfError,
+
+    /// Thrown when an API key is created with a scope that is empty or contains whitespace.
+    #[error("InvalidScopeError: \"{0}\" is not a valid scope.")]
+    InvalidScopeError(String),
+
+    /// Thrown when the requested API key does not exist.
+    #[error("Could not find the requested API key.")]
+    ApiKeyNotFoundError,
+
+    /// Thrown when a user with two-factor authentication enabled logs in through a method that only checks the password,
+    /// such as basic authentication, or when [`Auth::login`](crate::Auth::login) is waiting for the second factor.
+    #[error("A second factor is required to log in.")]
+    SecondFactorRequiredError,
+
+    /// Thrown when a TOTP code is wrong, expired, or was already used.
+    #[error("The code is invalid or was already used.")]
+    InvalidTotpCodeError,
+
+    /// Thrown when too many wrong TOTP codes were entered in a row for a user. No code is accepted, and logins
+    /// waiting for their second factor are abandoned, until 15 minutes passed since the last code was tried,
+    /// or until [`Users::unlock_totp`](crate::Users::unlock_totp) is called.
+    #[error("Too many wrong codes were entered. Two-factor authentication is locked.")]
+    TotpLockedError,
+
+    /// Thrown when enrolling a user in TOTP who already has it enabled.
+    #[error("Two-factor authentication is already enabled.")]
+    TotpAlreadyEnabledError,
+
+    /// Thrown when confirming TOTP for a user who was not enrolled with [`Users::enroll_totp`](crate::Users::enroll_totp).
+    #[error("Two-factor authentication was not set up.")]
+    TotpNotEnrolledError,
+
+    /// Thrown when a [`Mailer`](crate::Mailer) fails to deliver an email.
+    #[error("MailError: {0}")]
+    MailError(String),
+
     /// A wrapper around [`validator::ValidationError`].
     #[error("{0}")]
     FormValidationError(#[from] validator::ValidationError),
@@ -42,7 +103,7 @@ pub enum Error {
     FormValidationErrors(#[from] validator::ValidationErrors),
 
     /// A wrapper around [`sqlx::Error`].
-    #[cfg(any(feature = "sqlx"))]
+    #[cfg(feature = "sqlx")]
     #[error("SqlxError: {0}")]
     SqlxError(#[from] sqlx::Error),
     /// A wrapper around [`argon2::Error`].
@@ -69,7 +130,6 @@ pub enum Error {
     SerdeError(#[from] serde_json::Error),
 
     /// A wrapper around [`std::io::Error`].
-    #[cfg(feature = "sqlx-postgres")]
     #[error("IOError: {0}")]
     IOError(#[from] std::io::Error),
 
@@ -87,6 +147,16 @@ pub enum Error {
     #[cfg(feature = "sled")]
     #[error("BsonDeserializeError: {0}")]
     BsonDeserializeError(#[from] bson::de::Error),
+
+    /// Thrown when JSON web tokens are used before they were configured with `Users::set_jwt_config`.
+    #[cfg(feature = "jwt")]
+    #[error("JwtNotConfiguredError: JSON web tokens require a call to `Users::set_jwt_config`.")]
+    JwtNotConfiguredError,
+
+    /// A wrapper around [`jsonwebtoken::errors::Error`].
+    #[cfg(feature = "jwt")]
+    #[error("JwtError: {0}")]
+    JwtError(#[from] jsonwebtoken::errors::Error),
 }
 
 /*****  CONVERSIONS  *****/
@@ -116,12 +186,21 @@ impl Error {
             InvalidEmailAddressError
             | EmailAlreadyExists
             | UnauthorizedError
-            | UserNotFoundError => format!("{}", self),
+            | UserNotFoundError
+            | InvalidScopeError(_)
+            | CsrfError
+            | UnverifiedEmailError
+            | EmailAlreadyVerified
+            | SecondFactorRequiredError
+            | InvalidTotpCodeError
+            | TotpLockedError
+            | TotpAlreadyEnabledError
+            | TotpNotEnrolledError
+            | InvalidTokenError => format!("{}", self),
             FormValidationErrors(source) => {
                 source
                     .field_errors()
-                    .into_iter()
-                    .map(|(_, error)| error)
+                    .into_values()
                     .map(IntoIterator::into_iter)
                     .map(|errs| {
                         errs //
@@ -131,7 +210,7 @@ impl Error {
                     .fold(String::new(), |a, b| a + &b)
             }
             #[cfg(debug_assertions)]
-            e => return format!("{}", e),
+            e => format!("{}", e),
             #[allow(unreachable_patterns)]
             _ => "undefined".into(),
         }
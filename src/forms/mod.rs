@@ -7,6 +7,9 @@ pub struct Login {
     #[validate(email)]
     pub email: String,
     pub(crate) password: String,
+    /// The [`CsrfToken`](crate::CsrfToken) of the form, required if [`CookieConfig::require_csrf`](crate::CookieConfig::require_csrf) is set.
+    #[serde(default)]
+    pub csrf_token: Option<String>,
 }
 
 /// The `Signup` form is used along with the [`Auth`] guard to create new users.
@@ -16,6 +19,9 @@ pub struct Signup {
     pub email: String,
     #[validate(custom(function = "is_password_secure"))]
     pub(crate) password: String,
+    /// The [`CsrfToken`](crate::CsrfToken) of the form, required if [`CookieConfig::require_csrf`](crate::CookieConfig::require_csrf) is set.
+    #[serde(default)]
+    pub csrf_token: Option<String>,
 }
 impl Debug for Signup {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -41,6 +47,7 @@ impl From<Signup> for Login {
         Login {
             email: form.email,
             password: form.password,
+            csrf_token: form.csrf_token,
         }
     }
 }
@@ -50,6 +57,7 @@ impl From<Login> for Signup {
         Self {
             email: form.email,
             password: form.password,
+            csrf_token: form.csrf_token,
         }
     }
 }
@@ -59,6 +67,7 @@ impl<T: Deref<Target = Signup>> From<T> for Login {
         Login {
             email: form.email.clone(),
             password: form.password.clone(),
+            csrf_token: form.csrf_token.clone(),
         }
     }
 }
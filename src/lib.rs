@@ -7,6 +7,8 @@
 //! * `redis`: for storing sessions on a redis server using `redis`.
 //! * `rusqlite`: for interacting with a SQLite database using `rusqlite`.
 //! * `tokio-postgres`: for interacting with a Postgresql database with `tokio-postgres`.
+//! * `jwt`: for issuing JSON web access tokens with rotating refresh tokens, see [`Auth::issue_jwt`].
+//! * `smtp`: for sending emails through an SMTP server with `lettre`, see [`Users::set_mailer`].
 //!
 //!
 //! `rocket_auth` uses private cookies to store session data.
@@ -61,12 +63,13 @@
 //! }
 //!
 //! #[get("/logout")]
-//! fn logout(auth: Auth<'_>) {
-//!     auth.logout();
+//! async fn logout(auth: Auth<'_>) {
+//!     auth.logout().await;
 //! }
 //! #[tokio::main]
 //! async fn main() -> Result<(), Error>{
-//!     let users = Users::open_sqlite("mydb.db").await?;
+//!     let mut users = Users::open_sqlite("mydb.db").await?;
+//!     users.set_token_key(std::env::var("TOKEN_KEY").unwrap());
 //!
 //!     rocket::build()
 //!         .mount("/", routes![signup, login, logout])
@@ -137,9 +140,11 @@
 extern crate core;
 
 mod cookies;
+mod csrf;
 mod db;
 mod error;
 mod forms;
+mod mail;
 pub mod prelude;
 mod session;
 mod user;
@@ -148,14 +153,26 @@ mod user;
 mod tests;
 
 use std::fmt::Debug;
+use std::sync::Arc;
 
 pub use prelude::*;
 
 // pub use language::Language;
 pub use crate::user::auth::Auth;
-pub use cookies::Session;
+pub use cookies::{CookieConfig, Session};
+pub use csrf::{CsrfProtected, CsrfToken, CSRF_FIELD, CSRF_HEADER};
 pub use error::Error;
+#[cfg(feature = "smtp")]
+pub use mail::SmtpMailer;
+pub use mail::{Email, EmailTemplate, EmailTemplates, FileMailer, Mailer, MemoryMailer};
+pub use session::cleaner::SessionCleaner;
+#[cfg(feature = "jwt")]
+pub use session::jwt::{Claims, JwtConfig, TokenPair};
+pub use session::{ClientBinding, SessionInfo, SessionPolicy};
+pub use user::api_keys::{ApiKey, ApiKeyUser, Scopes};
+pub use user::basic_auth::{basic_auth_challenge, BasicAuthChallenge, BasicAuthUser};
 pub use user::roles::{Role, Roles, ADMIN_ROLE};
+pub use user::totp::TotpEnrollment;
 
 /// The `User` guard can be used to restrict content, so that it can only be viewed by authenticated users.
 /// ```rust
@@ -176,6 +193,8 @@ pub struct User {
     pub roles: Roles,
     #[serde(skip_serializing)]
     password: String,
+    #[serde(default)]
+    verified: bool,
 }
 
 /// The [`AdminUser`] guard can be used analogously to [`User`].
@@ -200,5 +219,14 @@ impl Debug for AdminUser {
 /// The `Users` struct is used to query users from the database, as well as to create, modify and delete them.
 pub struct Users {
     conn: Box<dyn DBConnection>,
-    sess: Box<dyn SessionManager>,
+    sess: Arc<dyn SessionManager>,
+    policy: SessionPolicy,
+    token_key: Vec<u8>,
+    cookie: CookieConfig,
+    stateless: bool,
+    require_verified: bool,
+    mailer: Option<Box<dyn Mailer>>,
+    email_templates: EmailTemplates,
+    #[cfg(feature = "jwt")]
+    jwt: Option<JwtConfig>,
 }
@@ -0,0 +1,124 @@
+use super::{Email, Mailer};
+use crate::prelude::*;
+use crate::user::generate_token;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::path::PathBuf;
+
+/// A mailer that writes every email to a `.eml` file in a directory instead of delivering it,
+/// which is meant for development. The files can be opened with most mail clients.
+/// ```rust
+/// # use rocket_auth2::{Error, FileMailer, Users};
+/// # async fn func(mut users: Users) -> Result<(), Error> {
+/// users.set_mailer(FileMailer::new("target/emails"));
+/// # Ok(()) }
+/// ```
+#[derive(Debug, Clone)]
+pub struct FileMailer {
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    /// The directory is created when the first email is written, if it doesn't exist.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FileMailer { dir: dir.into() }
+    }
+}
+
+#[rocket::async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: &Email) -> Result<()> {
+        let contents = format_eml(email)?;
+        let path = self.dir.join(format!("{}-{}.eml", now(), generate_token()));
+        let dir = self.dir.clone();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            std::fs::create_dir_all(dir)?;
+            std::fs::write(path, contents)?;
+            Ok(())
+        })
+        .await
+        .map_err(|e| Error::MailError(e.to_string()))?
+    }
+}
+
+/// Formats an email as an RFC 5322 message, with a `multipart/alternative` body if it has an HTML part.
+fn format_eml(email: &Email) -> Result<String> {
+    email.check_headers()?;
+    let mut eml = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\n",
+        email.from,
+        email.to,
+        encode_header(&email.subject),
+        chrono::Utc::now().to_rfc2822(),
+    );
+    match &email.html {
+        None => eml.push_str(&format_part("text/plain", &email.text)),
+        Some(html) => {
+            let boundary = format!("=_{}", generate_token());
+            eml.push_str(&format!(
+                "Content-Type: multipart/alternative; boundary=\"{}\"\r\n\r\n",
+                boundary
+            ));
+            for (content_type, body) in [("text/plain", &email.text), ("text/html", html)] {
+                eml.push_str(&format!("--{}\r\n", boundary));
+                eml.push_str(&format_part(content_type, body));
+                eml.push_str("\r\n");
+            }
+            eml.push_str(&format!("--{}--\r\n", boundary));
+        }
+    }
+    Ok(eml)
+}
+
+fn format_part(content_type: &str, body: &str) -> String {
+    let body = body.replace("\r\n", "\n").replace('\n', "\r\n");
+    format!(
+        "Content-Type: {}; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n{}",
+        content_type, body
+    )
+}
+
+/// Encodes a header with non-ASCII characters as an RFC 2047 encoded word.
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        value.into()
+    } else {
+        format!("=?UTF-8?B?{}?=", STANDARD.encode(value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_emails_are_written_to_eml_files() {
+        let dir = std::env::temp_dir().join(format!("rocket_auth_mail_{}", generate_token()));
+        let mailer = FileMailer::new(&dir);
+        let email = Email {
+            from: "no-reply@example.com".into(),
+            to: "user@example.com".into(),
+            subject: "Vérification".into(),
+            text: "line one\nline two".into(),
+            html: Some("<p>line one</p>".into()),
+        };
+        mailer.send(&email).await.unwrap();
+
+        let files: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        let path = files[0].as_ref().unwrap().path();
+        assert_eq!(path.extension().unwrap(), "eml");
+        let eml = std::fs::read_to_string(path).unwrap();
+        assert!(eml.starts_with("From: no-reply@example.com\r\nTo: user@example.com\r\n"));
+        assert!(eml.contains("Subject: =?UTF-8?B?"));
+        assert!(eml.contains("line one\r\nline two"));
+        assert!(eml.contains("Content-Type: text/html; charset=utf-8"));
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let email = Email {
+            subject: "Hi\nBcc: other@example.com".into(),
+            ..email
+        };
+        assert!(format_eml(&email).is_err());
+    }
+}
@@ -0,0 +1,56 @@
+use super::{Email, Mailer};
+use crate::prelude::*;
+use std::sync::{Arc, Mutex};
+
+/// A mailer that keeps the emails it is given in memory, so that tests can inspect them.
+/// Its clones share the same emails, so a clone can be kept after the mailer is passed to [`Users::set_mailer`].
+/// ```rust
+/// # use rocket_auth2::{Error, MemoryMailer, Users};
+/// # async fn func(mut users: Users) -> Result<(), Error> {
+/// let mailer = MemoryMailer::new();
+/// users.set_mailer(mailer.clone());
+/// let token = users.request_password_reset("user@example.com").await?;
+/// assert!(mailer.last().unwrap().text.contains(&token));
+/// # Ok(()) }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MemoryMailer {
+    emails: Arc<Mutex<Vec<Email>>>,
+}
+
+impl MemoryMailer {
+    /// A mailer that holds no email yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The emails sent so far, oldest first.
+    pub fn emails(&self) -> Vec<Email> {
+        self.lock().clone()
+    }
+
+    /// The email sent last.
+    pub fn last(&self) -> Option<Email> {
+        self.lock().last().cloned()
+    }
+
+    /// Forgets the emails sent so far.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Email>> {
+        self.emails
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[rocket::async_trait]
+impl Mailer for MemoryMailer {
+    async fn send(&self, email: &Email) -> Result<()> {
+        email.check_headers()?;
+        self.lock().push(email.clone());
+        Ok(())
+    }
+}
@@ -0,0 +1,316 @@
+mod file;
+mod memory;
+#[cfg(feature = "smtp")]
+mod smtp;
+
+pub use file::FileMailer;
+pub use memory::MemoryMailer;
+#[cfg(feature = "smtp")]
+pub use smtp::SmtpMailer;
+
+use crate::prelude::*;
+use std::sync::Arc;
+
+/// An email, ready to be delivered by a [`Mailer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Email {
+    /// The address the email is sent from.
+    pub from: String,
+    /// The address the email is sent to.
+    pub to: String,
+    /// The subject line.
+    pub subject: String,
+    /// The plain text body.
+    pub text: String,
+    /// An HTML alternative to the plain text body.
+    pub html: Option<String>,
+}
+
+impl Email {
+    /// Fails with [`Error::MailError`] if a header contains a line break,
+    /// which would let it inject other headers.
+    pub(crate) fn check_headers(&self) -> Result<()> {
+        for header in [&self.from, &self.to, &self.subject] {
+            if header.contains(['\r', '\n']) {
+                return Err(Error::MailError(format!(
+                    "the header {:?} contains a line break",
+                    header
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Delivers the emails sent by [`Users`], such as verification and password reset emails.
+/// It is set with [`Users::set_mailer`]. The crate ships a [`FileMailer`] and a [`MemoryMailer`] for development and tests,
+/// and an `SmtpMailer` with the `smtp` feature.
+/// ```rust
+/// # use rocket_auth2::{Email, Error, Mailer};
+/// struct LogMailer;
+///
+/// #[rocket::async_trait]
+/// impl Mailer for LogMailer {
+///     async fn send(&self, email: &Email) -> Result<(), Error> {
+///         println!("to {}: {}\n{}", email.to, email.subject, email.text);
+///         Ok(())
+///     }
+/// }
+/// ```
+#[rocket::async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<()>;
+}
+
+#[rocket::async_trait]
+impl<T: Mailer> Mailer for Arc<T> {
+    async fn send(&self, email: &Email) -> Result<()> {
+        T::send(self, email).await
+    }
+}
+
+/// The subject and bodies of an email, in which `{{ name }}` placeholders are replaced when it is rendered.
+/// Emails sent by the crate fill in `{{ email }}`, the address of the user, and `{{ token }}`, if the email carries one.
+/// The notice of an email change also fills in `{{ new_email }}`.
+/// ```rust
+/// # use rocket_auth2::EmailTemplate;
+/// let template = EmailTemplate::new(
+///     "Verify your email",
+///     "Visit https://example.com/verify/{{ token }} to verify {{ email }}.",
+/// )
+/// .with_html(r#"<a href="https://example.com/verify/{{ token }}">Verify {{ email }}</a>"#);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmailTemplate {
+    /// The subject line, which may hold placeholders too.
+    pub subject: String,
+    /// The plain text body.
+    pub text: String,
+    /// An HTML alternative to the plain text body, if any.
+    pub html: Option<String>,
+}
+
+impl EmailTemplate {
+    /// A template with a subject and a plain text body, without an HTML body.
+    pub fn new(subject: impl Into<String>, text: impl Into<String>) -> Self {
+        EmailTemplate {
+            subject: subject.into(),
+            text: text.into(),
+            html: None,
+        }
+    }
+
+    /// Adds an HTML alternative to the plain text body.
+    pub fn with_html(mut self, html: impl Into<String>) -> Self {
+        self.html = Some(html.into());
+        self
+    }
+
+    /// Renders an email to `to`, with the values of `vars` in place of the placeholders.
+    /// The values are escaped in the HTML body, and placeholders without a value are left as they are.
+    pub fn render(&self, from: &str, to: &str, vars: &[(&str, &str)]) -> Email {
+        Email {
+            from: from.into(),
+            to: to.into(),
+            subject: render(&self.subject, vars, |value| value.into()),
+            text: render(&self.text, vars, |value| value.into()),
+            html: self
+                .html
+                .as_ref()
+                .map(|html| render(html, vars, escape_html)),
+        }
+    }
+}
+
+fn render(template: &str, vars: &[(&str, &str)], escape: fn(&str) -> String) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        let placeholder = &rest[start..start + end + 2];
+        let name = placeholder[2..placeholder.len() - 2].trim();
+        rendered.push_str(&rest[..start]);
+        match vars.iter().find(|(var, _)| *var == name) {
+            Some((_, value)) => rendered.push_str(&escape(value)),
+            None => rendered.push_str(placeholder),
+        }
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The sender and the templates of the emails sent by [`Users`], set with [`Users::set_email_templates`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmailTemplates {
+    /// The `From` address of every email.
+    pub from: String,
+    /// Sent by [`Users::send_verification`].
+    pub verification: EmailTemplate,
+    /// Sent by [`Users::request_password_reset`].
+    pub password_reset: EmailTemplate,
+    /// Sent by [`Users::reset_password`] once the password was changed, without a token.
+    pub password_changed: EmailTemplate,
+    /// Sent to the new address by [`Users::request_email_change`].
+    pub email_change: EmailTemplate,
+    /// Sent to the current address by [`Users::request_email_change`], without a token.
+    pub email_change_notice: EmailTemplate,
+}
+
+impl Default for EmailTemplates {
+    fn default() -> Self {
+        EmailTemplates {
+            from: "no-reply@localhost".into(),
+            verification: EmailTemplate::new(
+                "Verify your email address",
+                "Use this token to verify {{ email }}:\n\n{{ token }}\n\nIt is valid for one day.\n",
+            ),
+            password_reset: EmailTemplate::new(
+                "Reset your password",
+                "Use this token to choose a new password:\n\n{{ token }}\n\nIt is valid for one hour. \
+                If you did not ask to reset your password, you can ignore this email.\n",
+            ),
+            password_changed: EmailTemplate::new(
+                "Your password was changed",
+                "The password of {{ email }} was changed, and every session was logged out.\n\n\
+                If you did not change it, reset your password right away.\n",
+            ),
+            email_change: EmailTemplate::new(
+                "Confirm your new email address",
+                "Use this token to confirm {{ email }} as your new email address:\n\n{{ token }}\n\n\
+                It is valid for one day.\n",
+            ),
+            email_change_notice: EmailTemplate::new(
+                "Your email address is being changed",
+                "A change of your email address from {{ email }} to {{ new_email }} was requested. \
+                It will be applied once the new address is confirmed.\n\n\
+                If you did not request it, reset your password right away.\n",
+            ),
+        }
+    }
+}
+
+impl Users {
+    /// Sets the mailer through which verification, password reset, email change and security notification emails are sent.
+    /// Without one, no email is sent, and the tokens returned by [`Users::send_verification`],
+    /// [`Users::request_password_reset`] and [`Users::request_email_change`] have to be delivered by the application.
+    /// ```rust
+    /// # use rocket_auth2::{Error, FileMailer, Users};
+    /// # async fn func(mut users: Users) -> Result<(), Error> {
+    /// users.set_mailer(FileMailer::new("target/emails"));
+    /// # Ok(()) }
+    /// ```
+    pub fn set_mailer(&mut self, mailer: impl Mailer + 'static) {
+        self.mailer = Some(Box::new(mailer));
+    }
+
+    /// The mailer set with [`Users::set_mailer`], to send other emails through it.
+    pub fn mailer(&self) -> Option<&dyn Mailer> {
+        self.mailer.as_deref()
+    }
+
+    /// Sets the sender and the templates of the emails sent by the crate.
+    /// ```rust
+    /// # use rocket_auth2::{EmailTemplate, EmailTemplates, Users};
+    /// # fn func(mut users: Users) {
+    /// users.set_email_templates(EmailTemplates {
+    ///     from: "Example <no-reply@example.com>".into(),
+    ///     verification: EmailTemplate::new(
+    ///         "Welcome to Example",
+    ///         "Visit https://example.com/verify/{{ token }} to verify your email.",
+    ///     ),
+    ///     ..Default::default()
+    /// });
+    /// # }
+    /// ```
+    pub fn set_email_templates(&mut self, templates: EmailTemplates) {
+        self.email_templates = templates;
+    }
+
+    /// Renders the template chosen by `template` and sends it to `to`, if a mailer is set.
+    pub(crate) async fn send_email(
+        &self,
+        template: fn(&EmailTemplates) -> &EmailTemplate,
+        to: &str,
+        vars: &[(&str, &str)],
+    ) -> Result<()> {
+        let Some(mailer) = &self.mailer else {
+            return Ok(());
+        };
+        let templates = &self.email_templates;
+        let email = template(templates).render(&templates.from, to, vars);
+        mailer.send(&email).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_templates_fill_in_placeholders() {
+        let template =
+            EmailTemplate::new("Hi {{email}}", "{{ token }} for {{ email }}, {{ other }}")
+                .with_html("<b>{{ email }}</b>{{ unclosed");
+        let vars = [("email", "<a@example.com>"), ("token", "t.1")];
+        let email = template.render("from@example.com", "a@example.com", &vars);
+        assert_eq!(email.subject, "Hi <a@example.com>");
+        assert_eq!(email.text, "t.1 for <a@example.com>, {{ other }}");
+        assert_eq!(
+            email.html.as_deref(),
+            Some("<b>&lt;a@example.com&gt;</b>{{ unclosed")
+        );
+        assert!(email.check_headers().is_ok());
+        let email = template.render(
+            "from@example.com",
+            "a@example.com\r\nBcc: b@example.com",
+            &vars,
+        );
+        assert!(email.check_headers().is_err());
+    }
+
+    #[cfg(feature = "sqlx-sqlite")]
+    #[tokio::test]
+    async fn test_users_send_tokens_through_the_mailer() {
+        let mut users = crate::user::test_users("mail@example.com").await;
+        let mailer = MemoryMailer::new();
+        users.set_mailer(mailer.clone());
+        let user = users.get_by_email("mail@example.com").await.unwrap();
+
+        let token = users.send_verification(user.id).await.unwrap();
+        let email = mailer.last().unwrap();
+        assert_eq!(email.to, "mail@example.com");
+        assert_eq!(email.from, "no-reply@localhost");
+        assert!(email.text.contains(&token));
+
+        let token = users
+            .request_password_reset("mail@example.com")
+            .await
+            .unwrap();
+        assert!(mailer.last().unwrap().text.contains(&token));
+        users
+            .reset_password(&token, "NewPassword123")
+            .await
+            .unwrap();
+        let emails = mailer.emails();
+        assert_eq!(emails.len(), 3);
+        assert_eq!(emails[2].subject, "Your password was changed");
+    }
+}
@@ -1,7 +1,8 @@
 // pub use crate::language::Language;
-pub use crate::cookies::Session;
+pub use crate::cookies::{CookieConfig, Session};
 pub use crate::error::Error;
 pub use crate::forms::{is_password_secure, Login, Signup};
+pub use crate::session::{ClientBinding, SessionInfo, SessionPolicy};
 pub use crate::{AdminUser, Auth, User, Users};
 /// A type alias of result to omit the error type.
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -0,0 +1,98 @@
+use crate::prelude::*;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Orbit, Rocket};
+
+/// The default period of time between two sweeps of the [`SessionCleaner`].
+const SWEEP_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// The shortest period of time between two sweeps of the [`SessionCleaner`].
+const MIN_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The `SessionCleaner` fairing periodically removes expired sessions from the session store,
+/// so that it does not grow forever on long-running servers.
+/// It starts a background task on liftoff, which stops once Rocket shuts down.
+/// The number of sessions removed by each sweep is logged.
+/// ```rust,no_run
+/// # use rocket_auth2::{Users, Error, SessionCleaner};
+/// # use std::time::Duration;
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Error> {
+/// let users = Users::open_sqlite("database.db").await?;
+///
+/// rocket::build()
+///     .manage(users)
+///     .attach(SessionCleaner::new(Duration::from_secs(60 * 60)))
+///     .launch()
+///     .await;
+/// # Ok(()) }
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct SessionCleaner {
+    interval: Duration,
+}
+
+impl SessionCleaner {
+    /// Creates a fairing that sweeps expired sessions once every `interval`.
+    /// Intervals shorter than one second, including zero, are raised to one second.
+    pub fn new(interval: Duration) -> Self {
+        SessionCleaner {
+            interval: interval.max(MIN_SWEEP_INTERVAL),
+        }
+    }
+}
+
+impl Default for SessionCleaner {
+    fn default() -> Self {
+        SessionCleaner::new(SWEEP_INTERVAL)
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for SessionCleaner {
+    fn info(&self) -> Info {
+        Info {
+            name: "Expired session cleaner",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let Some(users) = rocket.state::<Users>() else {
+            log::error!("{}", Error::UnmanagedStateError);
+            return;
+        };
+        let sessions = users.sess.clone();
+        let mut shutdown = rocket.shutdown();
+        let mut interval = tokio::time::interval(self.interval);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = &mut shutdown => break,
+                    _ = interval.tick() => match sessions.clear_expired().await {
+                        Ok(removed) => log::info!("removed {} expired sessions", removed),
+                        Err(error) => log::error!("failed to remove expired sessions: {}", error),
+                    },
+                }
+            }
+        });
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-sqlite"))]
+mod test {
+    use super::*;
+    use rocket::local::asynchronous::Client;
+
+    #[tokio::test]
+    async fn test_zero_interval_is_raised() {
+        let cleaner = SessionCleaner::new(Duration::ZERO);
+        assert_eq!(cleaner.interval, MIN_SWEEP_INTERVAL);
+        assert_eq!(SessionCleaner::default().interval, SWEEP_INTERVAL);
+
+        // `tokio::time::interval` panics on a zero period, which would happen on liftoff.
+        let users = crate::user::test_users("cleaner@example.com").await;
+        let rocket = rocket::build().manage(users).attach(cleaner);
+        let client = Client::tracked(rocket).await.unwrap();
+        client.rocket().shutdown().notify();
+    }
+}
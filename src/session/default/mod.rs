@@ -1,41 +1,190 @@
-use super::AuthKey;
 use super::SessionManager;
+use super::{AuthKey, YEAR_IN_SECS};
 use crate::prelude::*;
 use chashmap::CHashMap;
+use std::cell::Cell;
 
-impl SessionManager for CHashMap<i32, AuthKey> {
-    fn insert(&self, id: i32, key: String) -> Result<()> {
-        self.insert(id, key.into());
+#[rocket::async_trait]
+impl SessionManager for CHashMap<String, AuthKey> {
+    async fn insert(&self, session_id: &str, key: AuthKey) -> Result<()> {
+        self.insert_for(session_id, key, Duration::from_secs(YEAR_IN_SECS))
+            .await
+    }
+
+    async fn insert_for(&self, session_id: &str, mut key: AuthKey, time: Duration) -> Result<()> {
+        key.expire_in(time);
+        self.insert(session_id.into(), key);
         Ok(())
     }
 
-    fn insert_for(&self, id: i32, key: String, time: Duration) -> Result<()> {
-        let key = AuthKey {
-            expires: time.as_secs() as i64,
-            secret: key,
-        };
-        self.insert(id, key);
+    async fn remove(&self, session_id: &str) -> Result<()> {
+        self.remove(session_id);
         Ok(())
     }
 
-    fn remove(&self, id: i32) -> Result<()> {
-        self.remove(&id);
+    async fn take(&self, session_id: &str) -> Result<Option<AuthKey>> {
+        let key = self.remove(session_id);
+        Ok(key.filter(|key| !key.is_expired()))
+    }
+
+    async fn remove_user(&self, user_id: i32) -> Result<()> {
+        self.retain(|_, auth_key| auth_key.user_id != user_id);
         Ok(())
     }
 
-    fn get(&self, id: i32) -> Option<String> {
-        let key = self.get(&id)?;
-        Some(key.secret.clone())
+    async fn get(&self, session_id: &str) -> Option<AuthKey> {
+        let key = self.get(session_id)?.clone();
+        if key.is_expired() {
+            self.remove(session_id);
+            return None;
+        }
+        Some(key)
     }
 
-    fn clear_all(&self) -> Result<()> {
-        self.clear();
+    async fn list(&self, user_id: i32) -> Result<Vec<(String, AuthKey)>> {
+        let sessions = self
+            .clone()
+            .into_iter()
+            .filter(|(_, auth_key)| auth_key.user_id == user_id && !auth_key.is_expired())
+            .collect();
+        Ok(sessions)
+    }
+
+    async fn touch(&self, session_id: &str, idle_timeout: Option<Duration>) -> Result<()> {
+        if let Some(mut key) = self.get_mut(session_id) {
+            key.refresh(idle_timeout);
+        }
         Ok(())
     }
 
-    fn clear_expired(&self) -> Result<()> {
-        let time = now();
-        self.retain(|_, auth_key| auth_key.expires > time);
+    async fn clear_all(&self) -> Result<()> {
+        self.clear();
         Ok(())
     }
+
+    async fn clear_expired(&self) -> Result<usize> {
+        let removed = Cell::new(0);
+        self.retain(|_, auth_key| {
+            let expired = auth_key.is_expired();
+            if expired {
+                removed.set(removed.get() + 1);
+            }
+            !expired
+        });
+        Ok(removed.get())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::session::ClientInfo;
+
+    fn key(user_id: i32, secret: &str) -> AuthKey {
+        AuthKey::new(user_id, secret.into(), &ClientInfo::default())
+    }
+
+    #[tokio::test]
+    async fn test_sessions_of_one_user_coexist() {
+        let sessions: CHashMap<String, AuthKey> = CHashMap::new();
+        SessionManager::insert(&sessions, "laptop", key(1, "first"))
+            .await
+            .unwrap();
+        SessionManager::insert(&sessions, "phone", key(1, "second"))
+            .await
+            .unwrap();
+        SessionManager::insert(&sessions, "other", key(2, "third"))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            SessionManager::get(&sessions, "laptop")
+                .await
+                .unwrap()
+                .secret,
+            "first"
+        );
+        assert_eq!(
+            SessionManager::get(&sessions, "phone")
+                .await
+                .unwrap()
+                .secret,
+            "second"
+        );
+
+        SessionManager::remove(&sessions, "phone").await.unwrap();
+        assert!(SessionManager::get(&sessions, "phone").await.is_none());
+        assert!(SessionManager::get(&sessions, "laptop").await.is_some());
+
+        SessionManager::remove_user(&sessions, 1).await.unwrap();
+        assert!(SessionManager::get(&sessions, "laptop").await.is_none());
+        assert!(SessionManager::get(&sessions, "other").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_list_sessions_of_user() {
+        let sessions: CHashMap<String, AuthKey> = CHashMap::new();
+        SessionManager::insert(&sessions, "laptop", key(1, "first"))
+            .await
+            .unwrap();
+        SessionManager::insert(&sessions, "phone", key(1, "second"))
+            .await
+            .unwrap();
+        SessionManager::insert(&sessions, "other", key(2, "third"))
+            .await
+            .unwrap();
+
+        let mut ids: Vec<String> = SessionManager::list(&sessions, 1)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        ids.sort();
+        assert_eq!(ids, ["laptop", "phone"]);
+    }
+
+    #[tokio::test]
+    async fn test_expired_sessions_are_rejected() {
+        let sessions: CHashMap<String, AuthKey> = CHashMap::new();
+        SessionManager::insert_for(&sessions, "expired", key(1, "first"), Duration::ZERO)
+            .await
+            .unwrap();
+        SessionManager::insert_for(
+            &sessions,
+            "hour",
+            key(1, "second"),
+            Duration::from_secs(3600),
+        )
+        .await
+        .unwrap();
+
+        assert!(SessionManager::get(&sessions, "expired").await.is_none());
+        assert!(SessionManager::get(&sessions, "hour").await.is_some());
+        assert_eq!(SessionManager::list(&sessions, 1).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_clear_expired_keeps_live_sessions() {
+        let sessions: CHashMap<String, AuthKey> = CHashMap::new();
+        SessionManager::insert(&sessions, "default", key(1, "first"))
+            .await
+            .unwrap();
+        SessionManager::insert_for(
+            &sessions,
+            "hour",
+            key(1, "second"),
+            Duration::from_secs(3600),
+        )
+        .await
+        .unwrap();
+        SessionManager::insert_for(&sessions, "expired", key(2, "third"), Duration::ZERO)
+            .await
+            .unwrap();
+
+        assert_eq!(SessionManager::clear_expired(&sessions).await.unwrap(), 1);
+        assert_eq!(sessions.len(), 2);
+        assert!(sessions.contains_key("default"));
+        assert!(sessions.contains_key("hour"));
+    }
 }
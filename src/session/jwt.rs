@@ -0,0 +1,372 @@
+use super::{hash_token, AuthKey, ClientInfo, REFRESH_PREFIX};
+use crate::cookies::bearer_token;
+use crate::prelude::*;
+use crate::user::generate_token;
+use crate::user::roles::Roles;
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rocket::Request;
+use subtle::ConstantTimeEq;
+
+/// The default lifetime of an access token.
+const ACCESS_LIFETIME: Duration = Duration::from_secs(15 * 60);
+/// The default lifetime of a family of refresh tokens.
+const REFRESH_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// How many of the refresh tokens a family exchanged are remembered, to tell a reused token from a forged one.
+const USED_SECRETS_KEPT: usize = 32;
+
+/// The keys and lifetimes of JSON web tokens. It can be set with [`Users::set_jwt_config`].
+/// ```rust
+/// # use rocket_auth2::{Users, JwtConfig};
+/// # use std::time::Duration;
+/// # fn func(mut users: Users) {
+/// let mut config = JwtConfig::hs256(std::env::var("JWT_SECRET").unwrap());
+/// config.access_lifetime = Duration::from_secs(5 * 60);
+/// config.trust_claims = true;
+/// users.set_jwt_config(config);
+/// # }
+/// ```
+pub struct JwtConfig {
+    algorithm: Algorithm,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    /// How long an access token is valid, 15 minutes by default.
+    pub access_lifetime: Duration,
+    /// How long a refresh token may be exchanged for new tokens after logging in, 30 days by default.
+    /// It is capped by [`SessionPolicy::absolute_timeout`], and [`SessionPolicy::idle_timeout`] ends unused refresh tokens.
+    pub refresh_lifetime: Duration,
+    /// Whether the `User` and `AdminUser` guards trust the claims of a valid access token, without reading the database.
+    /// Revoked refresh tokens and changed roles then only take effect once the access token expires,
+    /// and the user returned by the guards has no password hash and is not marked as verified.
+    pub trust_claims: bool,
+}
+
+impl JwtConfig {
+    /// Signs tokens with HMAC-SHA256 under a shared secret.
+    pub fn hs256(secret: impl AsRef<[u8]>) -> Self {
+        let secret = secret.as_ref();
+        JwtConfig::new(
+            Algorithm::HS256,
+            EncodingKey::from_secret(secret),
+            DecodingKey::from_secret(secret),
+        )
+    }
+
+    /// Signs tokens with Ed25519, so that other services can verify them with the public key alone.
+    /// Both keys are PEM encoded.
+    pub fn eddsa(private_key: &[u8], public_key: &[u8]) -> Result<Self> {
+        Ok(JwtConfig::new(
+            Algorithm::EdDSA,
+            EncodingKey::from_ed_pem(private_key)?,
+            DecodingKey::from_ed_pem(public_key)?,
+        ))
+    }
+
+    fn new(algorithm: Algorithm, encoding_key: EncodingKey, decoding_key: DecodingKey) -> Self {
+        JwtConfig {
+            algorithm,
+            encoding_key,
+            decoding_key,
+            access_lifetime: ACCESS_LIFETIME,
+            refresh_lifetime: REFRESH_LIFETIME,
+            trust_claims: false,
+        }
+    }
+
+    fn encode(&self, claims: &Claims) -> Result<String> {
+        Ok(encode(
+            &Header::new(self.algorithm),
+            claims,
+            &self.encoding_key,
+        )?)
+    }
+
+    fn decode(&self, token: &str) -> Result<Claims> {
+        let validation = Validation::new(self.algorithm);
+        Ok(decode(token, &self.decoding_key, &validation)?.claims)
+    }
+}
+
+/// The claims of an access token.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Claims {
+    /// The id of the user.
+    pub sub: String,
+    /// The email of the user when the token was issued.
+    pub email: String,
+    /// The roles of the user when the token was issued.
+    pub roles: Roles,
+    /// The family of refresh tokens the access token was issued from.
+    pub sid: String,
+    /// The Unix time at which the token was issued.
+    pub iat: i64,
+    /// The Unix time at which the token expires.
+    pub exp: i64,
+}
+
+/// A short-lived access token, together with the refresh token that replaces it.
+/// It serializes to the shape of an OAuth 2.0 token response.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TokenPair {
+    /// A signed JSON web token, to be sent in an `Authorization: Bearer <token>` header.
+    pub access_token: String,
+    /// Always `Bearer`.
+    pub token_type: String,
+    /// The number of seconds the access token is valid for.
+    pub expires_in: u64,
+    /// An opaque token that can be exchanged once with [`Users::refresh_jwt`].
+    pub refresh_token: String,
+}
+
+impl Users {
+    /// Sets the keys and the lifetimes of JSON web tokens, which are issued by [`Auth::issue_jwt`](crate::Auth::issue_jwt).
+    /// ```rust, no_run
+    /// # use rocket_auth2::{Users, Error, JwtConfig};
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), Error> {
+    /// let mut users = Users::open_sqlite("database.db").await?;
+    /// users.set_jwt_config(JwtConfig::hs256(std::env::var("JWT_SECRET").unwrap()));
+    /// # Ok(()) }
+    /// ```
+    pub fn set_jwt_config(&mut self, config: JwtConfig) {
+        self.jwt = Some(config);
+    }
+
+    fn jwt_config(&self) -> Result<&JwtConfig> {
+        self.jwt.as_ref().ok_or(Error::JwtNotConfiguredError)
+    }
+
+    /// Starts a new family of refresh tokens for `user`.
+    pub(crate) async fn issue_jwt(&self, user: &User, client: &ClientInfo) -> Result<TokenPair> {
+        let config = self.jwt_config()?;
+        let family = generate_token();
+        let secret = generate_token();
+        let mut key = AuthKey::new(user.id, hash_token(self.token_key()?, &secret), client);
+        key.refresh(self.policy.idle_timeout);
+        self.sess
+            .insert_for(
+                &refresh_id(&family),
+                key,
+                self.policy.lifetime(config.refresh_lifetime),
+            )
+            .await?;
+        token_pair(config, user, &family, &secret)
+    }
+
+    /// Exchanges a refresh token for a new access token and a new refresh token.
+    /// Every refresh token can only be used once, even by concurrent requests. If a used refresh token is presented
+    /// again, it is assumed to be stolen, and the whole family of tokens descending from the same login is revoked.
+    /// A token that never belonged to the family is rejected, and leaves the family in place.
+    /// ```rust
+    /// # use rocket::{post, State, serde::json::Json};
+    /// # use rocket_auth2::{Users, Error, TokenPair};
+    /// #[post("/api/refresh", data="<refresh_token>")]
+    /// async fn refresh(refresh_token: String, users: &State<Users>) -> Result<Json<TokenPair>, Error> {
+    ///     Ok(Json(users.refresh_jwt(&refresh_token).await?))
+    /// }
+    /// ```
+    pub async fn refresh_jwt(&self, refresh_token: &str) -> Result<TokenPair> {
+        let config = self.jwt_config()?;
+        let (family, secret) = refresh_token
+            .split_once('.')
+            .ok_or(Error::UnauthorizedError)?;
+        let id = refresh_id(family);
+        let token_key = self.token_key()?;
+        let key = self.sess.get(&id).await.ok_or(Error::UnauthorizedError)?;
+        if !key.verify(token_key, secret) {
+            if key.was_used(token_key, secret) {
+                log::warn!("a refresh token was used twice, so its family was revoked");
+                self.sess.remove(&id).await?;
+            }
+            return Err(Error::UnauthorizedError);
+        }
+        // the family is only taken out of the store if it did not change since it was read, so that
+        // a token presented by two requests at once is only exchanged by one of them.
+        let mut key = self.sess.take(&id).await?.ok_or(Error::UnauthorizedError)?;
+        if !key.verify(token_key, secret) {
+            log::warn!("a refresh token was used twice, so its family was revoked");
+            return Err(Error::UnauthorizedError);
+        }
+        let user = self.conn.get_user_by_id(key.user_id).await?;
+        let secret = generate_token();
+        key.replace_secret(hash_token(self.token_key()?, &secret));
+        key.refresh(self.policy.idle_timeout);
+        self.sess
+            .insert_for(&id, key.clone(), key.lifetime())
+            .await?;
+        token_pair(config, &user, family, &secret)
+    }
+
+    /// Revokes the family of a refresh token, so that neither it nor its successors can be used anymore.
+    /// Access tokens issued from the family stay valid until they expire if [`JwtConfig::trust_claims`] is set.
+    /// ```rust
+    /// # use rocket_auth2::{Users, Error};
+    /// # async fn func(users: Users, refresh_token: &str) -> Result<(), Error> {
+    /// users.revoke_refresh_token(refresh_token).await?;
+    /// # Ok(()) }
+    /// ```
+    pub async fn revoke_refresh_token(&self, refresh_token: &str) -> Result<()> {
+        let Some((family, secret)) = refresh_token.split_once('.') else {
+            return Ok(());
+        };
+        let id = refresh_id(family);
+        match self.sess.get(&id).await {
+            Some(key) if key.verify(self.token_key()?, secret) => self.sess.remove(&id).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// The user of a valid access token. Unless the claims are trusted,
+    /// the family of the token must not have been revoked, and the user is read from the database.
+    pub(crate) async fn jwt_user(&self, token: &str) -> Option<User> {
+        let config = self.jwt.as_ref()?;
+        let claims = config.decode(token).ok()?;
+        let id = claims.sub.parse().ok()?;
+        if config.trust_claims {
+            return Some(User {
+                id,
+                email: claims.email,
+                roles: claims.roles,
+                password: String::new(),
+                verified: false,
+            });
+        }
+        let key = self.sess.get(&refresh_id(&claims.sid)).await?;
+        if key.user_id != id {
+            return None;
+        }
+        self.conn.get_user_by_id(id).await.ok()
+    }
+}
+
+/// The user of the access token in the `Authorization: Bearer <token>` header of a request.
+pub(crate) async fn request_jwt_user(request: &Request<'_>) -> Option<User> {
+    let users = request.rocket().state::<Users>()?;
+    users.jwt_user(bearer_token(request)?).await
+}
+
+impl AuthKey {
+    /// Whether `token` is one of the refresh tokens this family already exchanged.
+    fn was_used(&self, hash_key: &[u8], token: &str) -> bool {
+        let hash = hash_token(hash_key, token);
+        self.used_secrets
+            .split(' ')
+            .any(|used| bool::from(used.as_bytes().ct_eq(hash.as_bytes())))
+    }
+
+    /// Replaces the secret of a family of refresh tokens, and remembers the hash of the replaced one.
+    fn replace_secret(&mut self, secret: String) {
+        let replaced = std::mem::replace(&mut self.secret, secret);
+        let mut used: Vec<&str> = self.used_secrets.split_whitespace().collect();
+        used.push(&replaced);
+        let skipped = used.len().saturating_sub(USED_SECRETS_KEPT);
+        self.used_secrets = used[skipped..].join(" ");
+    }
+}
+
+/// The id under which a family of refresh tokens is kept in the session store.
+fn refresh_id(family: &str) -> String {
+    format!("{}{}", REFRESH_PREFIX, family)
+}
+
+fn token_pair(config: &JwtConfig, user: &User, family: &str, secret: &str) -> Result<TokenPair> {
+    let iat = now();
+    let claims = Claims {
+        sub: user.id.to_string(),
+        email: user.email.clone(),
+        roles: user.roles.clone(),
+        sid: family.into(),
+        iat,
+        exp: iat + config.access_lifetime.as_secs() as i64,
+    };
+    Ok(TokenPair {
+        access_token: config.encode(&claims)?,
+        token_type: "Bearer".into(),
+        expires_in: config.access_lifetime.as_secs(),
+        refresh_token: format!("{}.{}", family, secret),
+    })
+}
+
+#[cfg(all(test, feature = "sqlx-sqlite"))]
+mod test {
+    use super::*;
+
+    async fn users(trust_claims: bool) -> Users {
+        let mut users = crate::user::test_users("jwt@example.com").await;
+        let mut config = JwtConfig::hs256("secret");
+        config.trust_claims = trust_claims;
+        users.set_jwt_config(config);
+        users
+    }
+
+    #[tokio::test]
+    async fn test_refresh_tokens_rotate_and_detect_reuse() {
+        let users = users(false).await;
+        let user = users.get_by_email("jwt@example.com").await.unwrap();
+        let first = users
+            .issue_jwt(&user, &ClientInfo::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            users.jwt_user(&first.access_token).await.unwrap().id,
+            user.id
+        );
+
+        let second = users.refresh_jwt(&first.refresh_token).await.unwrap();
+        assert_ne!(second.refresh_token, first.refresh_token);
+        assert!(users.jwt_user(&second.access_token).await.is_some());
+
+        // the family is public, as the `sid` of every access token, but a forged secret does not revoke it
+        let (family, _) = second.refresh_token.split_once('.').unwrap();
+        let forged = format!("{}.{}", family, generate_token());
+        assert!(users.refresh_jwt(&forged).await.is_err());
+        assert!(users.jwt_user(&second.access_token).await.is_some());
+        let second = users.refresh_jwt(&second.refresh_token).await.unwrap();
+
+        assert!(users.refresh_jwt(&first.refresh_token).await.is_err());
+        assert!(users.refresh_jwt(&second.refresh_token).await.is_err());
+        assert!(users.jwt_user(&second.access_token).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_trusted_claims_skip_revocation() {
+        let users = users(true).await;
+        let user = users.get_by_email("jwt@example.com").await.unwrap();
+        let pair = users
+            .issue_jwt(&user, &ClientInfo::default())
+            .await
+            .unwrap();
+        users
+            .revoke_refresh_token(&pair.refresh_token)
+            .await
+            .unwrap();
+
+        let claimed = users.jwt_user(&pair.access_token).await.unwrap();
+        assert_eq!(claimed.email(), "jwt@example.com");
+        assert!(users.refresh_jwt(&pair.refresh_token).await.is_err());
+        assert!(users.jwt_user("not.a.token").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_families_are_revoked_with_other_sessions() {
+        let users = users(false).await;
+        let user = users.get_by_email("jwt@example.com").await.unwrap();
+        let key = AuthKey::new(user.id, "secret".into(), &ClientInfo::default());
+        users.sess.insert("laptop", key).await.unwrap();
+        let sessions = users.sessions(user.id).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+
+        let pair = users
+            .issue_jwt(&user, &ClientInfo::default())
+            .await
+            .unwrap();
+        assert_eq!(users.sessions(user.id).await.unwrap(), sessions);
+
+        users
+            .revoke_other_sessions(user.id, "laptop")
+            .await
+            .unwrap();
+        assert_eq!(users.sessions(user.id).await.unwrap(), sessions);
+        assert!(users.jwt_user(&pair.access_token).await.is_none());
+        assert!(users.refresh_jwt(&pair.refresh_token).await.is_err());
+    }
+}
@@ -1,41 +1,436 @@
 use crate::prelude::*;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::net::IpAddr;
 use std::time::Duration;
+use subtle::ConstantTimeEq;
+pub mod cleaner;
 pub mod default;
+#[cfg(feature = "jwt")]
+pub mod jwt;
 
 #[cfg(feature = "redis")]
 pub mod redis;
+#[cfg(feature = "sled")]
+pub mod sled;
 
+pub(crate) const YEAR_IN_SECS: u64 = 365 * 60 * 60 * 24;
 
+/// Marks secrets that are stored as a keyed hash of the session token, rather than the token itself.
+const HASH_PREFIX: &str = "hmac-sha256:";
+
+/// Families of refresh tokens are kept in the session store under ids with this prefix.
+/// They are not sessions, so they can not be used as bearer tokens.
+pub(crate) const REFRESH_PREFIX: &str = "refresh-";
+
+/// The default minimum period between two updates of a session's last activity.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Sessions are stored under a random per-session id, so a single user may hold
+/// several live sessions at the same time (one per device).
+#[rocket::async_trait]
 pub trait SessionManager: Send + Sync {
-    fn insert(&self, id: i32, key: String) -> Result<()>;
-    fn insert_for(&self, id: i32, key: String, time: Duration) -> Result<()>;
-    fn remove(&self, id: i32) -> Result<()>;
-    fn get(&self, id: i32) -> Option<String>;
-    fn clear_all(&self) -> Result<()>;
-    fn clear_expired(&self) -> Result<()>;
+    async fn insert(&self, session_id: &str, key: AuthKey) -> Result<()>;
+    async fn insert_for(&self, session_id: &str, key: AuthKey, time: Duration) -> Result<()>;
+    async fn remove(&self, session_id: &str) -> Result<()>;
+    /// Removes a session and returns it, unless it expired. Of several concurrent calls for the same session,
+    /// only one gets it.
+    async fn take(&self, session_id: &str) -> Result<Option<AuthKey>>;
+    async fn remove_user(&self, user_id: i32) -> Result<()>;
+    async fn get(&self, session_id: &str) -> Option<AuthKey>;
+    async fn list(&self, user_id: i32) -> Result<Vec<(String, AuthKey)>>;
+    async fn touch(&self, session_id: &str, idle_timeout: Option<Duration>) -> Result<()>;
+    async fn clear_all(&self) -> Result<()>;
+    async fn clear_expired(&self) -> Result<usize>;
 }
 
+#[rocket::async_trait]
+impl<T: SessionManager> SessionManager for std::sync::Arc<T> {
+    async fn insert(&self, session_id: &str, key: AuthKey) -> Result<()> {
+        T::insert(self, session_id, key).await
+    }
+    async fn insert_for(&self, session_id: &str, key: AuthKey, time: Duration) -> Result<()> {
+        T::insert_for(self, session_id, key, time).await
+    }
+    async fn remove(&self, session_id: &str) -> Result<()> {
+        T::remove(self, session_id).await
+    }
+    async fn take(&self, session_id: &str) -> Result<Option<AuthKey>> {
+        T::take(self, session_id).await
+    }
+    async fn remove_user(&self, user_id: i32) -> Result<()> {
+        T::remove_user(self, user_id).await
+    }
+    async fn get(&self, session_id: &str) -> Option<AuthKey> {
+        T::get(self, session_id).await
+    }
+    async fn list(&self, user_id: i32) -> Result<Vec<(String, AuthKey)>> {
+        T::list(self, user_id).await
+    }
+    async fn touch(&self, session_id: &str, idle_timeout: Option<Duration>) -> Result<()> {
+        T::touch(self, session_id, idle_timeout).await
+    }
+    async fn clear_all(&self) -> Result<()> {
+        T::clear_all(self).await
+    }
+    async fn clear_expired(&self) -> Result<usize> {
+        T::clear_expired(self).await
+    }
+}
+
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthKey {
-    expires: i64,
-    secret: String,
+    pub(crate) user_id: i32,
+    pub(crate) expires: i64,
+    pub(crate) idle_expires: Option<i64>,
+    pub(crate) secret: String,
+    pub(crate) time_stamp: i64,
+    pub(crate) last_active: i64,
+    pub(crate) ip: Option<String>,
+    pub(crate) user_agent: Option<String>,
+    /// Set when the privileges of the user changed, so that the token is replaced on the next request.
+    #[serde(default)]
+    pub(crate) rotate: bool,
+    /// For a family of refresh tokens, the hashes of the tokens it already exchanged, separated by spaces.
+    #[serde(default)]
+    pub(crate) used_secrets: String,
 }
 
-impl From<String> for AuthKey {
-    fn from(secret: String) -> AuthKey {
+impl AuthKey {
+    pub(crate) fn new(user_id: i32, secret: String, client: &ClientInfo) -> AuthKey {
         AuthKey {
-            expires: 31536000,
-            secret
+            user_id,
+            expires: now() + YEAR_IN_SECS as i64,
+            idle_expires: None,
+            secret,
+            time_stamp: now(),
+            last_active: now(),
+            ip: client.ip.clone(),
+            user_agent: client.user_agent.clone(),
+            rotate: false,
+            used_secrets: String::new(),
         }
     }
+
+    /// The period of time left until the key expires by age.
+    pub(crate) fn lifetime(&self) -> Duration {
+        Duration::from_secs((self.expires - now()).max(0) as u64)
+    }
+
+    /// Sets the key to expire after the given period of time, counting from now.
+    pub(crate) fn expire_in(&mut self, time: Duration) {
+        self.expires = now() + time.as_secs() as i64;
+    }
+
+    /// Marks the key as used now, and moves its idle deadline accordingly.
+    pub(crate) fn refresh(&mut self, idle_timeout: Option<Duration>) {
+        self.last_active = now();
+        self.idle_expires = idle_deadline(idle_timeout);
+    }
+
+    /// The moment the key expires, either by age or by inactivity.
+    pub(crate) fn deadline(&self) -> i64 {
+        match self.idle_expires {
+            Some(idle_expires) => idle_expires.min(self.expires),
+            None => self.expires,
+        }
+    }
+
+    /// The number of seconds left until the key expires.
+    pub(crate) fn ttl(&self) -> i64 {
+        self.deadline() - now()
+    }
+
+    pub(crate) fn is_expired(&self) -> bool {
+        self.ttl() <= 0
+    }
+
+    /// Whether the secret is a hash of the session token.
+    /// Keys created before tokens were hashed hold the token itself.
+    pub(crate) fn is_hashed(&self) -> bool {
+        self.secret.starts_with(HASH_PREFIX)
+    }
+
+    /// Checks the token of a session against this key, in constant time.
+    pub(crate) fn verify(&self, hash_key: &[u8], token: &str) -> bool {
+        let expected = if self.is_hashed() {
+            hash_token(hash_key, token)
+        } else {
+            token.to_string()
+        };
+        expected.as_bytes().ct_eq(self.secret.as_bytes()).into()
+    }
 }
 
-impl From<&str> for AuthKey {
-    fn from(secret: &str) -> AuthKey {
-        AuthKey {
-            expires: 31536000,
-            secret: secret.into()
+/// A URL-safe signature of `message` under `key`, for tokens that are checked without being stored.
+pub(crate) fn sign(key: &[u8], message: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(message.as_bytes());
+    URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+}
+
+/// A keyed hash of a session token, which is what gets stored in the session store.
+pub(crate) fn hash_token(hash_key: &[u8], token: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(hash_key).expect("HMAC accepts keys of any size");
+    mac.update(token.as_bytes());
+    let hash = mac.finalize().into_bytes();
+    let mut secret = String::from(HASH_PREFIX);
+    for byte in hash {
+        secret.push_str(&format!("{:02x}", byte));
+    }
+    secret
+}
+
+/// The idle deadline of a session that is used now.
+pub(crate) fn idle_deadline(idle_timeout: Option<Duration>) -> Option<i64> {
+    idle_timeout.map(|time| now() + time.as_secs() as i64)
+}
+
+/// The client a session was created from, as seen by the [`Auth`](crate::Auth) guard.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ClientInfo {
+    pub(crate) ip: Option<String>,
+    pub(crate) user_agent: Option<String>,
+}
+
+/// Limits on how long sessions may live. It can be set with [`Users::set_session_policy`](crate::Users::set_session_policy).
+/// ```rust
+/// # use rocket_auth2::{Users, SessionPolicy};
+/// # use std::time::Duration;
+/// # fn func(mut users: Users) {
+/// users.set_session_policy(SessionPolicy {
+///     idle_timeout: Some(Duration::from_secs(15 * 60)),
+///     absolute_timeout: Some(Duration::from_secs(8 * 60 * 60)),
+///     ..Default::default()
+/// });
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionPolicy {
+    /// Sessions end after this period of time without any request. By default there is no idle timeout.
+    pub idle_timeout: Option<Duration>,
+    /// Sessions end this long after logging in, regardless of activity or of the period passed to
+    /// [`Auth::login_for`](crate::Auth::login_for). By default sessions last one year.
+    pub absolute_timeout: Option<Duration>,
+    /// The [`Auth`](crate::Auth) and [`User`](crate::User) guards refresh the idle deadline at most once
+    /// in this period of time, so that not every request writes to the session store. It defaults to one minute.
+    pub refresh_interval: Duration,
+    /// Whether [`Auth::change_password`](crate::Auth::change_password) ends every other session of the user,
+    /// along with the families of refresh tokens issued to the user.
+    /// By default other sessions stay logged in.
+    pub revoke_on_password_change: bool,
+    /// Binds sessions to the client that logged in. A session used by a client that differs from it
+    /// beyond the tolerance of the [`ClientBinding`] is ended, so the client has to log in again.
+    /// By default sessions are not bound.
+    pub client_binding: Option<ClientBinding>,
+}
+
+impl Default for SessionPolicy {
+    fn default() -> SessionPolicy {
+        SessionPolicy {
+            idle_timeout: None,
+            absolute_timeout: None,
+            refresh_interval: REFRESH_INTERVAL,
+            revoke_on_password_change: false,
+            client_binding: None,
+        }
+    }
+}
+
+impl SessionPolicy {
+    /// The lifetime of a session that was requested to last `time`, capped by the absolute timeout.
+    pub(crate) fn lifetime(&self, time: Duration) -> Duration {
+        match self.absolute_timeout {
+            Some(absolute_timeout) => time.min(absolute_timeout),
+            None => time,
+        }
+    }
+}
+
+/// How closely the client of a request must resemble the client that logged in, see [`SessionPolicy::client_binding`].
+/// Stateless sessions do not record their client, so they are not bound.
+/// ```rust
+/// # use rocket_auth2::{Users, SessionPolicy, ClientBinding};
+/// # fn func(mut users: Users) {
+/// users.set_session_policy(SessionPolicy {
+///     client_binding: Some(ClientBinding {
+///         ipv4_prefix: 16,
+///         ..Default::default()
+///     }),
+///     ..Default::default()
+/// });
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientBinding {
+    /// The number of leading bits of an IPv4 address that may not change, 24 by default.
+    /// The default tolerates moving within a /24 network, and 0 ignores the address.
+    pub ipv4_prefix: u8,
+    /// The number of leading bits of an IPv6 address that may not change, 64 by default.
+    pub ipv6_prefix: u8,
+    /// Whether the `User-Agent` header may not change, which is the default.
+    pub user_agent: bool,
+}
+
+impl Default for ClientBinding {
+    fn default() -> ClientBinding {
+        ClientBinding {
+            ipv4_prefix: 24,
+            ipv6_prefix: 64,
+            user_agent: true,
+        }
+    }
+}
+
+impl ClientBinding {
+    /// Whether `client` resembles the client that created `key`.
+    /// A session whose client was not known when it was created can not be bound to it.
+    pub(crate) fn matches(&self, key: &AuthKey, client: &ClientInfo) -> bool {
+        let same_agent =
+            !self.user_agent || key.user_agent.is_none() || key.user_agent == client.user_agent;
+        same_agent && self.same_network(key.ip.as_deref(), client.ip.as_deref())
+    }
+
+    fn same_network(&self, recorded: Option<&str>, current: Option<&str>) -> bool {
+        let Some(recorded) = recorded.and_then(|ip| ip.parse().ok()) else {
+            return true;
+        };
+        let Some(current) = current.and_then(|ip| ip.parse().ok()) else {
+            return false;
+        };
+        match (canonical(recorded), canonical(current)) {
+            (IpAddr::V4(recorded), IpAddr::V4(current)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.ipv4_prefix.min(32) as u32)
+                    .unwrap_or(0);
+                u32::from(recorded) & mask == u32::from(current) & mask
+            }
+            (IpAddr::V6(recorded), IpAddr::V6(current)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.ipv6_prefix.min(128) as u32)
+                    .unwrap_or(0);
+                u128::from(recorded) & mask == u128::from(current) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// IPv4 clients may be reported as IPv4-mapped IPv6 addresses, which are compared as IPv4 addresses.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Metadata about one of the active sessions of a user.
+/// It can be used to show a user where they are logged in.
+/// ```rust
+/// # use rocket::get;
+/// # use rocket_auth2::{Auth, Error};
+/// #[get("/my-sessions")]
+/// async fn my_sessions(auth: Auth<'_>) -> Result<String, Error> {
+///     let sessions = auth.sessions().await?;
+///     Ok(format!("{:?}", sessions))
+/// }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SessionInfo {
+    /// The identifier of the session. It can be passed to [`Auth::revoke_session`](crate::Auth::revoke_session).
+    pub session_id: String,
+    /// It represents the Unix time in which the user logged in. It is measured in seconds.
+    pub time_stamp: i64,
+    /// The Unix time of the last request made with this session. It is measured in seconds.
+    pub last_active: i64,
+    /// The IP address of the client that logged in, if it was known.
+    pub ip: Option<String>,
+    /// The `User-Agent` header of the client that logged in, if it was sent.
+    pub user_agent: Option<String>,
+}
+
+impl SessionInfo {
+    pub(crate) fn new(session_id: String, key: AuthKey) -> SessionInfo {
+        SessionInfo {
+            session_id,
+            time_stamp: key.time_stamp,
+            last_active: key.last_active,
+            ip: key.ip,
+            user_agent: key.user_agent,
         }
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_absolute_timeout_caps_lifetime() {
+        let hour = Duration::from_secs(3600);
+        let policy = SessionPolicy {
+            absolute_timeout: Some(hour),
+            ..Default::default()
+        };
+        assert_eq!(policy.lifetime(hour * 2), hour);
+        assert_eq!(policy.lifetime(hour / 2), hour / 2);
+        assert_eq!(SessionPolicy::default().lifetime(hour * 2), hour * 2);
+    }
+
+    #[test]
+    fn test_client_binding_tolerates_network_drift() {
+        let client = |ip: &str, user_agent: &str| ClientInfo {
+            ip: Some(ip.into()),
+            user_agent: Some(user_agent.into()),
+        };
+        let key = AuthKey::new(1, String::new(), &client("192.0.2.10", "Firefox"));
+        let binding = ClientBinding::default();
+        assert!(binding.matches(&key, &client("192.0.2.200", "Firefox")));
+        assert!(binding.matches(&key, &client("::ffff:192.0.2.1", "Firefox")));
+        assert!(!binding.matches(&key, &client("192.0.3.10", "Firefox")));
+        assert!(!binding.matches(&key, &client("192.0.2.10", "Chrome")));
+        assert!(!binding.matches(&key, &ClientInfo::default()));
+
+        let loose = ClientBinding {
+            ipv4_prefix: 0,
+            user_agent: false,
+            ..binding
+        };
+        assert!(loose.matches(&key, &client("203.0.113.5", "Chrome")));
+        assert!(!loose.matches(&key, &client("2001:db8::1", "Chrome")));
+        let v6 = AuthKey::new(1, String::new(), &client("2001:db8:0:1::5", "curl"));
+        assert!(binding.matches(&v6, &client("2001:db8:0:1::9", "curl")));
+        assert!(!binding.matches(&v6, &client("2001:db8:0:2::5", "curl")));
+    }
+
+    #[test]
+    fn test_idle_timeout_expires_key() {
+        let mut key = AuthKey::new(1, "secret".into(), &ClientInfo::default());
+        key.refresh(Some(Duration::ZERO));
+        assert!(key.is_expired());
+        key.refresh(Some(Duration::from_secs(60)));
+        assert!(!key.is_expired());
+        assert!(key.ttl() <= 60);
+    }
+
+    #[test]
+    fn test_hashed_key_verifies_token() {
+        let key = AuthKey::new(1, hash_token(b"key", "token"), &ClientInfo::default());
+        assert!(key.is_hashed());
+        assert!(key.verify(b"key", "token"));
+        assert!(!key.verify(b"key", "other token"));
+        assert!(!key.verify(b"other key", "token"));
+        assert!(!key.verify(b"key", &key.secret));
+    }
+
+    #[test]
+    fn test_legacy_key_verifies_token() {
+        let key = AuthKey::new(1, "token".into(), &ClientInfo::default());
+        assert!(!key.is_hashed());
+        assert!(key.verify(b"key", "token"));
+        assert!(!key.verify(b"key", "other"));
+    }
+}
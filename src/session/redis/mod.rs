@@ -1,42 +1,375 @@
 use super::SessionManager;
+use super::{AuthKey, YEAR_IN_SECS};
 use crate::prelude::*;
 
-use redis::{Client, Commands};
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, Client};
 
-const YEAR_IN_SECS: u64 = 365 * 60 * 60 * 24;
+/// The prefix of every key written by the redis session store, unless another one is chosen
+/// with [`Users::open_redis_with_prefix`](crate::Users::open_redis_with_prefix).
+pub(crate) const DEFAULT_PREFIX: &str = "rocket_auth:session:";
 
-impl SessionManager for Client {
-    fn insert(&self, id: i32, key: String) -> Result<()> {
-        let mut cnn = self.get_connection()?;
-        let _: () = cnn.set_ex(id, key, YEAR_IN_SECS)?;
+/// Stores a session and adds it to the index of its user, whose expiry is pushed back so that it outlives the session.
+const INSERT_SCRIPT: &str = r"
+redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[2])
+redis.call('SADD', KEYS[2], ARGV[3])
+if redis.call('TTL', KEYS[2]) < tonumber(ARGV[2]) then
+    redis.call('EXPIRE', KEYS[2], ARGV[2])
+end
+";
+
+/// A `SCAN` pattern that matches every key starting with `prefix`, and nothing else.
+fn scan_pattern(prefix: &str) -> String {
+    let mut pattern = String::new();
+    for c in prefix.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('*');
+    pattern
+}
+
+/// Stores sessions on a redis server. Every key it writes starts with the same prefix,
+/// so several apps can share one redis database without their keys colliding.
+///
+/// A session is kept until its absolute deadline, even once it expired by inactivity, so that it can always
+/// be removed from the index of its user. Each index expires along with the last of its sessions.
+#[derive(Clone)]
+pub(crate) struct RedisSessions {
+    cnn: MultiplexedConnection,
+    prefix: String,
+}
+
+impl RedisSessions {
+    pub(crate) fn new(cnn: MultiplexedConnection, prefix: impl Into<String>) -> Self {
+        RedisSessions {
+            cnn,
+            prefix: prefix.into(),
+        }
+    }
+
+    fn session(&self, session_id: &str) -> String {
+        format!("{}{}", self.prefix, session_id)
+    }
+
+    /// Session ids never contain a colon, so the index can not collide with a session.
+    fn user_sessions(&self, user_id: i32) -> String {
+        format!("{}{}", self.user_sessions_prefix(), user_id)
+    }
+
+    /// The prefix of the index of every user.
+    fn user_sessions_prefix(&self) -> String {
+        format!("{}user:", self.prefix)
+    }
+
+    /// A stored session, even if it expired by inactivity.
+    async fn stored(&self, session_id: &str) -> Result<Option<AuthKey>> {
+        let mut cnn = self.cnn.clone();
+        let key: Option<String> = AsyncCommands::get(&mut cnn, self.session(session_id)).await?;
+        Ok(match key {
+            Some(key) => Some(serde_json::from_str(&key)?),
+            None => None,
+        })
+    }
+
+    /// Removes the sessions that expired from an index, and returns how many were removed.
+    async fn prune(&self, index: &str) -> Result<usize> {
+        let mut cnn = self.cnn.clone();
+        let session_ids: Vec<String> = cnn.smembers(index).await?;
+        let mut removed = 0;
+        for session_id in session_ids {
+            match self.stored(&session_id).await? {
+                Some(key) if !key.is_expired() => continue,
+                Some(_) => {
+                    let _: () = cnn.del(self.session(&session_id)).await?;
+                }
+                None => (),
+            }
+            let _: () = cnn.srem(index, &session_id).await?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+}
+
+#[rocket::async_trait]
+impl SessionManager for RedisSessions {
+    async fn insert(&self, session_id: &str, key: AuthKey) -> Result<()> {
+        self.insert_for(session_id, key, Duration::from_secs(YEAR_IN_SECS))
+            .await
+    }
+
+    async fn insert_for(&self, session_id: &str, mut key: AuthKey, time: Duration) -> Result<()> {
+        let mut cnn = self.cnn.clone();
+        key.expire_in(time);
+        redis::Script::new(INSERT_SCRIPT)
+            .key(self.session(session_id))
+            .key(self.user_sessions(key.user_id))
+            .arg(serde_json::to_string(&key)?)
+            .arg(key.lifetime().as_secs().max(1))
+            .arg(session_id)
+            .invoke_async::<()>(&mut cnn)
+            .await?;
         Ok(())
     }
 
-    fn insert_for(&self, id: i32, key: String, time: Duration) -> Result<()> {
-        let mut cnn = self.get_connection()?;
-        let _: () = cnn.set_ex(id, key, time.as_secs())?;
+    async fn remove(&self, session_id: &str) -> Result<()> {
+        let mut cnn = self.cnn.clone();
+        if let Some(key) = self.stored(session_id).await? {
+            let _: () = cnn
+                .srem(self.user_sessions(key.user_id), session_id)
+                .await?;
+        }
+        let _: () = cnn.del(self.session(session_id)).await?;
         Ok(())
     }
 
-    fn remove(&self, id: i32) -> Result<()> {
-        let mut cnn = self.get_connection()?;
-        let _: () = cnn.del(id)?;
+    async fn take(&self, session_id: &str) -> Result<Option<AuthKey>> {
+        let mut cnn = self.cnn.clone();
+        // only the transaction whose `DEL` removed the key gets the session.
+        let (key, removed): (Option<String>, u32) = redis::pipe()
+            .atomic()
+            .get(self.session(session_id))
+            .del(self.session(session_id))
+            .query_async(&mut cnn)
+            .await?;
+        let Some(key) = key.filter(|_| removed == 1) else {
+            return Ok(None);
+        };
+        let key: AuthKey = serde_json::from_str(&key)?;
+        let _: () = cnn
+            .srem(self.user_sessions(key.user_id), session_id)
+            .await?;
+        Ok(Some(key).filter(|key| !key.is_expired()))
+    }
+
+    async fn remove_user(&self, user_id: i32) -> Result<()> {
+        let mut cnn = self.cnn.clone();
+        let session_ids: Vec<String> = cnn.smembers(self.user_sessions(user_id)).await?;
+        if !session_ids.is_empty() {
+            let keys: Vec<String> = session_ids.iter().map(|id| self.session(id)).collect();
+            let _: () = cnn.del(keys).await?;
+        }
+        let _: () = cnn.del(self.user_sessions(user_id)).await?;
         Ok(())
     }
 
-    fn get(&self, id: i32) -> Option<String> {
-        let mut cnn = self.get_connection().ok()?;
-        let key = cnn.get(id).ok()?;
-        key
+    async fn get(&self, session_id: &str) -> Option<AuthKey> {
+        let key = self.stored(session_id).await.ok()??;
+        Some(key).filter(|key| !key.is_expired())
     }
 
-    fn clear_all(&self) -> Result<()> {
-        let mut cnn = self.get_connection()?;
-        redis::Cmd::new().arg("FLUSHDB").exec(&mut cnn)?;
+    async fn list(&self, user_id: i32) -> Result<Vec<(String, AuthKey)>> {
+        let mut cnn = self.cnn.clone();
+        let session_ids: Vec<String> = cnn.smembers(self.user_sessions(user_id)).await?;
+        let mut sessions = vec![];
+        for session_id in session_ids {
+            match self.get(&session_id).await {
+                Some(key) => sessions.push((session_id, key)),
+                // the session expired, so it is removed from the index as well.
+                None => cnn.srem(self.user_sessions(user_id), &session_id).await?,
+            }
+        }
+        Ok(sessions)
+    }
+
+    async fn touch(&self, session_id: &str, idle_timeout: Option<Duration>) -> Result<()> {
+        let mut cnn = self.cnn.clone();
+        if let Some(mut key) = self.get(session_id).await {
+            key.refresh(idle_timeout);
+            redis::cmd("SET")
+                .arg(self.session(session_id))
+                .arg(serde_json::to_string(&key)?)
+                .arg("XX")
+                .arg("EX")
+                .arg(key.lifetime().as_secs().max(1))
+                .exec_async(&mut cnn)
+                .await?;
+        }
         Ok(())
     }
 
-    fn clear_expired(&self) -> Result<()> {
+    /// Only the keys that start with the prefix of this store are deleted.
+    async fn clear_all(&self) -> Result<()> {
+        let mut cnn = self.cnn.clone();
+        let pattern = scan_pattern(&self.prefix);
+        let mut cursor = 0;
+        loop {
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(100)
+                .query_async(&mut cnn)
+                .await?;
+            if !keys.is_empty() {
+                let _: () = cnn.del(keys).await?;
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
         Ok(())
     }
+
+    /// Redis removes sessions once they reach their absolute deadline, so this removes the sessions
+    /// that expired by inactivity, and prunes the indexes of every user.
+    async fn clear_expired(&self) -> Result<usize> {
+        let mut cnn = self.cnn.clone();
+        let pattern = scan_pattern(&self.user_sessions_prefix());
+        let mut removed = 0;
+        let mut cursor = 0;
+        loop {
+            let (next, indexes): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(100)
+                .query_async(&mut cnn)
+                .await?;
+            for index in indexes {
+                removed += self.prune(&index).await?;
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        Ok(removed)
+    }
+}
+
+/// A multiplexed connection stores sessions under the [`DEFAULT_PREFIX`].
+#[rocket::async_trait]
+impl SessionManager for MultiplexedConnection {
+    async fn insert(&self, session_id: &str, key: AuthKey) -> Result<()> {
+        RedisSessions::new(self.clone(), DEFAULT_PREFIX)
+            .insert(session_id, key)
+            .await
+    }
+
+    async fn insert_for(&self, session_id: &str, key: AuthKey, time: Duration) -> Result<()> {
+        RedisSessions::new(self.clone(), DEFAULT_PREFIX)
+            .insert_for(session_id, key, time)
+            .await
+    }
+
+    async fn remove(&self, session_id: &str) -> Result<()> {
+        RedisSessions::new(self.clone(), DEFAULT_PREFIX)
+            .remove(session_id)
+            .await
+    }
+
+    async fn take(&self, session_id: &str) -> Result<Option<AuthKey>> {
+        RedisSessions::new(self.clone(), DEFAULT_PREFIX)
+            .take(session_id)
+            .await
+    }
+
+    async fn remove_user(&self, user_id: i32) -> Result<()> {
+        RedisSessions::new(self.clone(), DEFAULT_PREFIX)
+            .remove_user(user_id)
+            .await
+    }
+
+    async fn get(&self, session_id: &str) -> Option<AuthKey> {
+        RedisSessions::new(self.clone(), DEFAULT_PREFIX)
+            .get(session_id)
+            .await
+    }
+
+    async fn list(&self, user_id: i32) -> Result<Vec<(String, AuthKey)>> {
+        RedisSessions::new(self.clone(), DEFAULT_PREFIX)
+            .list(user_id)
+            .await
+    }
+
+    async fn touch(&self, session_id: &str, idle_timeout: Option<Duration>) -> Result<()> {
+        RedisSessions::new(self.clone(), DEFAULT_PREFIX)
+            .touch(session_id, idle_timeout)
+            .await
+    }
+
+    async fn clear_all(&self) -> Result<()> {
+        RedisSessions::new(self.clone(), DEFAULT_PREFIX)
+            .clear_all()
+            .await
+    }
+
+    async fn clear_expired(&self) -> Result<usize> {
+        RedisSessions::new(self.clone(), DEFAULT_PREFIX)
+            .clear_expired()
+            .await
+    }
+}
+
+/// A [`Client`] opens a new multiplexed connection for every operation, which costs a round trip each time.
+/// Prefer [`Users::open_redis`](crate::Users::open_redis) or a [`MultiplexedConnection`], which share a single connection.
+#[rocket::async_trait]
+impl SessionManager for Client {
+    async fn insert(&self, session_id: &str, key: AuthKey) -> Result<()> {
+        let cnn = self.get_multiplexed_async_connection().await?;
+        cnn.insert(session_id, key).await
+    }
+
+    async fn insert_for(&self, session_id: &str, key: AuthKey, time: Duration) -> Result<()> {
+        let cnn = self.get_multiplexed_async_connection().await?;
+        cnn.insert_for(session_id, key, time).await
+    }
+
+    async fn remove(&self, session_id: &str) -> Result<()> {
+        let cnn = self.get_multiplexed_async_connection().await?;
+        SessionManager::remove(&cnn, session_id).await
+    }
+
+    async fn take(&self, session_id: &str) -> Result<Option<AuthKey>> {
+        let cnn = self.get_multiplexed_async_connection().await?;
+        cnn.take(session_id).await
+    }
+
+    async fn remove_user(&self, user_id: i32) -> Result<()> {
+        let cnn = self.get_multiplexed_async_connection().await?;
+        cnn.remove_user(user_id).await
+    }
+
+    async fn get(&self, session_id: &str) -> Option<AuthKey> {
+        let cnn = self.get_multiplexed_async_connection().await.ok()?;
+        SessionManager::get(&cnn, session_id).await
+    }
+
+    async fn list(&self, user_id: i32) -> Result<Vec<(String, AuthKey)>> {
+        let cnn = self.get_multiplexed_async_connection().await?;
+        cnn.list(user_id).await
+    }
+
+    async fn touch(&self, session_id: &str, idle_timeout: Option<Duration>) -> Result<()> {
+        let cnn = self.get_multiplexed_async_connection().await?;
+        cnn.touch(session_id, idle_timeout).await
+    }
+
+    async fn clear_all(&self) -> Result<()> {
+        let cnn = self.get_multiplexed_async_connection().await?;
+        cnn.clear_all().await
+    }
+
+    async fn clear_expired(&self) -> Result<usize> {
+        let cnn = self.get_multiplexed_async_connection().await?;
+        cnn.clear_expired().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scan_pattern_escapes_prefix() {
+        assert_eq!(scan_pattern(DEFAULT_PREFIX), "rocket_auth:session:*");
+        assert_eq!(scan_pattern("app[1]:*:"), "app\\[1\\]:\\*:*");
+    }
 }
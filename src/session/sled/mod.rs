@@ -0,0 +1,238 @@
+use super::SessionManager;
+use super::{AuthKey, YEAR_IN_SECS};
+use crate::prelude::*;
+use sled::transaction::{ConflictableTransactionError, ConflictableTransactionResult};
+use sled::{Transactional, Tree};
+
+const TABLE_NAME: &str = "sessions";
+/// Session ids, ordered by the moment their session expires.
+const EXPIRY_INDEX_NAME: &str = "sessions_expiry";
+/// Session ids, grouped by the user they belong to.
+const USER_INDEX_NAME: &str = "sessions_users";
+
+fn map_error(e: impl Into<Error>) -> ConflictableTransactionError<Error> {
+    ConflictableTransactionError::Abort(e.into())
+}
+
+/// Deadlines are positive, so their big endian bytes sort in chronological order.
+fn expiry_key(deadline: i64, session_id: &str) -> Vec<u8> {
+    [&deadline.to_be_bytes()[..], session_id.as_bytes()].concat()
+}
+
+fn user_key(user_id: i32, session_id: &str) -> Vec<u8> {
+    [&user_id.to_be_bytes()[..], session_id.as_bytes()].concat()
+}
+
+fn serialize_key(key: &AuthKey) -> Result<Vec<u8>> {
+    Ok(bson::to_vec(key)?)
+}
+
+fn deserialize_key(key: &[u8]) -> Result<AuthKey> {
+    Ok(bson::from_slice(key)?)
+}
+
+fn open_trees(db: &sled::Db) -> Result<(Tree, Tree, Tree)> {
+    Ok((
+        db.open_tree(TABLE_NAME)?,
+        db.open_tree(EXPIRY_INDEX_NAME)?,
+        db.open_tree(USER_INDEX_NAME)?,
+    ))
+}
+
+/// Removes a session together with its index entries, and returns it if it existed.
+fn remove_session(db: &sled::Db, session_id: &str) -> Result<Option<AuthKey>> {
+    let (tree, expiry, users) = open_trees(db)?;
+
+    let removed = (&tree, &expiry, &users).transaction(
+        |(tree, expiry, users)| -> ConflictableTransactionResult<Option<AuthKey>, Error> {
+            let Some(old_entry) = tree.remove(session_id.as_bytes())? else {
+                return Ok(None);
+            };
+            let old_key = deserialize_key(&old_entry).map_err(map_error)?;
+            expiry.remove(expiry_key(old_key.deadline(), session_id))?;
+            users.remove(user_key(old_key.user_id, session_id))?;
+            Ok(Some(old_key))
+        },
+    )?;
+
+    Ok(removed)
+}
+
+#[rocket::async_trait]
+impl SessionManager for sled::Db {
+    async fn insert(&self, session_id: &str, key: AuthKey) -> Result<()> {
+        self.insert_for(session_id, key, Duration::from_secs(YEAR_IN_SECS))
+            .await
+    }
+
+    async fn insert_for(&self, session_id: &str, mut key: AuthKey, time: Duration) -> Result<()> {
+        key.expire_in(time);
+        let data = serialize_key(&key)?;
+        let (tree, expiry, users) = open_trees(self)?;
+
+        (&tree, &expiry, &users).transaction(
+            |(tree, expiry, users)| -> ConflictableTransactionResult<(), Error> {
+                if let Some(old_entry) = tree.insert(session_id.as_bytes(), data.as_slice())? {
+                    let old_key = deserialize_key(&old_entry).map_err(map_error)?;
+                    expiry.remove(expiry_key(old_key.deadline(), session_id))?;
+                    users.remove(user_key(old_key.user_id, session_id))?;
+                }
+                expiry.insert(expiry_key(key.deadline(), session_id), &[])?;
+                users.insert(user_key(key.user_id, session_id), &[])?;
+                Ok(())
+            },
+        )?;
+
+        Ok(())
+    }
+
+    async fn remove(&self, session_id: &str) -> Result<()> {
+        remove_session(self, session_id)?;
+        Ok(())
+    }
+
+    async fn take(&self, session_id: &str) -> Result<Option<AuthKey>> {
+        let key = remove_session(self, session_id)?;
+        Ok(key.filter(|key| !key.is_expired()))
+    }
+
+    async fn remove_user(&self, user_id: i32) -> Result<()> {
+        let users = self.open_tree(USER_INDEX_NAME)?;
+        for entry in users.scan_prefix(user_id.to_be_bytes()).keys() {
+            let entry = entry?;
+            remove_session(self, &String::from_utf8_lossy(&entry[size_of::<i32>()..]))?;
+        }
+        Ok(())
+    }
+
+    async fn get(&self, session_id: &str) -> Option<AuthKey> {
+        let tree = self.open_tree(TABLE_NAME).ok()?;
+        let key = deserialize_key(&tree.get(session_id).ok()??).ok()?;
+        if key.is_expired() {
+            remove_session(self, session_id).ok()?;
+            return None;
+        }
+        Some(key)
+    }
+
+    async fn list(&self, user_id: i32) -> Result<Vec<(String, AuthKey)>> {
+        let users = self.open_tree(USER_INDEX_NAME)?;
+        let mut sessions = vec![];
+        for entry in users.scan_prefix(user_id.to_be_bytes()).keys() {
+            let entry = entry?;
+            let session_id = String::from_utf8_lossy(&entry[size_of::<i32>()..]).into_owned();
+            if let Some(key) = SessionManager::get(self, &session_id).await {
+                sessions.push((session_id, key));
+            }
+        }
+        Ok(sessions)
+    }
+
+    async fn touch(&self, session_id: &str, idle_timeout: Option<Duration>) -> Result<()> {
+        let (tree, expiry) = (
+            self.open_tree(TABLE_NAME)?,
+            self.open_tree(EXPIRY_INDEX_NAME)?,
+        );
+
+        (&tree, &expiry).transaction(
+            |(tree, expiry)| -> ConflictableTransactionResult<(), Error> {
+                let Some(entry) = tree.get(session_id.as_bytes())? else {
+                    return Ok(());
+                };
+                let mut key = deserialize_key(&entry).map_err(map_error)?;
+                expiry.remove(expiry_key(key.deadline(), session_id))?;
+                key.refresh(idle_timeout);
+                tree.insert(
+                    session_id.as_bytes(),
+                    serialize_key(&key).map_err(map_error)?,
+                )?;
+                expiry.insert(expiry_key(key.deadline(), session_id), &[])?;
+                Ok(())
+            },
+        )?;
+
+        Ok(())
+    }
+
+    async fn clear_all(&self) -> Result<()> {
+        let (tree, expiry, users) = open_trees(self)?;
+        tree.clear()?;
+        expiry.clear()?;
+        users.clear()?;
+        Ok(())
+    }
+
+    async fn clear_expired(&self) -> Result<usize> {
+        let expiry = self.open_tree(EXPIRY_INDEX_NAME)?;
+        let mut removed = 0;
+        for entry in expiry.range(..(now() + 1).to_be_bytes()).keys() {
+            let entry = entry?;
+            let session_id = String::from_utf8_lossy(&entry[size_of::<i64>()..]);
+            if remove_session(self, &session_id)?.is_some() {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::session::ClientInfo;
+
+    fn db() -> sled::Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    fn key(user_id: i32, secret: &str) -> AuthKey {
+        AuthKey::new(user_id, secret.into(), &ClientInfo::default())
+    }
+
+    #[tokio::test]
+    async fn test_sessions_are_indexed_by_user() {
+        let db = db();
+        SessionManager::insert(&db, "laptop", key(1, "first"))
+            .await
+            .unwrap();
+        SessionManager::insert(&db, "phone", key(1, "second"))
+            .await
+            .unwrap();
+        SessionManager::insert(&db, "other", key(2, "third"))
+            .await
+            .unwrap();
+
+        let mut ids: Vec<String> = SessionManager::list(&db, 1)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        ids.sort();
+        assert_eq!(ids, ["laptop", "phone"]);
+
+        SessionManager::remove_user(&db, 1).await.unwrap();
+        assert!(SessionManager::get(&db, "laptop").await.is_none());
+        assert!(SessionManager::get(&db, "other").await.is_some());
+        assert_eq!(db.open_tree(USER_INDEX_NAME).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_clear_expired_uses_expiry_index() {
+        let db = db();
+        SessionManager::insert(&db, "default", key(1, "first"))
+            .await
+            .unwrap();
+        SessionManager::insert_for(&db, "expired", key(2, "second"), Duration::ZERO)
+            .await
+            .unwrap();
+        SessionManager::touch(&db, "default", Some(Duration::from_secs(60)))
+            .await
+            .unwrap();
+
+        assert_eq!(SessionManager::clear_expired(&db).await.unwrap(), 1);
+        assert!(SessionManager::get(&db, "default").await.is_some());
+        assert_eq!(db.open_tree(TABLE_NAME).unwrap().len(), 1);
+        assert_eq!(db.open_tree(EXPIRY_INDEX_NAME).unwrap().len(), 1);
+    }
+}
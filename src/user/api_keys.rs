@@ -0,0 +1,335 @@
+use super::generate_token;
+use crate::cookies::bearer_token;
+use crate::error;
+use crate::prelude::*;
+use crate::session::hash_token;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use std::collections::btree_set::{self, BTreeSet};
+use std::fmt::{Display, Formatter};
+use subtle::ConstantTimeEq;
+
+/// The permissions granted to an API key, such as `read` or `repo:write`.
+/// Scopes are free-form, but they can not be empty or contain whitespace.
+#[derive(PartialEq, Eq, Clone, Serialize, Deserialize, Default)]
+#[serde(transparent)]
+pub struct Scopes {
+    scopes: BTreeSet<String>,
+}
+
+impl Debug for Scopes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.scopes.fmt(f)
+    }
+}
+
+/// Scopes are separated by spaces, as in OAuth 2.0.
+impl Display for Scopes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let scopes: Vec<&str> = self.iter().collect();
+        f.write_str(&scopes.join(" "))
+    }
+}
+
+impl From<String> for Scopes {
+    fn from(scopes: String) -> Self {
+        scopes.split_whitespace().collect()
+    }
+}
+
+impl<S: Into<String>> FromIterator<S> for Scopes {
+    fn from_iter<I: IntoIterator<Item = S>>(scopes: I) -> Self {
+        Scopes {
+            scopes: scopes.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl Scopes {
+    /// An empty set of scopes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The set of the given scopes.
+    pub fn from_strs(scopes: &[&str]) -> Self {
+        scopes.iter().copied().collect()
+    }
+
+    /// Adds a scope, and returns whether it was not already there.
+    pub fn insert(&mut self, scope: impl Into<String>) -> bool {
+        self.scopes.insert(scope.into())
+    }
+
+    /// Removes a scope, and returns whether it was there.
+    pub fn remove(&mut self, scope: &str) -> bool {
+        self.scopes.remove(scope)
+    }
+
+    /// Whether `scope` is one of the scopes.
+    pub fn contains(&self, scope: &str) -> bool {
+        self.scopes.contains(scope)
+    }
+
+    /// The scopes, in alphabetical order.
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.scopes.iter().map(String::as_str)
+    }
+
+    fn validate(&self) -> Result<()> {
+        match self
+            .scopes
+            .iter()
+            .find(|scope| scope.is_empty() || scope.contains(char::is_whitespace))
+        {
+            Some(scope) => Err(Error::InvalidScopeError(scope.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl IntoIterator for Scopes {
+    type Item = String;
+    type IntoIter = btree_set::IntoIter<String>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.scopes.into_iter()
+    }
+}
+
+/// An API key, also known as a personal access token, which lets scripts act on behalf of a user.
+/// Only a keyed hash of the key is stored, so the key itself is shown once, when it is created
+/// with [`Users::create_api_key`].
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ApiKey {
+    /// A random identifier of the key, which is also the part of the key before the dot.
+    pub id: String,
+    /// The id of the user the key belongs to.
+    pub user_id: i32,
+    /// A name given by the user, to tell their keys apart.
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub(crate) hash: String,
+    /// The permissions granted to the key.
+    #[cfg_attr(feature = "sqlx", sqlx(try_from = "String"))]
+    pub scopes: Scopes,
+    /// The Unix time at which the key was created.
+    pub created: i64,
+    /// The Unix time at which the key expires, if it does.
+    pub expires: Option<i64>,
+    /// The Unix time at which the key was last used, updated at most once every [`SessionPolicy::refresh_interval`].
+    pub last_used: Option<i64>,
+}
+
+impl ApiKey {
+    /// Whether the key has an expiry time which has passed.
+    pub fn is_expired(&self) -> bool {
+        matches!(self.expires, Some(expires) if expires <= now())
+    }
+}
+
+/// The `ApiKeyUser` guard authenticates a request through an API key, sent in an `X-Api-Key` header
+/// or in an `Authorization: Bearer <key>` header. It holds the user the key belongs to, along with the key itself.
+/// ```rust
+/// # use rocket::get;
+/// # use rocket_auth2::{ApiKeyUser, Error};
+/// #[get("/api/reports")]
+/// fn reports(user: ApiKeyUser) -> Result<String, Error> {
+///     user.require_scope("reports:read")?;
+///     Ok(format!("the reports of {}", user.email()))
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeyUser {
+    pub user: User,
+    pub key: ApiKey,
+}
+
+impl ApiKeyUser {
+    /// Whether the key was granted `scope`.
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.key.scopes.contains(scope)
+    }
+
+    /// Fails with [`Error::UnauthorizedError`] unless the key was granted `scope`.
+    pub fn require_scope(&self, scope: &str) -> Result<()> {
+        if self.has_scope(scope) {
+            Ok(())
+        } else {
+            Err(Error::UnauthorizedError)
+        }
+    }
+}
+
+impl Deref for ApiKeyUser {
+    type Target = User;
+    fn deref(&self) -> &Self::Target {
+        &self.user
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiKeyUser {
+    type Error = Error;
+    async fn from_request(request: &'r Request<'_>) -> Outcome<ApiKeyUser, Error> {
+        let Some(users) = request.rocket().state::<Users>() else {
+            return Outcome::Error((Status::InternalServerError, Error::UnmanagedStateError));
+        };
+        let key = request
+            .headers()
+            .get_one("X-Api-Key")
+            .or_else(|| bearer_token(request));
+        match key {
+            Some(key) => match users.api_key_user(key).await {
+                Some(user) => Outcome::Success(user),
+                None => Outcome::Error((Status::Unauthorized, error::Error::UnauthorizedError)),
+            },
+            None => Outcome::Error((Status::Unauthorized, error::Error::UnauthorizedError)),
+        }
+    }
+}
+
+impl Users {
+    /// Creates an API key for a user, which expires after `lifetime` if one is given.
+    /// It returns the key, which can not be retrieved later, together with its description.
+    /// ```rust
+    /// # use rocket_auth2::{Users, Error, Scopes};
+    /// # use std::time::Duration;
+    /// # async fn func(users: Users) -> Result<(), Error> {
+    /// let scopes = Scopes::from_strs(&["reports:read"]);
+    /// let ninety_days = Duration::from_secs(90 * 24 * 60 * 60);
+    /// let (key, _) = users.create_api_key(4, "backup script", scopes, Some(ninety_days)).await?;
+    /// println!("your new API key is {}", key);
+    /// # Ok(()) }
+    /// ```
+    pub async fn create_api_key(
+        &self,
+        user_id: i32,
+        name: &str,
+        scopes: Scopes,
+        lifetime: Option<Duration>,
+    ) -> Result<(String, ApiKey)> {
+        scopes.validate()?;
+        self.conn.get_user_by_id(user_id).await?;
+        let secret = generate_token();
+        let created = now();
+        let key = ApiKey {
+            id: generate_token(),
+            user_id,
+            name: name.into(),
+            hash: hash_token(self.token_key()?, &secret),
+            scopes,
+            created,
+            expires: lifetime.map(|time| created + time.as_secs() as i64),
+            last_used: None,
+        };
+        self.conn.create_api_key(&key).await?;
+        Ok((format!("{}.{}", key.id, secret), key))
+    }
+
+    /// Lists the API keys of a user, including the ones that expired.
+    /// ```rust
+    /// # use rocket::{get, State};
+    /// # use rocket_auth2::{Users, Error};
+    /// #[get("/api-keys-of/<id>")]
+    /// async fn api_keys_of(id: i32, users: &State<Users>) -> Result<String, Error> {
+    ///     let keys = users.api_keys(id).await?;
+    ///     Ok(format!("{:?}", keys))
+    /// }
+    /// ```
+    pub async fn api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>> {
+        self.conn.get_api_keys(user_id).await
+    }
+
+    /// Revokes an API key of a user. Keys that belong to a different user are left untouched.
+    /// ```rust
+    /// # use rocket_auth2::{Users, Error};
+    /// # async fn func(users: Users) -> Result<(), Error> {
+    /// for key in users.api_keys(4).await? {
+    ///     if key.is_expired() {
+    ///         users.revoke_api_key(4, &key.id).await?;
+    ///     }
+    /// }
+    /// # Ok(()) }
+    /// ```
+    pub async fn revoke_api_key(&self, user_id: i32, key_id: &str) -> Result<()> {
+        match self.conn.get_api_key(key_id).await {
+            Ok(key) if key.user_id == user_id => self.conn.delete_api_key(key_id).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// The user of a valid API key, whose last use is recorded.
+    pub(crate) async fn api_key_user(&self, key: &str) -> Option<ApiKeyUser> {
+        let (key_id, secret) = key.split_once('.')?;
+        let mut key = self.conn.get_api_key(key_id).await.ok()?;
+        let hash = hash_token(self.token_key().ok()?, secret);
+        if key.is_expired() || !bool::from(hash.as_bytes().ct_eq(key.hash.as_bytes())) {
+            return None;
+        }
+        let user = self.conn.get_user_by_id(key.user_id).await.ok()?;
+        let refresh_interval = self.policy.refresh_interval.as_secs() as i64;
+        let stale = match key.last_used {
+            Some(last_used) => now() - last_used >= refresh_interval,
+            None => true,
+        };
+        if stale {
+            key.last_used = Some(now());
+            if let Err(error) = self.conn.touch_api_key(key_id, now()).await {
+                log::warn!("failed to record the use of an API key: {}", error);
+            }
+        }
+        Some(ApiKeyUser { user, key })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scopes_round_trip_through_text() {
+        let scopes = Scopes::from_strs(&["write", "read"]);
+        assert_eq!(scopes.to_string(), "read write");
+        assert_eq!(Scopes::from(scopes.to_string()), scopes);
+        assert!(scopes.validate().is_ok());
+        assert!(Scopes::from_strs(&["two words"]).validate().is_err());
+        assert!(Scopes::from_strs(&[""]).validate().is_err());
+    }
+
+    #[cfg(feature = "sqlx-sqlite")]
+    #[tokio::test]
+    async fn test_api_keys_authenticate_until_revoked() {
+        let users = crate::user::test_users("script@example.com").await;
+        let user = users.get_by_email("script@example.com").await.unwrap();
+        let scopes = Scopes::from_strs(&["read"]);
+        let (key, info) = users
+            .create_api_key(user.id, "backup", scopes.clone(), None)
+            .await
+            .unwrap();
+
+        let authenticated = users.api_key_user(&key).await.unwrap();
+        assert_eq!(authenticated.id, user.id);
+        assert!(authenticated.has_scope("read"));
+        assert!(!authenticated.has_scope("write"));
+        let listed = users.api_keys(user.id).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].scopes, scopes);
+        assert!(listed[0].last_used.is_some());
+
+        assert!(users
+            .api_key_user(&format!("{}.{}", info.id, generate_token()))
+            .await
+            .is_none());
+        users.revoke_api_key(user.id + 1, &info.id).await.unwrap();
+        assert!(users.api_key_user(&key).await.is_some());
+        users.revoke_api_key(user.id, &info.id).await.unwrap();
+        assert!(users.api_key_user(&key).await.is_none());
+
+        let (expired, _) = users
+            .create_api_key(user.id, "old", Scopes::new(), Some(Duration::ZERO))
+            .await
+            .unwrap();
+        assert!(users.api_key_user(&expired).await.is_none());
+    }
+}
@@ -1,22 +1,38 @@
+use crate::cookies::{bearer_token, get_session};
+use crate::csrf::csrf_token;
 use crate::prelude::*;
+use crate::session::ClientInfo;
+use crate::user::totp::TotpEnrollment;
 use regex::Regex;
+use rocket::http::CookieJar;
 use rocket::http::Status;
-use rocket::http::{Cookie, CookieJar};
 use rocket::request::FromRequest;
 use rocket::request::Outcome;
 use rocket::Request;
 use rocket::State;
-use serde_json::json;
+use std::sync::OnceLock;
 use std::time::Duration;
 
+/// How long a client has to enter the code of a login waiting for its second factor.
+const SECOND_FACTOR_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// A login whose password was accepted, waiting for a TOTP code. It is kept in a private cookie.
+#[derive(Clone, Serialize, Deserialize)]
+struct PendingLogin {
+    user_id: i32,
+    expires: i64,
+    /// The lifetime in seconds of the session to open, if it was given to [`Auth::login_for`].
+    time: Option<u64>,
+}
+
 /// Validates an email address (helper function).
-pub fn validate_email(email: &String) -> bool {
+pub fn validate_email(email: &str) -> bool {
     let expr = Regex::new("^[\\w\\.-]+@[\\w\\.-]+\\.[a-zA-Z]{2,6}$");
 
     if let Ok(reg_ex) = expr {
-        return reg_ex.is_match(&email);
+        reg_ex.is_match(email)
     } else {
-        return false;
+        false
     }
 }
 
@@ -40,8 +56,8 @@ pub fn validate_email(email: &String) -> bool {
 /// }
 ///
 /// #[post("/logout")]
-/// fn logout(auth: Auth) {
-///     auth.logout();
+/// async fn logout(auth: Auth<'_>) {
+///     auth.logout().await;
 /// }
 /// #[tokio::main]
 /// async fn main() -> Result<(), Error>{
@@ -61,28 +77,75 @@ pub struct Auth<'a> {
     pub users: &'a State<Users>,
     pub cookies: &'a CookieJar<'a>,
     pub session: Option<Session>,
+    client: ClientInfo,
+    /// The session that replaced [`Auth::session`] during this request, after logging in or rotating it.
+    reissued: OnceLock<Session>,
+    /// The login that started waiting for its second factor during this request, whose cookie can not be read yet.
+    awaiting: OnceLock<PendingLogin>,
+}
+
+/// The session of the current request, after its activity was recorded.
+/// It is cached, since guards such as `User` and `Auth` may be used in the same request,
+/// and a rotated session must only be rotated once.
+struct RequestSession(Option<Session>);
+
+async fn request_session(req: &Request<'_>, users: &Users, client: &ClientInfo) -> Option<Session> {
+    let Some(session) = get_session(req.cookies(), &users.cookie.name) else {
+        // a bearer token can not be replaced behind the back of the client, so it is never rotated.
+        let session = users.token_session(bearer_token(req)?).await?;
+        match users.record_activity(&session, client, false).await {
+            Err(Error::ClientMismatchError) => return None,
+            Err(error) => log::error!("failed to record the activity of a session: {}", error),
+            Ok(_) => (),
+        }
+        return Some(session);
+    };
+    match users.record_activity(&session, client, true).await {
+        Ok(Some(rotated)) => {
+            req.cookies()
+                .add_private(users.session_cookie(&rotated).await);
+            Some(rotated)
+        }
+        Ok(None) => Some(session),
+        Err(Error::ClientMismatchError) => {
+            req.cookies().remove_private(users.cookie.removal_cookie());
+            None
+        }
+        Err(error) => {
+            log::error!("failed to record the activity of a session: {}", error);
+            Some(session)
+        }
+    }
 }
 
 #[async_trait]
 impl<'r> FromRequest<'r> for Auth<'r> {
     type Error = Error;
     async fn from_request(req: &'r Request<'_>) -> Outcome<Auth<'r>, Error> {
-        let session: Option<Session> = if let Outcome::Success(users) = req.guard().await {
-            Some(users)
-        } else {
-            None
-        };
-
         let users: &State<Users> = if let Outcome::Success(users) = req.guard().await {
             users
         } else {
             return Outcome::Error((Status::InternalServerError, Error::UnmanagedStateError));
         };
 
+        let client = ClientInfo {
+            ip: req.client_ip().map(|ip| ip.to_string()),
+            user_agent: req.headers().get_one("User-Agent").map(String::from),
+        };
+
+        let session = req
+            .local_cache_async(async { RequestSession(request_session(req, users, &client).await) })
+            .await
+            .0
+            .clone();
+
         Outcome::Success(Auth {
             users,
             session,
             cookies: req.cookies(),
+            client,
+            reissued: OnceLock::new(),
+            awaiting: OnceLock::new(),
         })
     }
 }
@@ -90,7 +153,11 @@ impl<'r> FromRequest<'r> for Auth<'r> {
 impl<'a> Auth<'a> {
     /// Logs in the user through a parsed form or json.
     /// The session is set to expire in one year by default.
+    /// A session the client held before logging in is ended, so that it can not be fixed by an attacker.
     /// For a custom expiration date use [`Auth::login_for`].
+    ///
+    /// If the user has two-factor authentication enabled, no session is opened yet: it fails with
+    /// [`Error::SecondFactorRequiredError`], and the login waits for a code passed to [`Auth::verify_totp`].
     /// ```rust
     /// # use rocket::{get, post, form::Form};
     /// # use rocket_auth2::{Auth, Login};
@@ -100,17 +167,11 @@ impl<'a> Auth<'a> {
     /// }
     /// ```
     pub async fn login(&self, form: &Login) -> Result<()> {
-        let key = self.users.login(form).await?;
-        let user = self.users.get_by_email(&form.email.to_lowercase()).await?;
-        let session = Session {
-            id: user.id,
-            email: user.email,
-            auth_key: key,
-            time_stamp: now(),
-        };
-        let to_str = format!("{}", json!(session));
-        self.cookies.add_private(Cookie::new("rocket_auth", to_str));
-        Ok(())
+        self.check_csrf(form.csrf_token.as_deref())?;
+        match self.users.login(form, &self.client).await {
+            Err(Error::SecondFactorRequiredError) => self.await_second_factor(form, None).await,
+            session => self.reissue(session?).await,
+        }
     }
 
     /// Logs a user in for the specified period of time.
@@ -125,23 +186,147 @@ impl<'a> Auth<'a> {
     /// }
     /// ```
     pub async fn login_for(&self, form: &Login, time: Duration) -> Result<()> {
-        let key = self.users.login_for(form, time).await?;
-        let user = self.users.get_by_email(&form.email.to_lowercase()).await?;
-
-        let session = Session {
-            id: user.id,
-            email: user.email,
-            auth_key: key,
-            time_stamp: now(),
+        self.check_csrf(form.csrf_token.as_deref())?;
+        match self.users.login_for(form, time, &self.client).await {
+            Err(Error::SecondFactorRequiredError) => {
+                self.await_second_factor(form, Some(time)).await
+            }
+            session => self.reissue(session?).await,
+        }
+    }
+
+    /// Keeps a login whose password was accepted until its code is checked by [`Auth::verify_totp`].
+    async fn await_second_factor(&self, form: &Login, time: Option<Duration>) -> Result<()> {
+        let user = self
+            .users
+            .conn
+            .get_user_by_email(&form.email.to_lowercase())
+            .await?;
+        let login = PendingLogin {
+            user_id: user.id,
+            expires: now() + SECOND_FACTOR_TIMEOUT.as_secs() as i64,
+            time: time.map(|time| time.as_secs()),
         };
-        let to_str = format!("{}", json!(session));
-        let cookie = Cookie::new("rocket_auth", to_str);
-        self.cookies.add_private(cookie);
-        Ok(())
+        self.cookies.add_private(
+            self.users
+                .cookie
+                .second_factor_cookie(serde_json::to_string(&login)?),
+        );
+        let _ = self.awaiting.set(login);
+        Err(Error::SecondFactorRequiredError)
+    }
+
+    /// Completes a login waiting for its second factor, given a code from the authenticator app of the user,
+    /// and opens the session. The code has to be entered within five minutes of the password, and a code is only
+    /// accepted once. It fails with [`Error::InvalidTotpCodeError`] if the code is wrong, in which case another one
+    /// can be tried, and with [`Error::UnauthenticatedError`] if no login is waiting. After five wrong codes in a row,
+    /// it fails with [`Error::TotpLockedError`] and the login is abandoned.
+    /// As with [`Auth::compare_password`], routes using it should be rate limited.
+    /// ```rust
+    /// # use rocket::{post, form::Form};
+    /// # use rocket_auth2::{Auth, Error, Login};
+    /// #[post("/login", data = "<form>")]
+    /// async fn login(form: Form<Login>, auth: Auth<'_>) -> Result<&'static str, Error> {
+    ///     match auth.login(&form).await {
+    ///         Err(Error::SecondFactorRequiredError) => Ok("Enter the code of your authenticator app."),
+    ///         result => result.map(|_| "Logged in."),
+    ///     }
+    /// }
+    ///
+    /// #[post("/login/code", data = "<code>")]
+    /// async fn code(code: Form<String>, auth: Auth<'_>) -> Result<&'static str, Error> {
+    ///     auth.verify_totp(&code).await?;
+    ///     Ok("Logged in.")
+    /// }
+    /// ```
+    pub async fn verify_totp(&self, code: &str) -> Result<()> {
+        let login = self.pending_login().ok_or(Error::UnauthenticatedError)?;
+        let checked = self.users.check_totp(login.user_id, code).await;
+        if matches!(checked, Ok(()) | Err(Error::TotpLockedError)) {
+            self.cookies
+                .remove_private(self.users.cookie.second_factor_removal_cookie());
+        }
+        checked?;
+        let user = self.users.get_by_id(login.user_id).await?;
+        let session = match login.time {
+            Some(time) => {
+                let time = Duration::from_secs(time);
+                self.users
+                    .set_auth_key_for(&user, time, &self.client)
+                    .await?
+            }
+            None => self.users.set_auth_key(&user, &self.client).await?,
+        };
+        self.reissue(session).await
+    }
+
+    /// Whether the client entered the password of a user with two-factor authentication enabled,
+    /// and the login is waiting for a code passed to [`Auth::verify_totp`].
+    pub fn is_awaiting_second_factor(&self) -> bool {
+        self.pending_login().is_some()
+    }
+
+    /// No login is waiting once a session was opened during this request.
+    fn pending_login(&self) -> Option<PendingLogin> {
+        if self.reissued.get().is_some() {
+            return None;
+        }
+        if let Some(login) = self.awaiting.get() {
+            return Some(login.clone());
+        }
+        let cookie = self
+            .cookies
+            .get_private(&self.users.cookie.second_factor_name())?;
+        let login: PendingLogin = serde_json::from_str(cookie.value()).ok()?;
+        (login.expires > now()).then_some(login)
+    }
+
+    /// Logs in the user and returns a token, rather than setting a cookie. It is meant for clients that can not hold
+    /// private cookies, which authenticate by sending it in an `Authorization: Bearer <token>` header.
+    /// The token is a session like any other, which expires with the [`SessionPolicy`] and can be revoked.
+    /// Unlike a cookie, it is not replaced when the roles of the user change, and it ends when the password changes.
+    /// ```rust
+    /// # use rocket::{post, serde::json::Json};
+    /// # use rocket_auth2::{Auth, Error, Login};
+    /// #[post("/api/token", data="<form>")]
+    /// async fn token(form: Json<Login>, auth: Auth<'_>) -> Result<String, Error> {
+    ///     auth.issue_token(&form).await
+    /// }
+    /// ```
+    pub async fn issue_token(&self, form: &Login) -> Result<String> {
+        self.users.issue_token(form, &self.client).await
+    }
+
+    /// Logs in the user and returns a short-lived JSON web access token, along with a refresh token.
+    /// The access token is accepted by the `User` and `AdminUser` guards in an `Authorization: Bearer <token>` header,
+    /// and the refresh token is exchanged for new tokens with [`Users::refresh_jwt`].
+    /// It fails unless the tokens were configured with [`Users::set_jwt_config`].
+    /// ```rust
+    /// # use rocket::{post, serde::json::Json};
+    /// # use rocket_auth2::{Auth, Error, Login, TokenPair};
+    /// #[post("/api/login", data="<form>")]
+    /// async fn login(form: Json<Login>, auth: Auth<'_>) -> Result<Json<TokenPair>, Error> {
+    ///     Ok(Json(auth.issue_jwt(&form).await?))
+    /// }
+    /// ```
+    #[cfg(feature = "jwt")]
+    pub async fn issue_jwt(&self, form: &Login) -> Result<crate::TokenPair> {
+        let user = self.users.check_credentials(form).await?;
+        self.users.issue_jwt(&user, &self.client).await
+    }
+
+    /// Rejects a form without the CSRF token of the client, if [`CookieConfig::require_csrf`] is set.
+    fn check_csrf(&self, token: Option<&str>) -> Result<()> {
+        if !self.users.cookie.require_csrf {
+            return Ok(());
+        }
+        let expected = csrf_token(self.users, self.cookies, false).ok_or(Error::CsrfError)?;
+        expected.verify(token.ok_or(Error::CsrfError)?)
     }
 
     /// Creates a new user from a form or a json. The user will not be authenticated by default.
     /// In order to authenticate the user, cast the signup form to a login form or use `signup_for`.
+    /// The email of the new user is not verified, see [`Users::send_verification`].
     /// ```rust
     /// # use rocket::{post, form::Form};
     /// # use rocket_auth2::{Auth, Signup, Error};
@@ -154,6 +339,7 @@ impl<'a> Auth<'a> {
     /// }
     /// ```
     pub async fn signup(&self, form: &Signup) -> Result<()> {
+        self.check_csrf(form.csrf_token.as_deref())?;
         self.users.signup(form).await
     }
 
@@ -170,7 +356,7 @@ impl<'a> Auth<'a> {
     /// }
     /// ```
     pub async fn signup_for(&self, form: &Signup, time: Duration) -> Result<()> {
-        self.users.signup(form).await?;
+        self.signup(form).await?;
         self.login_for(&form.clone().into(), time).await?;
         Ok(())
     }
@@ -182,8 +368,8 @@ impl<'a> Auth<'a> {
     /// # use rocket::{get};
     /// # use rocket_auth2::{Auth};
     /// #[get("/am-I-authenticated")]
-    /// fn is_auth(auth: Auth<'_>) -> &'static str {
-    ///     if auth.is_auth() {
+    /// async fn is_auth(auth: Auth<'_>) -> &'static str {
+    ///     if auth.is_auth().await {
     ///         "Yes you are."
     ///     } else {
     ///         "nope."
@@ -191,9 +377,9 @@ impl<'a> Auth<'a> {
     /// }
     /// # fn main() {}
     /// ```
-    pub fn is_auth(&self) -> bool {
-        if let Some(session) = &self.session {
-            self.users.is_auth(session)
+    pub async fn is_auth(&self) -> bool {
+        if let Some(session) = self.current_session() {
+            self.users.is_auth(session).await
         } else {
             false
         }
@@ -209,30 +395,29 @@ impl<'a> Auth<'a> {
     /// }
     /// ```
     pub async fn get_user(&self) -> Option<User> {
-        if !self.is_auth() {
+        if !self.is_auth().await {
             return None;
         }
-        let id = self.session.as_ref()?.id;
-        if let Ok(user) = self.users.get_by_id(id).await {
-            Some(user)
-        } else {
-            None
-        }
+        let id = self.current_session()?.id;
+        self.users.get_by_id(id).await.ok()
     }
 
     /// Logs the currently authenticated user out.
+    /// Only the current session is ended, other devices of the same user stay logged in.
+    /// A stateless session can not be ended on its own, so only its cookie is removed.
     /// ```rust
     /// # use rocket::post;
     /// # use rocket_auth2::Auth;
     /// #[post("/logout")]
-    /// fn logout(auth: Auth)  {
-    ///     auth.logout();
+    /// async fn logout(auth: Auth<'_>)  {
+    ///     auth.logout().await;
     /// }
     /// ```
-    pub fn logout(&self) -> Result<()> {
+    pub async fn logout(&self) -> Result<()> {
         let session = self.get_session()?;
-        self.users.logout(session)?;
-        self.cookies.remove_private(Cookie::build("rocket_auth"));
+        self.users.logout(session).await?;
+        self.cookies
+            .remove_private(self.users.cookie.removal_cookie());
         Ok(())
     }
     /// Deletes the account of the currently authenticated user.
@@ -245,17 +430,20 @@ impl<'a> Auth<'a> {
     /// }
     /// ```
     pub async fn delete(&self) -> Result<()> {
-        if self.is_auth() {
+        if self.is_auth().await {
             let session = self.get_session()?;
             self.users.delete(session.id).await?;
-            self.cookies.remove_private("rocket_auth");
+            self.cookies
+                .remove_private(self.users.cookie.removal_cookie());
             Ok(())
         } else {
             Err(Error::UnauthenticatedError)
         }
     }
 
-    /// Changes the password of the currently authenticated user
+    /// Changes the password of the currently authenticated user.
+    /// The current session gets a new token, and if [`SessionPolicy::revoke_on_password_change`] is set,
+    /// every other session of the user is ended.
     /// ```
     /// # use rocket_auth2::Auth;
     /// # use rocket::post;
@@ -265,37 +453,156 @@ impl<'a> Auth<'a> {
     /// # }
     /// ```
     pub async fn change_password(&self, password: &str) -> Result<(), Box<dyn std::error::Error>> {
-        if self.is_auth() {
+        if self.is_auth().await {
             let session = self.get_session()?;
             let mut user = self.users.get_by_id(session.id).await?;
             user.set_password(password)?;
             self.users.modify(&user).await?;
 
+            let revoke = self.users.policy.revoke_on_password_change;
+            if revoke && session.version.is_some() {
+                self.users.revoke_all_sessions(user.id).await?;
+            }
+            let rotated = self.users.rotate_session(session).await?;
+            if revoke {
+                self.users
+                    .revoke_other_sessions(user.id, &rotated.session_id)
+                    .await?;
+            }
+            self.reissue(rotated).await?;
             Ok(())
         } else {
             Err(Box::new(Error::UnauthorizedError))
         }
     }
 
-    /// Changes the email of the currently authenticated user
+    /// Starts changing the email of the currently authenticated user, see [`Users::request_email_change`].
+    /// The address is only changed once the returned token is confirmed with [`Users::confirm_email_change`],
+    /// which the token sent to the new address by the mailer allows.
     /// ```
-    /// # use rocket_auth2::Auth;
-    /// # fn func(auth: Auth) {
-    /// auth.change_email("new@email.com".into());
-    /// # }
+    /// # use rocket::post;
+    /// # use rocket_auth2::{Auth, Error};
+    /// #[post("/change-email/<email>")]
+    /// async fn change_email(email: String, auth: Auth<'_>) -> Result<&'static str, Error> {
+    ///     auth.change_email(email).await?;
+    ///     Ok("Check your inbox to confirm your new email address.")
+    /// }
     /// ```
-    pub async fn change_email(&self, email: String) -> Result<(), Error> {
-        if self.is_auth() {
-            if !validate_email(&email) {
-                return Err(Error::InvalidEmailAddressError);
-            }
+    pub async fn change_email(&self, email: String) -> Result<String, Error> {
+        if self.is_auth().await {
             let session = self.get_session()?;
-            let mut user = self.users.get_by_id(session.id).await?;
-            user.email = email.to_lowercase();
-            self.users.modify(&user).await?;
-            return Ok(());
+            self.users.request_email_change(session.id, &email).await
+        } else {
+            Err(Error::UnauthorizedError)
+        }
+    }
+
+    /// Starts enabling two-factor authentication for the currently authenticated user, see [`Users::enroll_totp`].
+    /// It is enabled once a first code is passed to [`Auth::confirm_totp`].
+    /// ```rust
+    /// # use rocket::post;
+    /// # use rocket_auth2::{Auth, Error};
+    /// #[post("/2fa/enroll")]
+    /// async fn enroll(auth: Auth<'_>) -> Result<String, Error> {
+    ///     let enrollment = auth.enroll_totp("Example").await?;
+    ///     Ok(format!("Scan {} with your authenticator app.", enrollment.uri))
+    /// }
+    /// ```
+    pub async fn enroll_totp(&self, issuer: &str) -> Result<TotpEnrollment> {
+        if self.is_auth().await {
+            let session = self.get_session()?;
+            self.users.enroll_totp(session.id, issuer).await
+        } else {
+            Err(Error::UnauthenticatedError)
+        }
+    }
+
+    /// Enables two-factor authentication for the currently authenticated user, given a first code, see [`Users::confirm_totp`].
+    pub async fn confirm_totp(&self, code: &str) -> Result<()> {
+        if self.is_auth().await {
+            let session = self.get_session()?;
+            self.users.confirm_totp(session.id, code).await
+        } else {
+            Err(Error::UnauthenticatedError)
+        }
+    }
+
+    /// Disables two-factor authentication for the currently authenticated user, given a current code.
+    pub async fn disable_totp(&self, code: &str) -> Result<()> {
+        if self.is_auth().await {
+            let session = self.get_session()?;
+            self.users.check_totp(session.id, code).await?;
+            self.users.disable_totp(session.id).await
+        } else {
+            Err(Error::UnauthenticatedError)
+        }
+    }
+
+    /// Lists the active sessions of the currently authenticated user.
+    /// ```rust
+    /// # use rocket::get;
+    /// # use rocket_auth2::{Auth, Error};
+    /// #[get("/where-am-i-logged-in")]
+    /// async fn sessions(auth: Auth<'_>) -> Result<String, Error> {
+    ///     let current = &auth.get_session()?.session_id;
+    ///     let mut page = String::new();
+    ///     for session in auth.sessions().await? {
+    ///         let marker = if &session.session_id == current { "*" } else { " " };
+    ///         page += &format!("{} {:?} {:?}\n", marker, session.ip, session.user_agent);
+    ///     }
+    ///     Ok(page)
+    /// }
+    /// ```
+    pub async fn sessions(&self) -> Result<Vec<SessionInfo>> {
+        if self.is_auth().await {
+            let session = self.get_session()?;
+            self.users.sessions(session.id).await
+        } else {
+            Err(Error::UnauthenticatedError)
+        }
+    }
+
+    /// Ends one of the sessions of the currently authenticated user, for instance one opened on a lost phone.
+    /// ```rust
+    /// # use rocket::post;
+    /// # use rocket_auth2::{Auth, Error};
+    /// #[post("/sessions/<id>/revoke")]
+    /// async fn revoke(id: &str, auth: Auth<'_>) -> Result<(), Error> {
+    ///     auth.revoke_session(id).await
+    /// }
+    /// ```
+    pub async fn revoke_session(&self, session_id: &str) -> Result<()> {
+        if self.is_auth().await {
+            let session = self.get_session()?;
+            self.users.revoke_session(session.id, session_id).await
+        } else {
+            Err(Error::UnauthenticatedError)
+        }
+    }
+
+    /// Ends every session of the currently authenticated user, except the current one.
+    /// In stateless mode, the current session is reissued under a new session version, which ends all the others.
+    /// ```rust
+    /// # use rocket::post;
+    /// # use rocket_auth2::{Auth, Error};
+    /// #[post("/sessions/revoke-others")]
+    /// async fn revoke_others(auth: Auth<'_>) -> Result<(), Error> {
+    ///     auth.revoke_other_sessions().await
+    /// }
+    /// ```
+    pub async fn revoke_other_sessions(&self) -> Result<()> {
+        if self.is_auth().await {
+            let session = self.get_session()?;
+            if session.version.is_some() {
+                self.users.revoke_all_sessions(session.id).await?;
+                let rotated = self.users.rotate_session(session).await?;
+                return self.reissue(rotated).await;
+            }
+            self.users
+                .revoke_other_sessions(session.id, &session.session_id)
+                .await
         } else {
-            return Err(Error::UnauthorizedError);
+            Err(Error::UnauthenticatedError)
         }
     }
 
@@ -309,16 +616,35 @@ impl<'a> Auth<'a> {
     /// # }
     /// ```
     pub fn get_session(&self) -> Result<&Session> {
-        let session = self.session.as_ref().ok_or(Error::UnauthenticatedError)?;
+        let session = self.current_session().ok_or(Error::UnauthenticatedError)?;
         Ok(session)
     }
 
+    fn current_session(&self) -> Option<&Session> {
+        self.reissued.get().or(self.session.as_ref())
+    }
+
+    /// Sends a new session to the client, ending the one it held before.
+    async fn reissue(&self, session: Session) -> Result<()> {
+        if let Some(old_session) = self.current_session() {
+            if old_session.session_id != session.session_id {
+                self.users.logout(old_session).await?;
+            }
+        }
+        self.cookies
+            .add_private(self.users.session_cookie(&session).await);
+        if self.reissued.set(session).is_err() {
+            log::warn!("a session was reissued twice in one request, the first one is kept");
+        }
+        Ok(())
+    }
+
     /// Compares the password of the currently authenticated user with another password.
     /// Useful for checking password before resetting email/password.
     /// To avoid bruteforcing this function should not be directly accessible from a route.
     /// Additionally, it is good to implement rate limiting on routes using this function.
     pub async fn compare_password(&self, password: &str) -> Result<bool> {
-        if self.is_auth() {
+        if self.is_auth().await {
             let session = self.get_session()?;
             let user: User = self.users.get_by_id(session.id).await?;
             Ok(user.compare_password(password)?)
@@ -0,0 +1,137 @@
+use crate::error;
+use crate::prelude::*;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::{self, Responder};
+use rocket::{catch, Response};
+
+/// The realm announced in the `WWW-Authenticate` challenge.
+const REALM: &str = "rocket_auth";
+
+/// Marks a request whose [`BasicAuthUser`] guard failed, so that [`basic_auth_challenge`]
+/// only challenges the clients that were asked for Basic credentials.
+struct BasicAuthFailed;
+
+/// The `BasicAuthUser` guard authenticates a request through an `Authorization: Basic` header,
+/// which is meant for scripts and internal tools. The credentials are checked like those of
+/// [`Auth::login`](crate::Auth::login), but no session is created.
+///
+/// When the credentials are missing or wrong the request fails with `401 Unauthorized`.
+/// Register the [`basic_auth_challenge`] catcher, so that the response carries a `WWW-Authenticate` challenge.
+/// ```rust
+/// # use rocket::{get, catchers};
+/// # use rocket_auth2::{BasicAuthUser, basic_auth_challenge};
+/// #[get("/ci/artifacts")]
+/// fn artifacts(user: BasicAuthUser) -> String {
+///     format!("the artifacts of {}", user.email())
+/// }
+///
+/// # fn app() -> rocket::Rocket<rocket::Build> {
+/// rocket::build().register("/", catchers![basic_auth_challenge])
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicAuthUser(User);
+
+impl BasicAuthUser {
+    pub fn into_inner(self) -> User {
+        self.0
+    }
+}
+
+impl Deref for BasicAuthUser {
+    type Target = User;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BasicAuthUser {
+    type Error = Error;
+    async fn from_request(request: &'r Request<'_>) -> Outcome<BasicAuthUser, Error> {
+        let Some(users) = request.rocket().state::<Users>() else {
+            return Outcome::Error((Status::InternalServerError, Error::UnmanagedStateError));
+        };
+        let credentials = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(basic_credentials);
+        if let Some(form) = credentials {
+            if let Ok(user) = users.check_credentials(&form).await {
+                return Outcome::Success(BasicAuthUser(user));
+            }
+        }
+        request.local_cache(|| Some(BasicAuthFailed));
+        Outcome::Error((Status::Unauthorized, error::Error::UnauthorizedError))
+    }
+}
+
+/// The credentials of an `Authorization: Basic <base64 of email:password>` header.
+fn basic_credentials(header: &str) -> Option<Login> {
+    let (scheme, encoded) = header.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = STANDARD.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (email, password) = decoded.split_once(':')?;
+    Some(Login {
+        email: email.into(),
+        password: password.into(),
+        csrf_token: None,
+    })
+}
+
+/// The response of [`basic_auth_challenge`].
+pub struct BasicAuthChallenge {
+    challenge: bool,
+}
+
+impl<'r> Responder<'r, 'static> for BasicAuthChallenge {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build_from(Error::UnauthorizedError.respond_to(request)?)
+            .status(Status::Unauthorized)
+            .finalize();
+        if self.challenge {
+            response.set_raw_header(
+                "WWW-Authenticate",
+                format!("Basic realm=\"{}\", charset=\"UTF-8\"", REALM),
+            );
+        }
+        Ok(response)
+    }
+}
+
+/// A catcher for `401 Unauthorized` that adds a `WWW-Authenticate: Basic` challenge
+/// to the requests rejected by the [`BasicAuthUser`] guard.
+/// Other unauthorized requests get the same response without the challenge,
+/// so browsers don't prompt for credentials on pages that use sessions.
+#[catch(401)]
+pub fn basic_auth_challenge(request: &Request<'_>) -> BasicAuthChallenge {
+    BasicAuthChallenge {
+        challenge: request.local_cache(|| None::<BasicAuthFailed>).is_some(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_basic_credentials_are_decoded() {
+        let header = format!("Basic {}", STANDARD.encode("ci@example.com:pass:word"));
+        let form = basic_credentials(&header).unwrap();
+        assert_eq!(form.email, "ci@example.com");
+        assert_eq!(form.password, "pass:word");
+
+        let header = format!("basic {}", STANDARD.encode("ci@example.com:secret"));
+        assert!(basic_credentials(&header).is_some());
+        assert!(basic_credentials("Bearer token").is_none());
+        assert!(basic_credentials("Basic not-base64!").is_none());
+        let header = format!("Basic {}", STANDARD.encode("no colon"));
+        assert!(basic_credentials(&header).is_none());
+    }
+}
@@ -0,0 +1,164 @@
+use super::auth::validate_email;
+use super::generate_token;
+use crate::prelude::*;
+use crate::session::hash_token;
+use subtle::ConstantTimeEq;
+
+/// How long the token that confirms an email change is valid.
+const EMAIL_CHANGE_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A pending change of the email of a user, applied once the new address is confirmed.
+/// Only a keyed hash of its token is stored, and a user has at most one pending change.
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmailChange {
+    pub(crate) id: String,
+    pub(crate) user_id: i32,
+    /// The new address.
+    pub(crate) email: String,
+    pub(crate) hash: String,
+    pub(crate) expires: i64,
+}
+
+impl Users {
+    /// Starts changing the email of a user. The address is only changed once the token returned here is passed to
+    /// [`Users::confirm_email_change`], which proves the user owns the new address. The token is valid for one day,
+    /// and requesting another change invalidates it.
+    ///
+    /// If a mailer is set with [`Users::set_mailer`], the token is sent to the new address with the `email_change`
+    /// template, and the current address is told about the change with the `email_change_notice` template.
+    /// It fails with [`Error::EmailAlreadyExists`] if the address belongs to a user, this one included.
+    /// ```rust
+    /// # use rocket_auth2::{Users, Error};
+    /// # async fn func(users: Users) -> Result<(), Error> {
+    /// let token = users.request_email_change(4, "new@example.com").await?;
+    /// println!("visit /confirm-email/{} to confirm new@example.com", token);
+    /// # Ok(()) }
+    /// ```
+    pub async fn request_email_change(&self, user_id: i32, email: &str) -> Result<String> {
+        let email = email.to_lowercase();
+        if !validate_email(&email) {
+            return Err(Error::InvalidEmailAddressError);
+        }
+        let user = self.conn.get_user_by_id(user_id).await?;
+        if self.conn.get_user_by_email(&email).await.is_ok() {
+            return Err(Error::EmailAlreadyExists);
+        }
+        let secret = generate_token();
+        let change = EmailChange {
+            id: generate_token(),
+            user_id,
+            email,
+            hash: hash_token(self.token_key()?, &secret),
+            expires: now() + EMAIL_CHANGE_LIFETIME.as_secs() as i64,
+        };
+        self.conn.create_email_change(&change).await?;
+        let token = format!("{}.{}", change.id, secret);
+
+        let vars = [("email", change.email.as_str()), ("token", &token)];
+        self.send_email(|t| &t.email_change, &change.email, &vars)
+            .await?;
+        let vars = [("email", user.email.as_str()), ("new_email", &change.email)];
+        self.send_email(|t| &t.email_change_notice, &user.email, &vars)
+            .await?;
+        Ok(token)
+    }
+
+    /// Applies the email change started by [`Users::request_email_change`], given its token, and returns the user.
+    /// The new address counts as verified. It fails with [`Error::InvalidTokenError`] if the token is malformed,
+    /// expired, or was already used, and with [`Error::EmailAlreadyExists`] if the address was taken in the meantime.
+    /// ```rust
+    /// # use rocket::{get, State};
+    /// # use rocket_auth2::{Error, Users};
+    /// #[get("/confirm-email/<token>")]
+    /// async fn confirm_email(token: &str, users: &State<Users>) -> Result<String, Error> {
+    ///     let user = users.confirm_email_change(token).await?;
+    ///     Ok(format!("Your email is now {}.", user.email()))
+    /// }
+    /// ```
+    pub async fn confirm_email_change(&self, token: &str) -> Result<User> {
+        let (change_id, secret) = token.split_once('.').ok_or(Error::InvalidTokenError)?;
+        let change = self
+            .conn
+            .get_email_change(change_id)
+            .await
+            .map_err(|_| Error::InvalidTokenError)?;
+        let hash = hash_token(self.token_key()?, secret);
+        if !bool::from(hash.as_bytes().ct_eq(change.hash.as_bytes())) {
+            return Err(Error::InvalidTokenError);
+        }
+        // the change is consumed before it is applied, so that of several concurrent confirmations only one applies it.
+        if !self.conn.consume_email_change(change_id).await? || change.expires <= now() {
+            return Err(Error::InvalidTokenError);
+        }
+        let mut user = self.conn.get_user_by_id(change.user_id).await?;
+        user.email = change.email;
+        user.verified = true;
+        self.conn.update_user(&user).await?;
+        Ok(user)
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-sqlite"))]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_email_changes_apply_once_confirmed() {
+        let mut users = crate::user::test_users("old@example.com").await;
+        let mailer = crate::MemoryMailer::new();
+        users.set_mailer(mailer.clone());
+        users
+            .create_user("taken@example.com", "Password123", &Default::default())
+            .await
+            .unwrap();
+        let user = users.get_by_email("old@example.com").await.unwrap();
+        assert!(matches!(
+            users
+                .request_email_change(user.id, "Taken@example.com")
+                .await,
+            Err(Error::EmailAlreadyExists)
+        ));
+        assert!(users
+            .request_email_change(user.id, "invalid")
+            .await
+            .is_err());
+
+        let token = users
+            .request_email_change(user.id, "New@example.com")
+            .await
+            .unwrap();
+        let emails = mailer.emails();
+        assert_eq!(emails.len(), 2);
+        assert_eq!(emails[0].to, "new@example.com");
+        assert!(emails[0].text.contains(&token));
+        assert_eq!(emails[1].to, "old@example.com");
+        assert!(emails[1].text.contains("new@example.com"));
+        assert_eq!(
+            users.get_by_id(user.id).await.unwrap().email(),
+            "old@example.com"
+        );
+
+        let (first, second) = tokio::join!(
+            users.confirm_email_change(&token),
+            users.confirm_email_change(&token)
+        );
+        assert!(first.is_ok() != second.is_ok());
+        let changed = first.or(second).unwrap();
+        assert_eq!(changed.email(), "new@example.com");
+        assert!(changed.is_verified());
+        assert_eq!(
+            users.get_by_email("new@example.com").await.unwrap().id,
+            user.id
+        );
+        assert!(users.get_by_email("old@example.com").await.is_err());
+        assert!(users.confirm_email_change(&token).await.is_err());
+
+        let mut other = users.get_by_email("taken@example.com").await.unwrap();
+        other.set_email("new@example.com".into()).unwrap();
+        assert!(matches!(
+            users.modify(&other).await,
+            Err(Error::EmailAlreadyExists)
+        ));
+    }
+}
@@ -1,34 +1,248 @@
+pub mod api_keys;
 pub mod auth;
+pub mod basic_auth;
+pub(crate) mod email_change;
+pub(crate) mod password_reset;
 pub mod roles;
+pub(crate) mod totp;
 mod user_impl;
 mod users;
+mod verification;
 
 use crate::prelude::*;
+use crate::session::{hash_token, AuthKey, ClientInfo, REFRESH_PREFIX, YEAR_IN_SECS};
 use argon2::verify_encoded as verify;
 
 use crate::Roles;
-use rand::random;
-
-pub fn rand_string(size: usize) -> String {
-    (0..)
-        .map(|_| random::<char>())
-        .filter(|c| c.is_ascii())
-        .map(char::from)
-        .take(size)
-        .collect()
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rocket::http::Cookie;
+
+/// Every token carries 256 bits of entropy.
+const TOKEN_BYTES: usize = 32;
+const SALT_BYTES: usize = 16;
+
+/// Fills `bytes` from the CSPRNG of the operating system.
+fn fill_random(bytes: &mut [u8]) {
+    getrandom::fill(bytes).expect("the operating system's random number generator failed");
+}
+
+/// Generates a random token, such as a session id or the secret of a session,
+/// encoded as URL-safe base64 without padding.
+pub(crate) fn generate_token() -> String {
+    let mut bytes = [0; TOKEN_BYTES];
+    fill_random(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Generates a random salt for password hashes.
+pub(crate) fn generate_salt() -> [u8; SALT_BYTES] {
+    let mut salt = [0; SALT_BYTES];
+    fill_random(&mut salt);
+    salt
+}
+
+/// A `Users` on a fresh in-memory sqlite database, with the token key `key`, where `email` is registered
+/// with the password `Password123`.
+#[cfg(all(test, feature = "sqlx-sqlite"))]
+pub(crate) async fn test_users(email: &str) -> Users {
+    // every connection to `sqlite::memory:` opens a separate database.
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    let mut users: Users = (pool.clone(), pool).into();
+    users.set_token_key("key");
+    users.create_table().await.unwrap();
+    users
+        .create_user(email, "Password123", &Roles::default())
+        .await
+        .unwrap();
+    users
 }
 
 impl Users {
-    fn is_auth(&self, session: &Session) -> bool {
-        let option = self.sess.get(session.id);
-        if let Some(auth_key) = option {
-            auth_key == session.auth_key
-        } else {
-            false
+    async fn is_auth(&self, session: &Session) -> bool {
+        match session.version {
+            Some(version) => self.is_valid_stateless(session, version).await,
+            None => self.get_auth_key(session).await.is_some(),
+        }
+    }
+
+    /// A stateless session is valid until it expires, as long as the session version of the user did not change.
+    async fn is_valid_stateless(&self, session: &Session, version: i64) -> bool {
+        if !self.stateless || !matches!(session.expires, Some(expires) if expires > now()) {
+            return false;
+        }
+        match self.conn.get_session_version(session.id).await {
+            Ok(current) => current == version,
+            Err(_) => false,
+        }
+    }
+
+    /// Issues a stateless session, which is not kept in the session store.
+    async fn stateless_session(&self, user: &User, lifetime: Duration) -> Result<Session> {
+        let time_stamp = now();
+        Ok(Session {
+            id: user.id,
+            email: user.email.clone(),
+            session_id: generate_token(),
+            auth_key: String::new(),
+            time_stamp,
+            expires: Some(time_stamp + lifetime.as_secs() as i64),
+            version: Some(self.conn.get_session_version(user.id).await?),
+            login_id: generate_token(),
+        })
+    }
+
+    async fn get_auth_key(&self, session: &Session) -> Option<AuthKey> {
+        let token_key = self.token_key().ok()?;
+        let mut auth_key = self.sess.get(&session.session_id).await?;
+        if auth_key.user_id != session.id || !auth_key.verify(token_key, &session.auth_key) {
+            return None;
+        }
+        if !auth_key.is_hashed() {
+            // the session predates hashed tokens, so its token is replaced by the hash.
+            auth_key.secret = hash_token(token_key, &session.auth_key);
+            let result = self
+                .sess
+                .insert_for(&session.session_id, auth_key.clone(), auth_key.lifetime())
+                .await;
+            if let Err(error) = result {
+                log::warn!("failed to hash the token of a session: {}", error);
+            }
+        }
+        Some(auth_key)
+    }
+
+    /// Updates the last activity and the idle deadline of the session,
+    /// at most once every [`SessionPolicy::refresh_interval`].
+    /// If the privileges of the user changed and `reissue` is set, the session is rotated instead,
+    /// and the new session is returned.
+    /// A session bound to a different client by [`SessionPolicy::client_binding`] is ended with [`Error::ClientMismatchError`].
+    async fn record_activity(
+        &self,
+        session: &Session,
+        client: &ClientInfo,
+        reissue: bool,
+    ) -> Result<Option<Session>> {
+        if session.version.is_some() {
+            // stateless sessions do not track activity.
+            return Ok(None);
+        }
+        let Some(auth_key) = self.get_auth_key(session).await else {
+            return Ok(None);
+        };
+        if let Some(binding) = self.policy.client_binding {
+            if !binding.matches(&auth_key, client) {
+                log::warn!("a session was used by a different client, so it was ended");
+                self.sess.remove(&session.session_id).await?;
+                return Err(Error::ClientMismatchError);
+            }
+        }
+        if auth_key.rotate && reissue {
+            return self.rotate_session(session).await.map(Some);
+        }
+        let refresh_interval = self.policy.refresh_interval.as_secs() as i64;
+        if now() - auth_key.last_active >= refresh_interval {
+            self.sess
+                .touch(&session.session_id, self.policy.idle_timeout)
+                .await?;
         }
+        Ok(None)
     }
 
-    async fn login(&self, form: &Login) -> Result<String> {
+    /// Replaces the id and the token of a session, keeping its expiry and client information.
+    /// The old session is ended, and the new one has to be sent to the client.
+    /// A stateless session is reissued with the current session version of the user instead.
+    async fn rotate_session(&self, session: &Session) -> Result<Session> {
+        if session.version.is_some() {
+            let expires = session.expires.ok_or(Error::UnauthenticatedError)?;
+            if expires <= now() {
+                return Err(Error::UnauthenticatedError);
+            }
+            return Ok(Session {
+                session_id: generate_token(),
+                version: Some(self.conn.get_session_version(session.id).await?),
+                ..session.clone()
+            });
+        }
+        let mut key = self
+            .get_auth_key(session)
+            .await
+            .ok_or(Error::UnauthenticatedError)?;
+        let token = generate_token();
+        key.secret = hash_token(self.token_key()?, &token);
+        key.rotate = false;
+        key.refresh(self.policy.idle_timeout);
+        let rotated = Session {
+            session_id: generate_token(),
+            auth_key: token,
+            ..session.clone()
+        };
+        self.sess
+            .insert_for(&rotated.session_id, key.clone(), key.lifetime())
+            .await?;
+        self.sess.remove(&session.session_id).await?;
+        Ok(rotated)
+    }
+
+    /// The cookie that sends `session` to the client.
+    async fn session_cookie(&self, session: &Session) -> Cookie<'static> {
+        let mut lifetime = Duration::ZERO;
+        if let Some(expires) = session.expires {
+            lifetime = Duration::from_secs((expires - now()).max(0) as u64);
+        } else if self.cookie.persistent {
+            if let Some(key) = self.sess.get(&session.session_id).await {
+                lifetime = key.lifetime();
+            }
+        }
+        self.cookie.session_cookie(session, lifetime)
+    }
+
+    /// The key set by [`Users::set_token_key`], without which no token is hashed or signed.
+    pub(crate) fn token_key(&self) -> Result<&[u8]> {
+        if self.token_key.is_empty() {
+            return Err(Error::TokenKeyMissingError);
+        }
+        Ok(&self.token_key)
+    }
+
+    /// The sessions of a user in the session store, without the families of refresh tokens that are kept there too.
+    async fn list_sessions(&self, user_id: i32) -> Result<Vec<(String, AuthKey)>> {
+        let mut sessions = self.sess.list(user_id).await?;
+        sessions.retain(|(session_id, _)| !session_id.starts_with(REFRESH_PREFIX));
+        Ok(sessions)
+    }
+
+    /// Marks every session of a user, so that each is rotated on its next request.
+    async fn rotate_user_sessions(&self, user_id: i32) -> Result<()> {
+        for (session_id, mut key) in self.list_sessions(user_id).await? {
+            key.rotate = true;
+            self.sess
+                .insert_for(&session_id, key.clone(), key.lifetime())
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn login(&self, form: &Login, client: &ClientInfo) -> Result<Session> {
+        let user = self.check_credentials(form).await?;
+        self.set_auth_key(&user, client).await
+    }
+
+    /// Checks the email and password of `form`. A user with two-factor authentication enabled is rejected with
+    /// [`Error::SecondFactorRequiredError`], since a code has to be checked as well.
+    pub(crate) async fn check_credentials(&self, form: &Login) -> Result<User> {
+        let user = self.check_password(form).await?;
+        if self.has_totp(user.id).await? {
+            return Err(Error::SecondFactorRequiredError);
+        }
+        Ok(user)
+    }
+
+    async fn check_password(&self, form: &Login) -> Result<User> {
         let form_pwd = &form.password.as_bytes();
         let user = self
             .conn
@@ -38,63 +252,252 @@ impl Users {
         let user_pwd = &user.password;
 
         if verify(user_pwd, form_pwd)? {
-            self.set_auth_key(user.id)
+            if self.require_verified && !user.verified {
+                return Err(Error::UnverifiedEmailError);
+            }
+            Ok(user)
         } else {
             Err(Error::UnauthorizedError)
         }
     }
 
-    fn logout(&self, session: &Session) -> Result<()> {
-        if self.is_auth(session) {
-            self.sess.remove(session.id)?;
+    /// Tokens are always kept in the session store, even in stateless mode.
+    async fn issue_token(&self, form: &Login, client: &ClientInfo) -> Result<String> {
+        let user = self.check_credentials(form).await?;
+        let session = self
+            .stored_session(&user, self.policy.absolute_timeout, client)
+            .await?;
+        Ok(format!("{}.{}", session.session_id, session.auth_key))
+    }
+
+    /// The session of a bearer token, if the token is valid.
+    pub(crate) async fn token_session(&self, token: &str) -> Option<Session> {
+        let (session_id, secret) = token.split_once('.')?;
+        if session_id.starts_with(REFRESH_PREFIX) {
+            return None;
+        }
+        let key = self.sess.get(session_id).await?;
+        if !key.verify(self.token_key().ok()?, secret) {
+            return None;
+        }
+        let user = self.conn.get_user_by_id(key.user_id).await.ok()?;
+        Some(new_session(&user, session_id.into(), secret.into(), &key))
+    }
+
+    async fn logout(&self, session: &Session) -> Result<()> {
+        if self.is_auth(session).await {
+            self.sess.remove(&session.session_id).await?;
         }
 
         Ok(())
     }
 
-    fn set_auth_key_for(&self, user_id: i32, time: Duration) -> Result<String> {
-        let key = rand_string(10);
-        self.sess.insert_for(user_id, key.clone(), time)?;
-        Ok(key)
+    async fn set_auth_key_for(
+        &self,
+        user: &User,
+        time: Duration,
+        client: &ClientInfo,
+    ) -> Result<Session> {
+        if self.stateless {
+            return self
+                .stateless_session(user, self.policy.lifetime(time))
+                .await;
+        }
+        self.stored_session(user, Some(time), client).await
+    }
+
+    async fn set_auth_key(&self, user: &User, client: &ClientInfo) -> Result<Session> {
+        if self.stateless {
+            let lifetime = self
+                .policy
+                .absolute_timeout
+                .unwrap_or(Duration::from_secs(YEAR_IN_SECS));
+            return self.stateless_session(user, lifetime).await;
+        }
+        self.stored_session(user, self.policy.absolute_timeout, client)
+            .await
     }
 
-    fn set_auth_key(&self, user_id: i32) -> Result<String> {
-        let key = rand_string(15);
-        self.sess.insert(user_id, key.clone())?;
-        Ok(key)
+    /// Creates a session in the session store, which lasts `time` capped by the absolute timeout,
+    /// or one year if no time is given.
+    async fn stored_session(
+        &self,
+        user: &User,
+        time: Option<Duration>,
+        client: &ClientInfo,
+    ) -> Result<Session> {
+        let session_id = generate_token();
+        let token = generate_token();
+        let mut key = AuthKey::new(user.id, hash_token(self.token_key()?, &token), client);
+        key.refresh(self.policy.idle_timeout);
+        let session = new_session(user, session_id, token, &key);
+        match time {
+            Some(time) => {
+                self.sess
+                    .insert_for(&session.session_id, key, self.policy.lifetime(time))
+                    .await?
+            }
+            None => self.sess.insert(&session.session_id, key).await?,
+        }
+        Ok(session)
     }
 
     async fn signup(&self, form: &Signup) -> Result<()> {
         form.validate()?;
         let email = &form.email.to_lowercase();
         let password = &form.password;
-        let result = self.create_user(email, password, &Roles::default()).await;
-        match result {
-            Ok(_) => Ok(()),
-            #[cfg(feature = "sqlx")]
-            Err(Error::SqlxError(sqlx::Error::Database(error))) => {
-                if error.code() == Some("23000".into()) {
-                    Err(Error::EmailAlreadyExists)
-                } else {
-                    Err(Error::SqlxError(sqlx::Error::Database(error)))
-                }
-            }
-            Err(error) => Err(error),
-        }
+        self.create_user(email, password, &Roles::default()).await
     }
 
-    async fn login_for(&self, form: &Login, time: Duration) -> Result<String> {
-        let form_pwd = &form.password.as_bytes();
-        let user = self
-            .conn
-            .get_user_by_email(&form.email.to_lowercase())
-            .await?;
-        let user_pwd = &user.password;
+    async fn login_for(
+        &self,
+        form: &Login,
+        time: Duration,
+        client: &ClientInfo,
+    ) -> Result<Session> {
+        let user = self.check_credentials(form).await?;
+        self.set_auth_key_for(&user, time, client).await
+    }
+}
 
-        if verify(user_pwd, form_pwd)? {
-            self.set_auth_key_for(user.id, time)
-        } else {
-            Err(Error::UnauthorizedError)
-        }
+/// Only the hash of `token` is kept in `key`, the token itself is handed to the client.
+fn new_session(user: &User, session_id: String, token: String, key: &AuthKey) -> Session {
+    Session {
+        id: user.id,
+        email: user.email.clone(),
+        session_id,
+        auth_key: token,
+        time_stamp: key.time_stamp,
+        expires: None,
+        version: None,
+        login_id: generate_token(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tokens_are_url_safe_and_unique() {
+        let token = generate_token();
+        // 32 bytes take 43 characters of base64 without padding.
+        assert_eq!(token.len(), 43);
+        assert!(token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        assert_ne!(token, generate_token());
+        assert_ne!(generate_salt(), generate_salt());
+    }
+
+    #[cfg(feature = "sqlx-sqlite")]
+    #[tokio::test]
+    async fn test_role_change_rotates_sessions() {
+        let users = test_users("rotate@example.com").await;
+        let form = Login {
+            email: "rotate@example.com".into(),
+            password: "Password123".into(),
+            csrf_token: None,
+        };
+        let session = users.login(&form, &ClientInfo::default()).await.unwrap();
+        assert!(users
+            .record_activity(&session, &ClientInfo::default(), true)
+            .await
+            .unwrap()
+            .is_none());
+
+        let mut user = users.get_by_id(session.id).await.unwrap();
+        user.roles = Roles::from_strs(&[crate::ADMIN_ROLE]);
+        users.modify(&user).await.unwrap();
+
+        let rotated = users
+            .record_activity(&session, &ClientInfo::default(), true)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(rotated.session_id, session.session_id);
+        assert_ne!(rotated.auth_key, session.auth_key);
+        assert_eq!(rotated.login_id, session.login_id);
+        assert!(!users.is_auth(&session).await);
+        assert!(users.is_auth(&rotated).await);
+        assert!(users
+            .record_activity(&rotated, &ClientInfo::default(), true)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[cfg(feature = "sqlx-sqlite")]
+    #[tokio::test]
+    async fn test_stateless_sessions_are_revoked_by_version() {
+        let mut users = test_users("stateless@example.com").await;
+        users.set_stateless_sessions(true);
+        let form = Login {
+            email: "stateless@example.com".into(),
+            password: "Password123".into(),
+            csrf_token: None,
+        };
+        let session = users.login(&form, &ClientInfo::default()).await.unwrap();
+        assert_eq!(session.version, Some(0));
+        assert!(users.sess.get(&session.session_id).await.is_none());
+        assert!(users.is_auth(&session).await);
+
+        let expired = Session {
+            expires: Some(now()),
+            ..session.clone()
+        };
+        assert!(!users.is_auth(&expired).await);
+
+        users.revoke_all_sessions(session.id).await.unwrap();
+        assert!(!users.is_auth(&session).await);
+        let session = users.login(&form, &ClientInfo::default()).await.unwrap();
+        assert_eq!(session.version, Some(1));
+        assert!(users.is_auth(&session).await);
+
+        users.set_stateless_sessions(false);
+        assert!(!users.is_auth(&session).await);
+    }
+
+    #[cfg(feature = "sqlx-sqlite")]
+    #[tokio::test]
+    async fn test_issued_tokens_resolve_to_sessions() {
+        let mut users = test_users("token@example.com").await;
+        let form = Login {
+            email: "token@example.com".into(),
+            password: "Password123".into(),
+            csrf_token: None,
+        };
+        let token = users
+            .issue_token(&form, &ClientInfo::default())
+            .await
+            .unwrap();
+
+        // without a token key, no session is created, and none is accepted.
+        users.set_token_key("");
+        assert!(matches!(
+            users.issue_token(&form, &ClientInfo::default()).await,
+            Err(Error::TokenKeyMissingError)
+        ));
+        assert!(users.token_session(&token).await.is_none());
+        users.set_token_key("key");
+
+        let token = users
+            .issue_token(&form, &ClientInfo::default())
+            .await
+            .unwrap();
+
+        let session = users.token_session(&token).await.unwrap();
+        assert_eq!(session.email, "token@example.com");
+        assert!(users.is_auth(&session).await);
+
+        let (session_id, _) = token.split_once('.').unwrap();
+        assert!(users
+            .token_session(&format!("{}.{}", session_id, generate_token()))
+            .await
+            .is_none());
+        assert!(users.token_session(session_id).await.is_none());
+
+        users.logout(&session).await.unwrap();
+        assert!(users.token_session(&token).await.is_none());
     }
 }
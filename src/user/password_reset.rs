@@ -0,0 +1,166 @@
+use super::{generate_salt, generate_token};
+use crate::prelude::*;
+use crate::session::hash_token;
+use subtle::ConstantTimeEq;
+
+/// How long a password reset token is valid.
+const RESET_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// A pending password reset. Only a keyed hash of its token is stored, and a user has at most one pending reset.
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PasswordReset {
+    pub(crate) id: String,
+    pub(crate) user_id: i32,
+    pub(crate) hash: String,
+    pub(crate) expires: i64,
+}
+
+impl Users {
+    /// Creates a token that lets the user with this email choose a new password with [`Users::reset_password`].
+    /// It is valid for one hour. If a mailer is set with [`Users::set_mailer`], the token is sent to the address
+    /// with the `password_reset` template, otherwise it has to be delivered by the application.
+    /// Requesting a new token invalidates the previous one.
+    ///
+    /// It fails with [`Error::EmailDoesNotExist`] if no user has this email. To avoid revealing which
+    /// addresses are registered, a route should answer the same way whether this call succeeds or not.
+    /// ```rust
+    /// # use rocket::{post, State, form::Form};
+    /// # use rocket_auth2::{Error, Users};
+    /// #[post("/forgot-password", data = "<email>")]
+    /// async fn forgot_password(email: Form<String>, users: &State<Users>) -> &'static str {
+    ///     if let Ok(token) = users.request_password_reset(&email).await {
+    ///         println!("visit /reset-password/{} to reset the password of {}", token, *email);
+    ///     }
+    ///     "If the address is registered, a link to reset the password was sent to it."
+    /// }
+    /// ```
+    pub async fn request_password_reset(&self, email: &str) -> Result<String> {
+        let user = self
+            .conn
+            .get_user_by_email(&email.to_lowercase())
+            .await
+            .map_err(|_| Error::EmailDoesNotExist(email.into()))?;
+        let secret = generate_token();
+        let reset = PasswordReset {
+            id: generate_token(),
+            user_id: user.id,
+            hash: hash_token(self.token_key()?, &secret),
+            expires: now() + RESET_LIFETIME.as_secs() as i64,
+        };
+        self.conn.create_password_reset(&reset).await?;
+        let token = format!("{}.{}", reset.id, secret);
+        let vars = [("email", user.email.as_str()), ("token", &token)];
+        self.send_email(|t| &t.password_reset, &user.email, &vars)
+            .await?;
+        Ok(token)
+    }
+
+    /// Sets a new password, given a token from [`Users::request_password_reset`], and ends every session of the user.
+    /// The user is then notified with the `password_changed` template, if a mailer is set.
+    /// It fails with [`Error::InvalidTokenError`] if the token is malformed, expired, or was already used,
+    /// and with a validation error if the password is not secure enough, in which case the token can be used again.
+    /// The token is consumed before the password is set, so that only one of several concurrent requests succeeds.
+    /// ```rust
+    /// # use rocket::{post, State, form::Form};
+    /// # use rocket_auth2::{Error, Users};
+    /// #[post("/reset-password/<token>", data = "<password>")]
+    /// async fn reset_password(token: &str, password: Form<String>, users: &State<Users>) -> Result<&'static str, Error> {
+    ///     users.reset_password(token, &password).await?;
+    ///     Ok("Your password was changed, you can log in now.")
+    /// }
+    /// ```
+    pub async fn reset_password(&self, token: &str, new_password: &str) -> Result<()> {
+        let (reset_id, secret) = token.split_once('.').ok_or(Error::InvalidTokenError)?;
+        let reset = self
+            .conn
+            .get_password_reset(reset_id)
+            .await
+            .map_err(|_| Error::InvalidTokenError)?;
+        let hash = hash_token(self.token_key()?, secret);
+        if !bool::from(hash.as_bytes().ct_eq(reset.hash.as_bytes())) {
+            return Err(Error::InvalidTokenError);
+        }
+        if reset.expires <= now() {
+            self.conn.consume_password_reset(reset_id).await?;
+            return Err(Error::InvalidTokenError);
+        }
+        is_password_secure(new_password)?;
+        if !self.conn.consume_password_reset(reset_id).await? {
+            return Err(Error::InvalidTokenError);
+        }
+
+        let mut user = self.conn.get_user_by_id(reset.user_id).await?;
+        let salt = generate_salt();
+        let config = argon2::Config::default();
+        user.password = argon2::hash_encoded(new_password.as_bytes(), &salt, &config)?;
+        self.conn.update_user(&user).await?;
+        self.revoke_all_sessions(user.id).await?;
+        let vars = [("email", user.email.as_str())];
+        if let Err(error) = self
+            .send_email(|t| &t.password_changed, &user.email, &vars)
+            .await
+        {
+            log::warn!("failed to notify a user of a password reset: {}", error);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-sqlite"))]
+mod test {
+    use super::*;
+    use crate::session::ClientInfo;
+
+    #[tokio::test]
+    async fn test_password_reset_tokens_are_single_use() {
+        let users = crate::user::test_users("reset@example.com").await;
+        let login = |password: &str| Login {
+            email: "reset@example.com".into(),
+            password: password.into(),
+            csrf_token: None,
+        };
+        let session = users
+            .login(&login("Password123"), &ClientInfo::default())
+            .await
+            .unwrap();
+        assert!(users
+            .request_password_reset("nobody@example.com")
+            .await
+            .is_err());
+
+        let first = users
+            .request_password_reset("Reset@example.com")
+            .await
+            .unwrap();
+        let token = users
+            .request_password_reset("reset@example.com")
+            .await
+            .unwrap();
+        assert!(users
+            .reset_password(&first, "NewPassword123")
+            .await
+            .is_err());
+        assert!(users.reset_password(&token, "weak").await.is_err());
+
+        let (first, second) = tokio::join!(
+            users.reset_password(&token, "NewPassword123"),
+            users.reset_password(&token, "OtherPassword123")
+        );
+        assert!(first.is_ok() != second.is_ok());
+        let new_password = match first {
+            Ok(()) => "NewPassword123",
+            Err(_) => "OtherPassword123",
+        };
+        assert!(users
+            .reset_password(&token, "ThirdPassword123")
+            .await
+            .is_err());
+        assert!(!users.is_auth(&session).await);
+        assert!(users
+            .check_credentials(&login("Password123"))
+            .await
+            .is_err());
+        assert!(users.check_credentials(&login(new_password)).await.is_ok());
+    }
+}
@@ -0,0 +1,398 @@
+use super::fill_random;
+use crate::prelude::*;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+/// Secrets carry 160 bits, the size of an HMAC-SHA1 key recommended by RFC 4226.
+const SECRET_BYTES: usize = 20;
+const NONCE_BYTES: usize = 12;
+/// Codes have 6 digits and change every 30 seconds, which every authenticator app supports.
+const DIGITS: u32 = 6;
+const PERIOD: i64 = 30;
+/// How many steps a code may be early or late, to allow for the clock of the phone to drift.
+const SKEW: i64 = 1;
+/// How many wrong codes in a row lock two-factor authentication of a user, which leaves too few guesses
+/// to find a code by chance.
+const MAX_FAILURES: i32 = 5;
+/// How many seconds after the last code tried a lock ends, and the count of wrong codes starts over.
+const LOCKOUT: i64 = 15 * 60;
+/// Derives the key that encrypts the secrets from the token key.
+const ENCRYPTION_LABEL: &[u8] = b"rocket_auth2 totp secret";
+
+/// The TOTP secret of a user, encrypted with a key derived from the token key, so that it is useless without it.
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TotpSecret {
+    pub(crate) user_id: i32,
+    pub(crate) secret: String,
+    /// Set once the user confirmed the enrollment with a first code.
+    pub(crate) enabled: bool,
+    /// The time step of the last accepted code. Codes of this step and earlier ones are rejected.
+    pub(crate) last_step: Option<i64>,
+    /// The number of codes tried since the last accepted one.
+    #[serde(default)]
+    pub(crate) failures: i32,
+    /// The Unix time at which the last code was tried.
+    #[serde(default)]
+    pub(crate) last_attempt: Option<i64>,
+}
+
+/// What a user needs to add their account to an authenticator app, returned by [`Users::enroll_totp`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TotpEnrollment {
+    /// The secret encoded in base32, for apps where it is typed in.
+    pub secret: String,
+    /// An `otpauth://` URI holding the secret, meant to be shown as a QR code.
+    pub uri: String,
+}
+
+impl Users {
+    /// Starts enabling two-factor authentication for a user, with time-based one-time passwords (RFC 6238).
+    /// It generates a new secret, which the user adds to an authenticator app, and which is only enabled once
+    /// a first code is confirmed with [`Users::confirm_totp`]. Enrolling again before that replaces the secret.
+    /// `issuer` names the application in the app. The secret is stored encrypted with the key set by
+    /// [`Users::set_token_key`], which is required, and it fails with [`Error::TotpAlreadyEnabledError`]
+    /// if the user already has two-factor authentication enabled.
+    /// ```rust
+    /// # use rocket_auth2::{Users, Error};
+    /// # async fn func(users: Users) -> Result<(), Error> {
+    /// let enrollment = users.enroll_totp(4, "Example").await?;
+    /// println!("scan {} or type {}", enrollment.uri, enrollment.secret);
+    /// # Ok(()) }
+    /// ```
+    pub async fn enroll_totp(&self, user_id: i32, issuer: &str) -> Result<TotpEnrollment> {
+        let user = self.conn.get_user_by_id(user_id).await?;
+        if self.has_totp(user_id).await? {
+            return Err(Error::TotpAlreadyEnabledError);
+        }
+        let mut secret = [0; SECRET_BYTES];
+        fill_random(&mut secret);
+        let stored = TotpSecret {
+            user_id,
+            secret: self.encrypt_totp_secret(user_id, &secret)?,
+            enabled: false,
+            last_step: None,
+            failures: 0,
+            last_attempt: None,
+        };
+        self.conn.set_totp_secret(&stored).await?;
+
+        let secret = base32(&secret);
+        let issuer = percent_encode(issuer);
+        let uri = format!(
+            "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+            issuer,
+            percent_encode(&user.email),
+            secret,
+            issuer,
+            DIGITS,
+            PERIOD
+        );
+        Ok(TotpEnrollment { secret, uri })
+    }
+
+    /// Enables two-factor authentication for a user enrolled with [`Users::enroll_totp`], given a first code
+    /// from their authenticator app, which proves it was set up. From then on, logging in with [`Auth::login`]
+    /// requires a code as well. It fails with [`Error::InvalidTotpCodeError`] if the code is wrong.
+    pub async fn confirm_totp(&self, user_id: i32, code: &str) -> Result<()> {
+        let mut secret = self
+            .conn
+            .get_totp_secret(user_id)
+            .await?
+            .ok_or(Error::TotpNotEnrolledError)?;
+        if secret.enabled {
+            return Err(Error::TotpAlreadyEnabledError);
+        }
+        secret.last_step = Some(self.accept_totp_code(&secret, code).await?);
+        secret.enabled = true;
+        self.conn.set_totp_secret(&secret).await
+    }
+
+    /// Disables two-factor authentication for a user and forgets their secret, for instance when they lost their phone.
+    /// Users disabling it themselves should go through [`Auth::disable_totp`], which asks for a code.
+    pub async fn disable_totp(&self, user_id: i32) -> Result<()> {
+        self.conn.delete_totp_secret(user_id).await
+    }
+
+    /// Lets a user try codes again after two-factor authentication was locked by too many wrong codes,
+    /// see [`Error::TotpLockedError`], without waiting for the lock to end.
+    /// It is meant for administrators, once they made sure who is asking.
+    pub async fn unlock_totp(&self, user_id: i32) -> Result<()> {
+        let mut secret = self
+            .conn
+            .get_totp_secret(user_id)
+            .await?
+            .ok_or(Error::TotpNotEnrolledError)?;
+        secret.failures = 0;
+        self.conn.set_totp_secret(&secret).await
+    }
+
+    /// Whether the user has two-factor authentication enabled.
+    pub async fn has_totp(&self, user_id: i32) -> Result<bool> {
+        let secret = self.conn.get_totp_secret(user_id).await?;
+        Ok(secret.is_some_and(|secret| secret.enabled))
+    }
+
+    /// Checks a code of a user with two-factor authentication enabled. A code is only accepted once.
+    pub(crate) async fn check_totp(&self, user_id: i32, code: &str) -> Result<()> {
+        let secret = self
+            .conn
+            .get_totp_secret(user_id)
+            .await?
+            .filter(|secret| secret.enabled)
+            .ok_or(Error::InvalidTotpCodeError)?;
+        self.accept_totp_code(&secret, code).await?;
+        Ok(())
+    }
+
+    /// Compares `code` with the codes of the steps around the current time, and returns the step it matched,
+    /// after recording it so that neither this code nor an earlier one is accepted again.
+    /// Every attempt is counted before the code is compared, so that concurrent requests can not exceed
+    /// `MAX_FAILURES`, and the count is reset when a code is accepted, or `LOCKOUT` after the last attempt.
+    async fn accept_totp_code(&self, secret: &TotpSecret, code: &str) -> Result<i64> {
+        let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+        if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(Error::InvalidTotpCodeError);
+        }
+        if !self
+            .conn
+            .count_totp_attempt(secret.user_id, MAX_FAILURES, now() - LOCKOUT)
+            .await?
+        {
+            return Err(Error::TotpLockedError);
+        }
+        let key = self.decrypt_totp_secret(secret)?;
+        let current = now().div_euclid(PERIOD);
+        for step in current - SKEW..=current + SKEW {
+            let expected = format_code(hotp(&key, step as u64));
+            if bool::from(expected.as_bytes().ct_eq(code.as_bytes())) {
+                if self.conn.use_totp_step(secret.user_id, step).await? {
+                    return Ok(step);
+                }
+                break;
+            }
+        }
+        Err(Error::InvalidTotpCodeError)
+    }
+
+    fn totp_cipher(&self) -> Result<Aes256Gcm> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(self.token_key()?)
+            .expect("HMAC accepts keys of any size");
+        mac.update(ENCRYPTION_LABEL);
+        Ok(Aes256Gcm::new(&mac.finalize().into_bytes()))
+    }
+
+    /// Encrypts a secret with AES-GCM, bound to the user so that it can not be moved to another one.
+    fn encrypt_totp_secret(&self, user_id: i32, secret: &[u8]) -> Result<String> {
+        let mut nonce = [0; NONCE_BYTES];
+        fill_random(&mut nonce);
+        let payload = Payload {
+            msg: secret,
+            aad: &user_id.to_be_bytes(),
+        };
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend(
+            self.totp_cipher()?
+                .encrypt(Nonce::from_slice(&nonce), payload)
+                .expect("AES-GCM encrypts secrets of any size"),
+        );
+        Ok(URL_SAFE_NO_PAD.encode(encrypted))
+    }
+
+    fn decrypt_totp_secret(&self, secret: &TotpSecret) -> Result<Vec<u8>> {
+        let cipher = self.totp_cipher()?;
+        let encrypted = URL_SAFE_NO_PAD
+            .decode(&secret.secret)
+            .ok()
+            .filter(|encrypted| encrypted.len() > NONCE_BYTES);
+        let decrypted = encrypted.and_then(|encrypted| {
+            let (nonce, msg) = encrypted.split_at(NONCE_BYTES);
+            let payload = Payload {
+                msg,
+                aad: &secret.user_id.to_be_bytes(),
+            };
+            cipher.decrypt(Nonce::from_slice(nonce), payload).ok()
+        });
+        decrypted.ok_or_else(|| {
+            log::error!(
+                "the TOTP secret of user {} could not be decrypted, the token key may have changed",
+                secret.user_id
+            );
+            Error::InvalidTotpCodeError
+        })
+    }
+}
+
+/// The HOTP value of RFC 4226 for `counter`, reduced to the digits of a code.
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        hash[offset],
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    (value & 0x7fff_ffff) % 10u32.pow(DIGITS)
+}
+
+fn format_code(code: u32) -> String {
+    format!("{:0width$}", code, width = DIGITS as usize)
+}
+
+/// Encodes bytes in base32 without padding (RFC 4648), which is how authenticator apps expect secrets.
+fn base32(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let mut buffer: u16 = 0;
+    let mut bits = 0;
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ALPHABET[(buffer >> bits) as usize & 31] as char);
+        }
+        buffer &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        encoded.push(ALPHABET[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+    encoded
+}
+
+/// Percent-encodes everything but unreserved characters, for the label and the parameters of an `otpauth://` URI.
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_codes_match_rfc_6238() {
+        let key = b"12345678901234567890";
+        for (time, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+        ] {
+            assert_eq!(format_code(hotp(key, time / PERIOD as u64)), code);
+        }
+        assert_eq!(base32(key), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(base32(b"f"), "MY");
+        assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(percent_encode("Acme Co:a@b.c"), "Acme%20Co%3Aa%40b.c");
+    }
+
+    #[cfg(feature = "sqlx-sqlite")]
+    #[tokio::test]
+    async fn test_totp_codes_are_accepted_once() {
+        let mut users = crate::user::test_users("totp@example.com").await;
+        let user = users.get_by_email("totp@example.com").await.unwrap();
+        users.set_token_key("");
+        assert!(matches!(
+            users.enroll_totp(user.id, "Example").await,
+            Err(Error::TokenKeyMissingError)
+        ));
+        users.set_token_key("key");
+
+        let enrollment = users.enroll_totp(user.id, "Example").await.unwrap();
+        assert!(enrollment.uri.starts_with(&format!(
+            "otpauth://totp/Example:totp%40example.com?secret={}&issuer=Example",
+            enrollment.secret
+        )));
+        let stored = users.conn.get_totp_secret(user.id).await.unwrap().unwrap();
+        assert!(!stored.secret.contains(&enrollment.secret));
+        assert!(!users.has_totp(user.id).await.unwrap());
+
+        let key = users.decrypt_totp_secret(&stored).unwrap();
+        let code = |step: i64| format_code(hotp(&key, step as u64));
+        let step = now() / PERIOD;
+        assert!(users.confirm_totp(user.id, "000000x").await.is_err());
+        users.confirm_totp(user.id, &code(step - 1)).await.unwrap();
+        assert!(users.has_totp(user.id).await.unwrap());
+        assert!(matches!(
+            users.enroll_totp(user.id, "Example").await,
+            Err(Error::TotpAlreadyEnabledError)
+        ));
+
+        assert!(matches!(
+            users.check_totp(user.id, &code(step - 1)).await,
+            Err(Error::InvalidTotpCodeError)
+        ));
+        users.check_totp(user.id, &code(step)).await.unwrap();
+        assert!(users.check_totp(user.id, &code(step)).await.is_err());
+        assert!(users.check_totp(user.id, &code(step + 5)).await.is_err());
+
+        users.disable_totp(user.id).await.unwrap();
+        assert!(!users.has_totp(user.id).await.unwrap());
+    }
+
+    #[cfg(feature = "sqlx-sqlite")]
+    #[tokio::test]
+    async fn test_wrong_codes_lock_totp() {
+        let users = crate::user::test_users("totp@example.com").await;
+        let user = users.get_by_email("totp@example.com").await.unwrap();
+        users.enroll_totp(user.id, "Example").await.unwrap();
+        let stored = users.conn.get_totp_secret(user.id).await.unwrap().unwrap();
+        let key = users.decrypt_totp_secret(&stored).unwrap();
+        let code = |step: i64| format_code(hotp(&key, step as u64));
+        let step = now() / PERIOD;
+        users.confirm_totp(user.id, &code(step - 1)).await.unwrap();
+
+        // an accepted code resets the count.
+        for _ in 1..MAX_FAILURES {
+            assert!(users.check_totp(user.id, &code(step + 5)).await.is_err());
+        }
+        users.check_totp(user.id, &code(step)).await.unwrap();
+
+        for _ in 0..MAX_FAILURES {
+            assert!(matches!(
+                users.check_totp(user.id, &code(step + 5)).await,
+                Err(Error::InvalidTotpCodeError)
+            ));
+        }
+        assert!(matches!(
+            users.check_totp(user.id, &code(step + 1)).await,
+            Err(Error::TotpLockedError)
+        ));
+
+        // the lock ends on its own once the last code was tried long enough ago.
+        let mut stored = users.conn.get_totp_secret(user.id).await.unwrap().unwrap();
+        stored.last_attempt = Some(now() - LOCKOUT - 1);
+        users.conn.set_totp_secret(&stored).await.unwrap();
+        users.check_totp(user.id, &code(step + 1)).await.unwrap();
+
+        for _ in 0..MAX_FAILURES {
+            assert!(users.check_totp(user.id, &code(step + 5)).await.is_err());
+        }
+        assert!(matches!(
+            users.check_totp(user.id, &code(step + 5)).await,
+            Err(Error::TotpLockedError)
+        ));
+        users.unlock_totp(user.id).await.unwrap();
+        assert!(matches!(
+            users.check_totp(user.id, &code(step + 5)).await,
+            Err(Error::InvalidTotpCodeError)
+        ));
+    }
+}
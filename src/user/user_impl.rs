@@ -1,5 +1,5 @@
 use super::auth::{validate_email, Auth};
-use super::rand_string;
+use super::generate_salt;
 use std::borrow::Borrow;
 
 use crate::error;
@@ -28,9 +28,9 @@ impl User {
     pub fn set_password(&mut self, new: &str) -> Result<(), Box<dyn std::error::Error>> {
         crate::forms::is_password_secure(new)?;
         let password = new.as_bytes();
-        let salt = rand_string(10);
+        let salt = generate_salt();
         let config = argon2::Config::default();
-        let hash = argon2::hash_encoded(password, salt.as_bytes(), &config)?;
+        let hash = argon2::hash_encoded(password, &salt, &config)?;
         self.password = hash;
         Ok(())
     }
@@ -39,7 +39,6 @@ impl User {
     /// Useful for checking password before resetting email/password.
     /// To avoid bruteforcing this function should not be directly accessible from a route.
     /// Additionally, it is good to implement rate limiting on routes using this function.
-
     pub fn compare_password(&self, password: &str) -> Result<bool, argon2::Error> {
         verify_encoded(&self.password, password.as_bytes())
     }
@@ -73,27 +72,47 @@ impl User {
 
     /// This functions allows to easily modify the email of a user.
     /// In case the input is not a valid email, it will return an error.
-    /// In case the user corresponds to the authenticated client, it's easier to use [`Auth::change_email`].
+    /// A new email is not verified, see [`Users::send_verification`], and [`Users::modify`] fails with
+    /// [`Error::EmailAlreadyExists`] if it belongs to another user.
+    ///
+    /// The address is changed without asking its owner, so this is meant for administrators.
+    /// Users should change their own email with [`Auth::change_email`], which waits for the new address to be confirmed.
     /// ```rust
-    /// # use rocket::{State, get};
-    /// # use rocket_auth2::{Error, Auth};
-    /// #[get("/set-email/<email>")]
-    /// async fn set_email(email: String, auth: Auth<'_>) -> Result<String, Error> {
-    ///     let mut user = auth.get_user().await.unwrap();
+    /// # use rocket::{State, post};
+    /// # use rocket_auth2::{AdminUser, Error, Users};
+    /// #[post("/users/<id>/set-email/<email>")]
+    /// async fn set_email(id: i32, email: String, _admin: AdminUser, users: &State<Users>) -> Result<String, Error> {
+    ///     let mut user = users.get_by_id(id).await?;
     ///     user.set_email(email)?;
-    ///     auth.users.modify(&user).await?;
-    ///     Ok("Your user email was changed".into())
+    ///     users.modify(&user).await?;
+    ///     Ok("The email of the user was changed".into())
     /// }
     /// ```
     pub fn set_email(&mut self, email: String) -> Result<()> {
         if validate_email(&email) {
-            self.email = email.to_lowercase();
+            let email = email.to_lowercase();
+            if email != self.email {
+                self.verified = false;
+            }
+            self.email = email;
             Ok(())
         } else {
             Err(Error::InvalidEmailAddressError)
         }
     }
 
+    /// Whether the user proved that they own their email address, see [`Users::verify_email`].
+    pub fn is_verified(&self) -> bool {
+        self.verified
+    }
+
+    /// Marks the email of the user as verified, or not, without a token.
+    /// It is meant for accounts created by an administrator. In order for the change to be saved,
+    /// the user must be passed to [`Users::modify`].
+    pub fn set_verified(&mut self, verified: bool) {
+        self.verified = verified;
+    }
+
     pub fn is<Q>(&self, role: &Q) -> bool
     where
         Role: Borrow<Q>,
@@ -110,8 +129,8 @@ impl Debug for User {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "User {{ id: {:?}, email: {:?}, roles: {:?}, password: \"*****\" }}",
-            self.id, self.email, self.roles
+            "User {{ id: {:?}, email: {:?}, roles: {:?}, password: \"*****\", verified: {:?} }}",
+            self.id, self.email, self.roles, self.verified
         )
     }
 }
@@ -121,6 +140,10 @@ impl<'r> FromRequest<'r> for User {
     type Error = Error;
     async fn from_request(request: &'r Request<'_>) -> Outcome<User, Error> {
         use rocket::outcome::Outcome::*;
+        #[cfg(feature = "jwt")]
+        if let Some(user) = crate::session::jwt::request_jwt_user(request).await {
+            return Success(user);
+        }
         let guard = request.guard().await;
         let auth: Auth = match guard {
             Success(auth) => auth,
@@ -140,6 +163,12 @@ impl<'r> FromRequest<'r> for AdminUser {
     type Error = Error;
     async fn from_request(request: &'r Request<'_>) -> Outcome<AdminUser, Error> {
         use rocket::outcome::Outcome::*;
+        #[cfg(feature = "jwt")]
+        if let Some(user) = crate::session::jwt::request_jwt_user(request).await {
+            if user.is(ADMIN_ROLE) {
+                return Success(AdminUser(user));
+            }
+        }
         let guard = request.guard().await;
         let auth: Auth = match guard {
             Success(auth) => auth,
@@ -1,9 +1,28 @@
-use super::rand_string;
+use super::generate_salt;
 use crate::db::DBConnection;
+use crate::mail::EmailTemplates;
 use crate::prelude::*;
 use crate::user::roles::Roles;
+use std::sync::Arc;
 
 impl Users {
+    /// A `Users` with the default settings, which the constructors and the `From` implementations build on.
+    fn new(conn: Box<dyn DBConnection>, sess: Arc<dyn SessionManager>) -> Users {
+        Users {
+            conn,
+            sess,
+            policy: SessionPolicy::default(),
+            token_key: Vec::new(),
+            cookie: CookieConfig::default(),
+            stateless: false,
+            require_verified: false,
+            mailer: None,
+            email_templates: EmailTemplates::default(),
+            #[cfg(feature = "jwt")]
+            jwt: None,
+        }
+    }
+
     /// It creates a `Users` instance by connecting  it to a sqlite database.
     /// This method uses the [`sqlx`] crate.
     /// If the database does not yet exist it will return an Error. By default,
@@ -32,28 +51,28 @@ impl Users {
     /// It is necessary to call it explicitly when casting the `Users` struct from an already
     /// established database connection and if the table hasn't been created yet. If the table
     /// already exists then this step is not necessary.
-    /// ```rust
+    /// ```rust, no_run
     ///  use sqlx::{sqlite::SqlitePool, Connection};
     ///  use rocket_auth2::{Users, Error};
     /// # #[tokio::main]
     /// # async fn main() -> Result<(), Error> {
     /// let mut conn = SqlitePool::connect("./database.db").await?;
     /// let mut users: Users = conn.into();
-    /// users.open_redis("redis://127.0.0.1/")?;
+    /// users.open_redis("redis://127.0.0.1/").await?;
     /// users.create_table().await?;
     /// # Ok(()) }
     /// ```
     pub async fn create_table(&self) -> Result<(), Error> {
         self.conn.init().await
     }
-    /// Opens a redis connection. It allows for sessions to be stored persistently across
+    /// Opens a multiplexed redis connection, which is shared by all requests. It allows for sessions to be stored persistently across
     /// different launches. Note that persistent sessions also require a `secret_key` to be set in the [Rocket.toml](https://rocket.rs/v0.5-rc/guide/configuration/#configuration) configuration file.
     /// ```rust, no_run
     /// # use rocket_auth2::{Users, Error};
     /// # #[tokio::main]
     /// # async fn main() -> Result<(), Error> {
     /// let mut users = Users::open_sqlite("database.db").await?;
-    /// users.open_redis("redis://127.0.0.1/")?;
+    /// users.open_redis("redis://127.0.0.1/").await?;
     ///
     /// rocket::build()
     ///     .manage(users)
@@ -62,12 +81,106 @@ impl Users {
     /// # Ok(()) }
     /// ```
     #[cfg(feature = "redis")]
-    pub fn open_redis(&mut self, path: impl redis::IntoConnectionInfo) -> Result<(), Error> {
+    pub async fn open_redis(&mut self, path: impl redis::IntoConnectionInfo) -> Result<(), Error> {
+        self.open_redis_with_prefix(path, crate::session::redis::DEFAULT_PREFIX)
+            .await
+    }
+
+    /// Like [`open_redis`](Users::open_redis), but every redis key written for sessions starts with `prefix`
+    /// instead of `rocket_auth:session:`. Apps that share one redis database should each use their own prefix.
+    /// ```rust, no_run
+    /// # use rocket_auth2::{Users, Error};
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), Error> {
+    /// let mut users = Users::open_sqlite("database.db").await?;
+    /// users.open_redis_with_prefix("redis://127.0.0.1/", "my_app:session:").await?;
+    /// # Ok(()) }
+    /// ```
+    #[cfg(feature = "redis")]
+    pub async fn open_redis_with_prefix(
+        &mut self,
+        path: impl redis::IntoConnectionInfo,
+        prefix: &str,
+    ) -> Result<(), Error> {
         let client = redis::Client::open(path)?;
-        self.sess = Box::new(client);
+        let cnn = client.get_multiplexed_async_connection().await?;
+        self.sess = Arc::new(crate::session::redis::RedisSessions::new(cnn, prefix));
         Ok(())
     }
 
+    /// Sets the limits on how long sessions may live, such as an idle timeout.
+    /// The policy applies to sessions created and used after this call.
+    /// ```rust, no_run
+    /// # use rocket_auth2::{Users, Error, SessionPolicy};
+    /// # use std::time::Duration;
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), Error> {
+    /// let mut users = Users::open_sqlite("database.db").await?;
+    /// users.set_session_policy(SessionPolicy {
+    ///     idle_timeout: Some(Duration::from_secs(30 * 60)),
+    ///     absolute_timeout: Some(Duration::from_secs(12 * 60 * 60)),
+    ///     ..Default::default()
+    /// });
+    /// # Ok(()) }
+    /// ```
+    pub fn set_session_policy(&mut self, policy: SessionPolicy) {
+        self.policy = policy;
+    }
+
+    /// Sets the secret key of the keyed hash (HMAC-SHA256) under which session tokens are stored.
+    /// Only this hash is kept in the session store, so reading the store is not enough to take over a session.
+    /// The key is required: without it, logging in, and issuing or checking any other token,
+    /// fails with [`Error::TokenKeyMissingError`].
+    /// Sessions created under a different key are no longer valid after this call.
+    /// ```rust, no_run
+    /// # use rocket_auth2::{Users, Error};
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), Error> {
+    /// let mut users = Users::open_sqlite("database.db").await?;
+    /// users.set_token_key(std::env::var("SESSION_TOKEN_KEY").unwrap());
+    /// # Ok(()) }
+    /// ```
+    pub fn set_token_key(&mut self, key: impl AsRef<[u8]>) {
+        self.token_key = key.as_ref().to_vec();
+    }
+
+    /// Sets the name and the attributes of the cookie that holds the session.
+    /// Sessions that were sent in a cookie with another name are no longer recognized.
+    /// ```rust, no_run
+    /// # use rocket_auth2::{Users, Error, CookieConfig};
+    /// # use rocket::http::SameSite;
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), Error> {
+    /// let mut users = Users::open_sqlite("database.db").await?;
+    /// users.set_cookie_config(CookieConfig {
+    ///     name: "session".into(),
+    ///     same_site: SameSite::Lax,
+    ///     ..Default::default()
+    /// });
+    /// # Ok(()) }
+    /// ```
+    pub fn set_cookie_config(&mut self, config: CookieConfig) {
+        self.cookie = config;
+    }
+
+    /// Switches new logins to stateless sessions. A stateless session is validated from its cookie alone,
+    /// which carries its expiry and the session version of the user, so no session store is queried.
+    /// It is revoked by bumping the version with [`revoke_all_sessions`](Users::revoke_all_sessions),
+    /// which ends every stateless session of the user at once.
+    /// Stateless sessions have no idle timeout, and they are not listed by [`sessions`](Users::sessions).
+    /// Since they are only as safe as the private cookie that holds them, the `secret_key` must be kept secret.
+    /// ```rust, no_run
+    /// # use rocket_auth2::{Users, Error};
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), Error> {
+    /// let mut users = Users::open_sqlite("database.db").await?;
+    /// users.set_stateless_sessions(true);
+    /// # Ok(()) }
+    /// ```
+    pub fn set_stateless_sessions(&mut self, stateless: bool) {
+        self.stateless = stateless;
+    }
+
     /// It creates a `Users` instance by connecting  it to a sqlite database.
     /// This method uses the [`rusqlite`] crate.
     /// If the database does not yet exist it will attempt to create it. By default,
@@ -88,10 +201,10 @@ impl Users {
     #[cfg(feature = "rusqlite")]
     pub fn open_rusqlite(path: impl AsRef<std::path::Path>) -> Result<Self, Error> {
         use tokio::sync::Mutex;
-        let users = Users {
-            conn: Box::new(Mutex::new(rusqlite::Connection::open(path)?)),
-            sess: Box::new(chashmap::CHashMap::new()),
-        };
+        let users = Users::new(
+            Box::new(Mutex::new(rusqlite::Connection::open(path)?)),
+            Arc::new(chashmap::CHashMap::new()),
+        );
         futures::executor::block_on(users.conn.init())?;
         Ok(users)
     }
@@ -116,11 +229,7 @@ impl Users {
         use sqlx::PgPool;
         let conn = PgPool::connect(path).await?;
         conn.init().await?;
-        let users = Users {
-            conn: Box::new(conn),
-            sess: Box::new(chashmap::CHashMap::new()),
-        };
-        Ok(users)
+        Ok(conn.into())
     }
 
     /// It creates a `Users` instance by connecting  it to a mysql database.
@@ -137,7 +246,6 @@ impl Users {
     /// # Ok(()) }
     ///
     /// ```
-
     #[cfg(feature = "sqlx-mysql")]
     pub async fn open_mysql(path: &str) -> Result<Self, Error> {
         let conn = sqlx::MySqlPool::connect(path).await?;
@@ -148,9 +256,8 @@ impl Users {
 
     /// It creates a `Users` instance by connecting  it to a sled database.
     /// This method uses the [`sled`] crate.
-    /// If the database does not yet exist it will attempt to create it. By default,
-    /// sessions will be stored on a concurrent HashMap. In order to have persistent sessions see
-    /// the method [`open_redis`](Users::open_redis).
+    /// If the database does not yet exist it will attempt to create it.
+    /// Sessions are stored in the same database, so they survive restarts.
     /// ```rust, no_run
     /// # use rocket_auth2::{Error, Users};
     /// # #[tokio::main]
@@ -166,7 +273,7 @@ impl Users {
     #[cfg(feature = "sled")]
     pub fn open_sled(path: impl AsRef<std::path::Path>) -> Result<Self, Error> {
         let db = sled::open(path)?;
-        Ok(db.into())
+        Ok((db.clone(), db).into())
     }
 
     /// It queries a user by their email.
@@ -219,9 +326,9 @@ impl Users {
         roles: &Roles,
     ) -> Result<(), Error> {
         let password = password.as_bytes();
-        let salt = rand_string(30);
+        let salt = generate_salt();
         let config = argon2::Config::default();
-        let hash = argon2::hash_encoded(password, salt.as_bytes(), &config).unwrap();
+        let hash = argon2::hash_encoded(password, &salt, &config)?;
         self.conn.create_user(email, &hash, roles).await?;
 
         Ok(())
@@ -245,8 +352,8 @@ impl Users {
         self.conn.get_all_ids().await
     }
 
-    /// Deletes a user from de database. Note that this method won't delete the session.
-    /// To do that use [`Auth::delete`](crate::Auth::delete).
+    /// Deletes a user from de database, along with all of their sessions.
+    /// Note that this method won't remove the session cookie. To do that use [`Auth::delete`](crate::Auth::delete).
     /// ```rust
     /// use rocket::{get, State};
     /// use rocket_auth2::{Users, User, Error};
@@ -257,11 +364,19 @@ impl Users {
     /// }
     /// ```
     pub async fn delete(&self, id: i32) -> Result<()> {
-        self.sess.remove(id)?;
+        self.revoke_all_sessions(id).await?;
+        for key in self.conn.get_api_keys(id).await? {
+            self.conn.delete_api_key(&key.id).await?;
+        }
+        self.conn.delete_password_reset(id).await?;
+        self.conn.delete_email_change(id).await?;
+        self.conn.delete_totp_secret(id).await?;
         self.conn.delete_user_by_id(id).await
     }
 
     /// Modifies a user in the database.
+    /// If the roles of the user change, every session of the user gets a new token on its next request,
+    /// which the [`Auth`](crate::Auth) guard sends to the client in a new cookie.
     /// ```
     /// # use rocket_auth2::{Users, Error};
     /// # async fn func(users: Users) -> Result<(), Box<dyn std::error::Error>> {
@@ -272,7 +387,94 @@ impl Users {
     /// # Ok(())}
     /// ```
     pub async fn modify(&self, user: &User) -> Result<()> {
-        self.conn.update_user(user).await
+        let roles_changed = match self.conn.get_user_by_id(user.id).await {
+            Ok(old_user) => old_user.roles != user.roles,
+            Err(_) => false,
+        };
+        self.conn.update_user(user).await?;
+        if roles_changed {
+            self.rotate_user_sessions(user.id).await?;
+        }
+        Ok(())
+    }
+
+    /// Removes the sessions that expired from the session store, and returns how many were removed.
+    /// Rather than calling it by hand, consider attaching a [`SessionCleaner`](crate::SessionCleaner) fairing.
+    /// ```rust
+    /// # use rocket_auth2::{Users, Error};
+    /// # async fn func(users: Users) -> Result<(), Error> {
+    /// let removed = users.clear_expired_sessions().await?;
+    /// println!("{} sessions expired", removed);
+    /// # Ok(())}
+    /// ```
+    pub async fn clear_expired_sessions(&self) -> Result<usize> {
+        self.sess.clear_expired().await
+    }
+
+    /// Lists the active sessions of a user, along with the client they were created from.
+    /// ```rust
+    /// # use rocket::{State, get};
+    /// # use rocket_auth2::{Error, Users};
+    /// #[get("/sessions-of/<id>")]
+    /// async fn sessions_of(id: i32, users: &State<Users>) -> Result<String, Error> {
+    ///     let sessions = users.sessions(id).await?;
+    ///     Ok(format!("{:?}", sessions))
+    /// }
+    /// ```
+    pub async fn sessions(&self, user_id: i32) -> Result<Vec<SessionInfo>> {
+        let sessions = self
+            .list_sessions(user_id)
+            .await?
+            .into_iter()
+            .map(|(session_id, key)| SessionInfo::new(session_id, key))
+            .collect();
+        Ok(sessions)
+    }
+
+    /// Ends a single session of a user. Sessions that belong to a different user are left untouched.
+    /// ```rust
+    /// # use rocket_auth2::{Users, Error};
+    /// # async fn func(users: Users) -> Result<(), Error> {
+    /// for session in users.sessions(4).await? {
+    ///     users.revoke_session(4, &session.session_id).await?;
+    /// }
+    /// # Ok(())}
+    /// ```
+    pub async fn revoke_session(&self, user_id: i32, session_id: &str) -> Result<()> {
+        match self.sess.get(session_id).await {
+            Some(key) if key.user_id == user_id => self.sess.remove(session_id).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Ends every session of a user, except the one identified by `session_id`, along with the families of refresh tokens
+    /// issued to the user, which are not listed by [`sessions`](Users::sessions).
+    /// Stateless sessions can only be ended all at once, with [`revoke_all_sessions`](Users::revoke_all_sessions).
+    /// ```rust
+    /// # use rocket_auth2::{Users, Session, Error};
+    /// # async fn func(users: Users, session: Session) -> Result<(), Error> {
+    /// users.revoke_other_sessions(session.id, &session.session_id).await?;
+    /// # Ok(())}
+    /// ```
+    pub async fn revoke_other_sessions(&self, user_id: i32, session_id: &str) -> Result<()> {
+        for (id, _) in self.sess.list(user_id).await? {
+            if id != session_id {
+                self.sess.remove(&id).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Ends every session of a user, including stateless sessions, whose session version is bumped.
+    /// ```rust
+    /// # use rocket_auth2::{Users, Error};
+    /// # async fn func(users: Users) -> Result<(), Error> {
+    /// users.revoke_all_sessions(4).await?;
+    /// # Ok(())}
+    /// ```
+    pub async fn revoke_all_sessions(&self, user_id: i32) -> Result<()> {
+        self.sess.remove_user(user_id).await?;
+        self.conn.bump_session_version(user_id).await
     }
 }
 
@@ -288,18 +490,15 @@ impl Users {
 /// users.create_table().await?;
 /// # Ok(())}
 /// ```
-
 impl<Conn: 'static + DBConnection> From<Conn> for Users {
     fn from(db: Conn) -> Users {
-        Users {
-            conn: Box::from(db),
-            sess: Box::new(chashmap::CHashMap::new()),
-        }
+        Users::new(Box::from(db), Arc::new(chashmap::CHashMap::new()))
     }
 }
 
 /// Additionally, `Users` can be created from a tuple,
 /// where the first element is a database connection, and the second is a redis connection.
+/// A `redis::aio::MultiplexedConnection` is shared by every request, much like [`Users::open_redis`] does.
 /// ```rust
 /// # use rocket_auth2::{Users, Error};
 /// # extern crate tokio_postgres;
@@ -308,18 +507,28 @@ impl<Conn: 'static + DBConnection> From<Conn> for Users {
 /// # async fn func(postgres_path: &str, redis_path: &str) -> Result<(), Error> {
 /// let (db_client, connection) = tokio_postgres::connect(postgres_path, NoTls).await?;
 /// let redis_client = redis::Client::open(redis_path)?;
+/// let redis_cnn = redis_client.get_multiplexed_async_connection().await?;
 ///
-/// let users: Users = (db_client, redis_client).into();
+/// let users: Users = (db_client, redis_cnn).into();
 /// // we create the user table in the
 /// // database if it does not exist.
 /// users.create_table().await?;
 /// # Ok(())}
 /// ```
+/// `PgPool`, `MySqlPool`, `SqlitePool` and `tokio_postgres::Client` can store sessions themselves,
+/// in a `sessions` table that is created together with the user table. A `sled::Db` stores them in its own trees.
+/// This way, sessions survive restarts without the need of a redis server.
+/// ```rust
+/// # use rocket_auth2::{Users, Error};
+/// # async fn func(db_path: &str) -> Result<(), Error> {
+/// let pool = sqlx::SqlitePool::connect(db_path).await?;
+///
+/// let users: Users = (pool.clone(), pool).into();
+/// users.create_table().await?;
+/// # Ok(())}
+/// ```
 impl<T0: 'static + DBConnection, T1: 'static + SessionManager> From<(T0, T1)> for Users {
     fn from((db, ss): (T0, T1)) -> Users {
-        Users {
-            conn: Box::from(db),
-            sess: Box::new(ss),
-        }
+        Users::new(Box::from(db), Arc::new(ss))
     }
 }
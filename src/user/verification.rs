@@ -0,0 +1,143 @@
+use crate::prelude::*;
+use crate::session::sign;
+use subtle::ConstantTimeEq;
+
+/// How long a verification token is valid, unless another period is passed to [`Users::send_verification_for`].
+const VERIFICATION_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+impl Users {
+    /// Forbids users whose email is not verified from logging in, which they can do by default.
+    /// Logging in then fails with [`Error::UnverifiedEmailError`], and so does issuing a token.
+    /// ```rust, no_run
+    /// # use rocket_auth2::{Users, Error};
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), Error> {
+    /// let mut users = Users::open_sqlite("database.db").await?;
+    /// users.set_token_key(std::env::var("TOKEN_KEY").unwrap());
+    /// users.set_require_verified_email(true);
+    /// # Ok(()) }
+    /// ```
+    pub fn set_require_verified_email(&mut self, required: bool) {
+        self.require_verified = required;
+    }
+
+    /// Creates a token that proves the user owns their email address once it is passed to [`Users::verify_email`].
+    /// It is valid for one day. If a mailer is set with [`Users::set_mailer`], the token is sent to the address
+    /// with the `verification` template, otherwise it has to be delivered by the application.
+    ///
+    /// The token is signed with the key set by [`Users::set_token_key`], which is required, so it is not stored.
+    /// It can only be used once, since it is only valid while the email it was sent to is unverified.
+    /// ```rust
+    /// # use rocket::{post, State};
+    /// # use rocket_auth2::{Auth, Error, Users};
+    /// #[post("/resend-verification")]
+    /// async fn resend_verification(auth: Auth<'_>) -> Result<(), Error> {
+    ///     let user = auth.get_user().await.ok_or(Error::UnauthenticatedError)?;
+    ///     let token = auth.users.send_verification(user.id()).await?;
+    ///     println!("visit /verify/{} to verify {}", token, user.email());
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn send_verification(&self, user_id: i32) -> Result<String> {
+        self.send_verification_for(user_id, VERIFICATION_LIFETIME)
+            .await
+    }
+
+    /// Creates a verification token that is valid for the specified period of time.
+    pub async fn send_verification_for(&self, user_id: i32, time: Duration) -> Result<String> {
+        let user = self.conn.get_user_by_id(user_id).await?;
+        if user.verified {
+            return Err(Error::EmailAlreadyVerified);
+        }
+        let expires = now() + time.as_secs() as i64;
+        let signature = self.verification_signature(&user, expires)?;
+        let token = format!("{}.{}.{}", user.id, expires, signature);
+        let vars = [("email", user.email.as_str()), ("token", &token)];
+        self.send_email(|t| &t.verification, &user.email, &vars)
+            .await?;
+        Ok(token)
+    }
+
+    /// Marks the email of a user as verified, given a token from [`Users::send_verification`].
+    /// It fails with [`Error::InvalidTokenError`] if the token is malformed, expired, or was already used.
+    /// ```rust
+    /// # use rocket::{get, State};
+    /// # use rocket_auth2::{Error, Users};
+    /// #[get("/verify/<token>")]
+    /// async fn verify(token: &str, users: &State<Users>) -> Result<String, Error> {
+    ///     let user = users.verify_email(token).await?;
+    ///     Ok(format!("{} is verified.", user.email()))
+    /// }
+    /// ```
+    pub async fn verify_email(&self, token: &str) -> Result<User> {
+        let mut parts = token.splitn(3, '.');
+        let (Some(id), Some(expires), Some(signature)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(Error::InvalidTokenError);
+        };
+        let id: i32 = id.parse().map_err(|_| Error::InvalidTokenError)?;
+        let expires: i64 = expires.parse().map_err(|_| Error::InvalidTokenError)?;
+        if expires <= now() {
+            return Err(Error::InvalidTokenError);
+        }
+        let mut user = self
+            .conn
+            .get_user_by_id(id)
+            .await
+            .map_err(|_| Error::InvalidTokenError)?;
+        let expected = self.verification_signature(&user, expires)?;
+        if user.verified || !bool::from(expected.as_bytes().ct_eq(signature.as_bytes())) {
+            return Err(Error::InvalidTokenError);
+        }
+        user.verified = true;
+        self.conn.update_user(&user).await?;
+        Ok(user)
+    }
+
+    /// The signature covers the email, so that a token only verifies the address it was sent to.
+    fn verification_signature(&self, user: &User, expires: i64) -> Result<String> {
+        let message = format!("verify-email:{}:{}:{}", user.id, user.email, expires);
+        Ok(sign(self.token_key()?, &message))
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-sqlite"))]
+mod test {
+    use super::*;
+    use crate::session::ClientInfo;
+
+    #[tokio::test]
+    async fn test_verification_tokens_are_single_use() {
+        let mut users = crate::user::test_users("verify@example.com").await;
+        users.set_require_verified_email(true);
+        let user = users.get_by_email("verify@example.com").await.unwrap();
+        users.set_token_key("");
+        assert!(matches!(
+            users.send_verification(user.id).await,
+            Err(Error::TokenKeyMissingError)
+        ));
+
+        users.set_token_key("key");
+        let form = Login {
+            email: "verify@example.com".into(),
+            password: "Password123".into(),
+            csrf_token: None,
+        };
+        assert!(matches!(
+            users.check_credentials(&form).await,
+            Err(Error::UnverifiedEmailError)
+        ));
+        let expired = users
+            .send_verification_for(user.id, Duration::ZERO)
+            .await
+            .unwrap();
+        assert!(users.verify_email(&expired).await.is_err());
+        let token = users.send_verification(user.id).await.unwrap();
+        assert!(users.verify_email(&format!("{}x", token)).await.is_err());
+
+        assert!(users.verify_email(&token).await.unwrap().is_verified());
+        assert!(users.get_by_id(user.id).await.unwrap().is_verified());
+        assert!(users.verify_email(&token).await.is_err());
+        assert!(users.login(&form, &ClientInfo::default()).await.is_ok());
+    }
+}